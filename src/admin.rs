@@ -0,0 +1,247 @@
+//! Operations behind `mediapub-admin`. They work on the same tables as the routes
+//! and reuse their logic where there is any, so the rules stay in one place.
+use crate::{
+    db_pool::mongo_database,
+    repair::RepairStep,
+    route::{
+        drop,
+        user::signup::{self, SignupError},
+    },
+    storage::BlobStore,
+};
+use std::path::Path;
+use deadpool_postgres::Client;
+use mongodb::bson::{Document, doc};
+use serde::Serialize;
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub enum AdminError {
+    UserNotFound(String),
+    PostNotFound(Uuid),
+    Signup(SignupError),
+    /// Deleting a post stopped at this step, the rest is queued in `repair_queue`
+    Delete(RepairStep),
+    Database(String),
+}
+
+impl std::fmt::Display for AdminError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdminError::UserNotFound(user) => write!(f, "user {} not found", user),
+            AdminError::PostNotFound(post_id) => write!(f, "post {} not found", post_id),
+            AdminError::Signup(e) => write!(f, "{}", e),
+            AdminError::Delete(step) => write!(f, "post deletion failed at the {} step", step),
+            AdminError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+impl std::error::Error for AdminError {}
+
+fn db_failed(e: tokio_postgres::Error) -> AdminError {
+    AdminError::Database(e.to_string())
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserRef {
+    pub user_id: Uuid,
+    pub username: String,
+}
+
+/// Look a user up by id or username
+pub async fn find_user(psql_client: &Client, user: &str) -> Result<UserRef, AdminError> {
+    let row = match Uuid::parse_str(user) {
+        Ok(user_id) => {
+            psql_client
+                .query_opt(
+                    "SELECT user_id, username FROM \"user\" WHERE user_id = $1",
+                    &[&user_id],
+                )
+                .await
+        }
+        Err(_) => {
+            psql_client
+                .query_opt(
+                    "SELECT user_id, username FROM \"user\" WHERE username = $1",
+                    &[&user],
+                )
+                .await
+        }
+    };
+    match row.map_err(db_failed)? {
+        Some(row) => Ok(UserRef {
+            user_id: row.get(0),
+            username: row.get(1),
+        }),
+        None => Err(AdminError::UserNotFound(user.to_string())),
+    }
+}
+
+pub async fn create_user(
+    psql_client: &Client,
+    username: &str,
+    password: &str,
+) -> Result<UserRef, AdminError> {
+    let user_id = signup::create_user(psql_client, username, password)
+        .await
+        .map_err(AdminError::Signup)?;
+    Ok(UserRef {
+        user_id,
+        username: username.to_string(),
+    })
+}
+
+/// Revoke sessions of a user, or only `token_id`. Returns how many were revoked.
+pub async fn revoke_sessions(
+    psql_client: &Client,
+    user_id: &Uuid,
+    token_id: Option<&Uuid>,
+) -> Result<u64, AdminError> {
+    psql_client
+        .execute(
+            "UPDATE session SET is_revoked = true, updated_at = NOW()
+            WHERE user_id = $1 AND ($2::UUID IS NULL OR token_id = $2) AND is_revoked = false",
+            &[user_id, &token_id],
+        )
+        .await
+        .map_err(db_failed)
+}
+
+/// Revoke dev tokens of a user, or only `token_id`. Returns how many were revoked.
+pub async fn revoke_dev_tokens(
+    psql_client: &Client,
+    user_id: &Uuid,
+    token_id: Option<&Uuid>,
+) -> Result<u64, AdminError> {
+    psql_client
+        .execute(
+            "UPDATE dev_token SET is_revoked = true, updated_at = NOW()
+            WHERE user_id = $1 AND ($2::UUID IS NULL OR token_id = $2) AND is_revoked = false",
+            &[user_id, &token_id],
+        )
+        .await
+        .map_err(db_failed)
+}
+
+/// Set a new password and sign the user out everywhere. Returns the revoked session count.
+pub async fn reset_password(
+    psql_client: &Client,
+    user_id: &Uuid,
+    password: &str,
+) -> Result<u64, AdminError> {
+    signup::validate_password(password).map_err(AdminError::Signup)?;
+    let password_hash = signup::hash_password(password).map_err(AdminError::Signup)?;
+    psql_client
+        .execute(
+            "UPDATE \"user\" SET password_hash = $2, updated_at = NOW() WHERE user_id = $1",
+            &[user_id, &password_hash],
+        )
+        .await
+        .map_err(db_failed)?;
+    revoke_sessions(psql_client, user_id, None).await
+}
+
+#[derive(Debug, Serialize)]
+pub struct Revoked {
+    pub sessions: u64,
+    pub dev_tokens: u64,
+}
+
+/// Switch `is_active`. Deactivating also revokes every session and dev token,
+/// reactivating does not bring them back.
+pub async fn set_active(
+    psql_client: &Client,
+    user_id: &Uuid,
+    active: bool,
+) -> Result<Revoked, AdminError> {
+    psql_client
+        .execute(
+            "UPDATE \"user\" SET is_active = $2, updated_at = NOW() WHERE user_id = $1",
+            &[user_id, &active],
+        )
+        .await
+        .map_err(db_failed)?;
+    if active {
+        return Ok(Revoked {
+            sessions: 0,
+            dev_tokens: 0,
+        });
+    }
+    Ok(Revoked {
+        sessions: revoke_sessions(psql_client, user_id, None).await?,
+        dev_tokens: revoke_dev_tokens(psql_client, user_id, None).await?,
+    })
+}
+
+/// Delete a post the same way `DELETE /item/{id}` does. A running server keeps the
+/// post in its similarity index until it restarts.
+pub async fn delete_post(
+    psql_client: &mut Client,
+    mongo_pool: &mongodb::Client,
+    store: &dyn BlobStore,
+    destination: &Path,
+    post_id: &Uuid,
+) -> Result<String, AdminError> {
+    let filename: String = match psql_client
+        .query_opt("SELECT filename FROM post WHERE post_id = $1", &[post_id])
+        .await
+        .map_err(db_failed)?
+    {
+        Some(row) => row.get(0),
+        None => return Err(AdminError::PostNotFound(*post_id)),
+    };
+    drop::delete_post(psql_client, mongo_pool, store, destination, None, post_id, &filename)
+        .await
+        .map_err(AdminError::Delete)?;
+    Ok(filename)
+}
+
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub users: i64,
+    pub active_users: i64,
+    pub active_sessions: i64,
+    pub active_dev_tokens: i64,
+    pub posts: i64,
+    pub mongo_documents: u64,
+    pub blobs: i64,
+    pub blob_bytes: i64,
+    pub tags: i64,
+    pub pending_repairs: i64,
+}
+
+pub async fn stats(psql_client: &Client, mongo_pool: &mongodb::Client) -> Result<Stats, AdminError> {
+    let row = psql_client
+        .query_one(
+            "SELECT
+                (SELECT COUNT(*) FROM \"user\"),
+                (SELECT COUNT(*) FROM \"user\" WHERE is_active = true),
+                (SELECT COUNT(*) FROM session WHERE is_revoked = false AND session_expires_at > NOW()),
+                (SELECT COUNT(*) FROM dev_token WHERE is_revoked = false AND expires_at > NOW()),
+                (SELECT COUNT(*) FROM post),
+                (SELECT COUNT(*) FROM blob),
+                (SELECT COALESCE(SUM(size), 0)::BIGINT FROM blob),
+                (SELECT COUNT(*) FROM tag),
+                (SELECT COUNT(*) FROM repair_queue WHERE resolved_at IS NULL)",
+            &[],
+        )
+        .await
+        .map_err(db_failed)?;
+    let mongo_documents = mongo_database(mongo_pool)
+        .collection::<Document>("post")
+        .count_documents(doc! {})
+        .await
+        .map_err(|e| AdminError::Database(e.to_string()))?;
+    Ok(Stats {
+        users: row.get(0),
+        active_users: row.get(1),
+        active_sessions: row.get(2),
+        active_dev_tokens: row.get(3),
+        posts: row.get(4),
+        mongo_documents,
+        blobs: row.get(5),
+        blob_bytes: row.get(6),
+        tags: row.get(7),
+        pending_repairs: row.get(8),
+    })
+}
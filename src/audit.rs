@@ -0,0 +1,32 @@
+//! Security-relevant events, kept in `audit_event` for later review.
+use deadpool_postgres::Client;
+use serde::Serialize;
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditKind {
+    /// A rotated refresh token was presented again, its family was revoked
+    RefreshTokenReuse,
+}
+
+impl std::fmt::Display for AuditKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuditKind::RefreshTokenReuse => write!(f, "refresh_token_reuse"),
+        }
+    }
+}
+
+/// Failures are logged, the action being audited has already happened
+pub async fn record(psql_client: &Client, user_id: Option<&Uuid>, kind: AuditKind, detail: &str) {
+    if let Err(e) = psql_client
+        .execute(
+            "INSERT INTO \"audit_event\" (user_id, kind, detail) VALUES ($1, $2, $3)",
+            &[&user_id, &kind.to_string(), &detail],
+        )
+        .await
+    {
+        eprintln!("Failed to record {} audit event: {}", kind, e);
+    }
+}
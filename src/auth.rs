@@ -0,0 +1,201 @@
+//! The caller of a request.
+//!
+//! Taking [`AuthUser`] as a handler argument resolves the credential before the
+//! handler runs and rejects the request through [`generate_response`] when it is not
+//! valid. The credential is read from `Authorization` (`Bearer <session token>`,
+//! `Token <dev token>` or a bare session token), else from the [`SESSION_COOKIE`]
+//! cookie, which `POST /login` and `POST /login/refresh` set when asked with
+//! `"set_cookie": true`. The result is kept in the request extensions, so it is resolved once.
+//!
+//! A browser attaches the cookie to cross-site form posts as well, so the cookie only
+//! authenticates safe methods unless the request also carries [`CSRF_HEADER`]. Other
+//! sites cannot add that header without a CORS preflight, which fails for
+//! credentialed requests because credentials are not allowed cross-origin.
+use crate::{
+    errors::{
+        AHError::{AccountSuspended, InvalidCredential, MissingScope},
+        DBError::{ConnectionFailed, QueryFailed},
+        DBType::Postgres,
+        ErrorKind::{self, AuthError, DatabaseError},
+    },
+    route::user::login::hash_token,
+    scope::{Scope, allows, parse_scopes},
+    types::ErrorResponse,
+    utility::{CredentialType, generate_response, parse_credential},
+};
+use actix_web::{
+    FromRequest, HttpMessage, HttpRequest, HttpResponse, dev::Payload, error::InternalError,
+    http::header::AUTHORIZATION, web,
+};
+use deadpool_postgres::Pool;
+use std::future::Future;
+use std::pin::Pin;
+use uuid::Uuid;
+
+pub const SESSION_COOKIE: &str = "session_token";
+/// Needed on unsafe requests authenticated by [`SESSION_COOKIE`]
+pub const CSRF_HEADER: &str = "X-Requested-With";
+/// `session.last_seen_at` is only written when it is older than this
+pub const LAST_SEEN_RESOLUTION_SECS: f64 = 60.0;
+
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub user_id: Uuid,
+    /// `token_id` of the session or dev token used
+    pub token_id: Uuid,
+    pub credential_type: CredentialType,
+    scopes: Vec<Scope>,
+}
+
+impl AuthUser {
+    /// Session tokens hold every scope, dev tokens the ones they were created with
+    pub fn require(&self, scope: Scope) -> Result<(), ErrorKind> {
+        match allows(&self.scopes, scope) {
+            true => Ok(()),
+            false => Err(AuthError(MissingScope(scope))),
+        }
+    }
+}
+
+/// Look up a credential and check its user is active. Use of dev tokens and
+/// sessions is recorded in `last_used_at` and `last_seen_at`.
+pub async fn resolve(
+    pool: &Pool,
+    credential: &str,
+    credential_type: CredentialType,
+) -> Result<AuthUser, ErrorKind> {
+    let psql_client = match pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("Failed to get connection from pool: {}", e);
+            return Err(DatabaseError(ConnectionFailed(Postgres)));
+        }
+    };
+    let row = match credential_type {
+        CredentialType::DevToken => {
+            psql_client
+                .query_opt(
+                    "UPDATE dev_token SET last_used_at = NOW()
+                    WHERE token_hash = $1 AND is_revoked = false AND expires_at > NOW()
+                    RETURNING token_id, user_id, scope",
+                    &[&hash_token(credential)],
+                )
+                .await
+        }
+        CredentialType::SessionToken => {
+            psql_client
+                .query_opt(
+                    "SELECT token_id, user_id,
+                        COALESCE(last_seen_at < NOW() - make_interval(secs => $2), true)
+                    FROM session
+                    WHERE session_token = $1 AND is_revoked = false AND session_expires_at > NOW()",
+                    &[&credential, &LAST_SEEN_RESOLUTION_SECS],
+                )
+                .await
+        }
+    };
+    let row = match row {
+        Ok(Some(row)) => row,
+        Ok(None) => return Err(AuthError(InvalidCredential)),
+        Err(e) => {
+            eprintln!("Credential query failed: {}", e);
+            return Err(DatabaseError(QueryFailed(Postgres)));
+        }
+    };
+    let user = AuthUser {
+        token_id: row.get(0),
+        user_id: row.get(1),
+        scopes: match credential_type {
+            CredentialType::DevToken => parse_scopes(row.get(2)),
+            CredentialType::SessionToken => Scope::ALL.to_vec(),
+        },
+        credential_type,
+    };
+    if credential_type == CredentialType::SessionToken
+        && row.get::<_, bool>(2)
+        && let Err(e) = psql_client
+            .execute(
+                "UPDATE session SET last_seen_at = NOW() WHERE token_id = $1",
+                &[&user.token_id],
+            )
+            .await
+    {
+        eprintln!("Failed to update last_seen_at of session {}: {}", user.token_id, e);
+    }
+    match psql_client
+        .query_opt(
+            "SELECT 1 FROM \"user\" WHERE user_id = $1 AND is_active = true",
+            &[&user.user_id],
+        )
+        .await
+    {
+        Ok(Some(_)) => Ok(user),
+        Ok(None) => Err(AuthError(AccountSuspended)),
+        Err(e) => {
+            eprintln!("User active check failed: {}", e);
+            Err(DatabaseError(QueryFailed(Postgres)))
+        }
+    }
+}
+
+fn rejected(response: HttpResponse) -> actix_web::Error {
+    InternalError::from_response("request rejected", response).into()
+}
+
+/// The header if there is one, else the session cookie. See the module docs for [`CSRF_HEADER`].
+fn request_credential(request: &HttpRequest) -> Result<(String, CredentialType), HttpResponse> {
+    if let Some(header) = request.headers().get(AUTHORIZATION) {
+        return match header.to_str() {
+            Ok(header) => {
+                let (credential, credential_type) = parse_credential(header);
+                Ok((credential.to_string(), credential_type))
+            }
+            Err(_) => Err(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid authorization header format.".to_string(),
+            })),
+        };
+    }
+    match request.cookie(SESSION_COOKIE) {
+        Some(_) if !request.method().is_safe() && !request.headers().contains_key(CSRF_HEADER) => {
+            Err(HttpResponse::Forbidden().json(ErrorResponse {
+                error: format!(
+                    "requests authenticated by the session cookie need a {} header.",
+                    CSRF_HEADER
+                ),
+            }))
+        }
+        Some(cookie) => Ok((cookie.value().to_string(), CredentialType::SessionToken)),
+        None => Err(HttpResponse::Unauthorized().json(ErrorResponse {
+            error: "authorization header not found.".to_string(),
+        })),
+    }
+}
+
+impl FromRequest for AuthUser {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<AuthUser, actix_web::Error>>>>;
+
+    fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let request = request.clone();
+        Box::pin(async move {
+            if let Some(user) = request.extensions().get::<AuthUser>() {
+                return Ok(user.clone());
+            }
+            let (credential, credential_type) = request_credential(&request).map_err(rejected)?;
+            let pool = match request.app_data::<web::Data<Pool>>() {
+                Some(pool) => pool.clone(),
+                None => {
+                    eprintln!("AuthUser used without a Postgres pool in app data");
+                    return Err(rejected(generate_response(&DatabaseError(
+                        ConnectionFailed(Postgres),
+                    ))));
+                }
+            };
+            let user = resolve(&pool, &credential, credential_type)
+                .await
+                .map_err(|e| rejected(generate_response(&e)))?;
+            request.extensions_mut().insert(user.clone());
+            Ok(user)
+        })
+    }
+}
@@ -0,0 +1,483 @@
+//! Maintenance commands that run against the same databases and blob store as the server.
+//! With `--json` every command prints a single JSON object, errors included.
+use clap::{Parser, Subcommand, ValueEnum};
+use mediapub::{
+    admin::{self, AdminError},
+    db_pool::{create_mongo_pool, create_psql_pool},
+    fsck::{self, Finding, RepairMode},
+    init,
+    settings::{self, Settings, SettingsArgs},
+    storage::{self, StorageConfig},
+    types::ErrorResponse,
+};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufRead, BufReader, IsTerminal};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use uuid::Uuid;
+
+#[derive(Parser)]
+#[command(name = "mediapub-admin", about = "MediaPub maintenance tool")]
+struct Cli {
+    /// Print machine-readable JSON
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(flatten)]
+    settings: SettingsArgs,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create the database tables and indexes if they are missing
+    Init,
+    /// Report posts, documents, blobs and files that disagree with each other
+    Fsck {
+        /// Fix what the given mode covers, may be repeated. Queued repairs with
+        /// nothing left to fix are marked resolved afterwards.
+        #[arg(long, value_enum)]
+        repair: Vec<Repair>,
+    },
+    /// Manage accounts
+    User {
+        #[command(subcommand)]
+        command: UserCommand,
+    },
+    /// Revoke credentials of a user
+    Revoke {
+        #[command(subcommand)]
+        command: RevokeCommand,
+    },
+    /// Manage posts
+    Post {
+        #[command(subcommand)]
+        command: PostCommand,
+    },
+    /// Print counts of users, credentials, posts and storage
+    Stats,
+}
+
+#[derive(Subcommand)]
+enum UserCommand {
+    /// Create a user, the password is read from stdin unless a file is given
+    Create {
+        username: String,
+        /// Read the password from the first line of this file
+        #[arg(long)]
+        password_file: Option<PathBuf>,
+    },
+    /// Set a new password and revoke every session, read from stdin unless a file is given
+    ResetPassword {
+        /// Username or user id
+        user: String,
+        /// Read the password from the first line of this file
+        #[arg(long)]
+        password_file: Option<PathBuf>,
+    },
+    /// Suspend an account and revoke its sessions and dev tokens
+    Deactivate {
+        /// Username or user id
+        user: String,
+    },
+    /// Allow a deactivated account to sign in again
+    Activate {
+        /// Username or user id
+        user: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum RevokeCommand {
+    /// Revoke login sessions
+    Sessions {
+        /// Username or user id
+        user: String,
+        /// Only this session
+        #[arg(long)]
+        id: Option<Uuid>,
+    },
+    /// Revoke dev tokens
+    Tokens {
+        /// Username or user id
+        user: String,
+        /// Only this token
+        #[arg(long)]
+        id: Option<Uuid>,
+    },
+}
+
+#[derive(Subcommand)]
+enum PostCommand {
+    /// Delete posts with their documents, files and thumbnails
+    Delete {
+        #[arg(required = true)]
+        post_ids: Vec<Uuid>,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Repair {
+    /// Insert empty metadata for posts without a MongoDB document
+    Stubs,
+    /// Recount blob references and drop unreferenced blob rows
+    Refcounts,
+    /// Move orphaned blobs and files to the quarantine directory
+    Quarantine,
+    /// Every mode above
+    All,
+}
+
+#[derive(Serialize)]
+struct RepairOutcome {
+    finding: Finding,
+    ok: bool,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct FsckOutput {
+    findings: Vec<Finding>,
+    repairs: Vec<RepairOutcome>,
+}
+
+#[derive(Serialize)]
+struct UserOutput {
+    user_id: Uuid,
+    username: String,
+    revoked_sessions: u64,
+    revoked_dev_tokens: u64,
+}
+
+#[derive(Serialize)]
+struct RevokeOutput {
+    user_id: Uuid,
+    revoked: u64,
+}
+
+#[derive(Serialize)]
+struct DeletedPost {
+    post_id: Uuid,
+    filename: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DeleteOutput {
+    deleted: Vec<DeletedPost>,
+}
+
+fn repair_modes(repair: &[Repair]) -> Vec<RepairMode> {
+    let mut modes = Vec::new();
+    for mode in repair {
+        let covered: &[RepairMode] = match mode {
+            Repair::Stubs => &[RepairMode::Stubs],
+            Repair::Refcounts => &[RepairMode::RefCounts],
+            Repair::Quarantine => &[RepairMode::Quarantine],
+            Repair::All => &[RepairMode::Stubs, RepairMode::RefCounts, RepairMode::Quarantine],
+        };
+        for mode in covered {
+            if !modes.contains(mode) {
+                modes.push(*mode);
+            }
+        }
+    }
+    modes
+}
+
+/// Print `value` as JSON, or `text` for people
+fn emit<T: Serialize>(json: bool, value: &T, text: impl FnOnce() -> String) {
+    match json {
+        true => match serde_json::to_string_pretty(value) {
+            Ok(out) => println!("{}", out),
+            Err(e) => eprintln!("Failed to serialize output: {}", e),
+        },
+        false => println!("{}", text()),
+    }
+}
+
+fn fail(json: bool, error: impl std::fmt::Display) -> ExitCode {
+    match json {
+        true => emit(
+            true,
+            &ErrorResponse {
+                error: error.to_string(),
+            },
+            String::new,
+        ),
+        false => eprintln!("error: {}", error),
+    }
+    ExitCode::FAILURE
+}
+
+/// The first line of `--password-file` or of stdin. Passwords are never taken as
+/// arguments, those end up in shell history and the process list.
+fn read_password(file: Option<PathBuf>) -> Result<String, String> {
+    let mut line = String::new();
+    let read = match file {
+        Some(path) => match File::open(&path) {
+            Ok(file) => BufReader::new(file).read_line(&mut line),
+            Err(e) => return Err(format!("failed to open {}: {}", path.display(), e)),
+        },
+        None => {
+            let stdin = std::io::stdin();
+            if stdin.is_terminal() {
+                eprint!("Password: ");
+            }
+            stdin.lock().read_line(&mut line)
+        }
+    };
+    match read {
+        Ok(_) => Ok(line.trim_end_matches(['\r', '\n']).to_string()),
+        Err(e) => Err(format!("failed to read password: {}", e)),
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let json = cli.json;
+    settings::load_env_file();
+    let settings = match Settings::load(&cli.settings).and_then(|s| s.validate().map(|_| s)) {
+        Ok(settings) => settings,
+        Err(e) => return fail(json, e),
+    };
+    let destination = settings.files.destination.as_path();
+    let psql_pool = match create_psql_pool(&settings.postgres).await {
+        Ok(p) => p,
+        Err(e) => return fail(json, format!("Failed to create database pool: {}", e)),
+    };
+    let mongo_pool = match create_mongo_pool(&settings.mongodb).await {
+        Ok(p) => p,
+        Err(e) => return fail(json, format!("Failed to create mongodb pool: {}", e)),
+    };
+    let mut psql_client = match psql_pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => return fail(json, format!("Failed to get database connection: {}", e)),
+    };
+
+    match cli.command {
+        Command::Init => match init::database(&psql_pool, &mongo_pool).await {
+            Ok(_) => {
+                emit(json, &serde_json::json!({"initialized": true}), || {
+                    "Database initialized".to_string()
+                });
+                ExitCode::SUCCESS
+            }
+            Err(e) => fail(json, e),
+        },
+        Command::Fsck { repair } => {
+            let store = match storage::open(&StorageConfig::from_settings(&settings), &mongo_pool) {
+                Ok(store) => store,
+                Err(e) => return fail(json, format!("Failed to open blob storage: {}", e)),
+            };
+            let findings = match fsck::check(&psql_pool, &mongo_pool, store.as_ref(), destination).await {
+                Ok(findings) => findings,
+                Err(e) => return fail(json, format!("Consistency check failed: {}", e)),
+            };
+            let modes = repair_modes(&repair);
+            let repaired = match modes.is_empty() {
+                true => Vec::new(),
+                false => {
+                    fsck::repair(
+                        &psql_pool,
+                        &mongo_pool,
+                        store.as_ref(),
+                        destination,
+                        &findings,
+                        &modes,
+                    )
+                    .await
+                }
+            };
+            let repairs: Vec<RepairOutcome> = repaired
+                .into_iter()
+                .map(|outcome| {
+                    let (ok, message) = match outcome.result {
+                        Ok(action) => (true, action),
+                        Err(e) => (false, e.to_string()),
+                    };
+                    RepairOutcome {
+                        finding: outcome.finding,
+                        ok,
+                        message,
+                    }
+                })
+                .collect();
+            let failed = repairs.iter().filter(|r| !r.ok).count();
+            let output = FsckOutput { findings, repairs };
+            emit(json, &output, || {
+                let mut lines: Vec<String> = output.findings.iter().map(|f| f.to_string()).collect();
+                lines.push(format!("{} problems found", output.findings.len()));
+                for outcome in &output.repairs {
+                    match outcome.ok {
+                        true => lines.push(format!("repaired {}: {}", outcome.finding, outcome.message)),
+                        false => lines.push(format!("failed to repair {}: {}", outcome.finding, outcome.message)),
+                    }
+                }
+                if !modes.is_empty() {
+                    lines.push(format!("{} repairs applied, {} failed", output.repairs.len() - failed, failed));
+                }
+                lines.join("\n")
+            });
+            match (modes.is_empty(), output.findings.is_empty(), failed) {
+                (true, true, _) => ExitCode::SUCCESS,
+                (true, false, _) => ExitCode::from(1),
+                (false, _, 0) => ExitCode::SUCCESS,
+                (false, _, _) => ExitCode::from(2),
+            }
+        }
+        Command::User { command } => {
+            let result = match command {
+                UserCommand::Create { username, password_file } => {
+                    let password = match read_password(password_file) {
+                        Ok(p) => p,
+                        Err(e) => return fail(json, e),
+                    };
+                    admin::create_user(&psql_client, &username, &password)
+                        .await
+                        .map(|user| (user, 0, 0))
+                }
+                UserCommand::ResetPassword { user, password_file } => {
+                    let user = match admin::find_user(&psql_client, &user).await {
+                        Ok(user) => user,
+                        Err(e) => return fail(json, e),
+                    };
+                    let password = match read_password(password_file) {
+                        Ok(p) => p,
+                        Err(e) => return fail(json, e),
+                    };
+                    admin::reset_password(&psql_client, &user.user_id, &password)
+                        .await
+                        .map(|sessions| (user, sessions, 0))
+                }
+                UserCommand::Deactivate { user } => set_active(&psql_client, &user, false).await,
+                UserCommand::Activate { user } => set_active(&psql_client, &user, true).await,
+            };
+            match result {
+                Ok((user, sessions, dev_tokens)) => {
+                    let output = UserOutput {
+                        user_id: user.user_id,
+                        username: user.username,
+                        revoked_sessions: sessions,
+                        revoked_dev_tokens: dev_tokens,
+                    };
+                    emit(json, &output, || {
+                        format!(
+                            "{} ({}): {} sessions and {} dev tokens revoked",
+                            output.username, output.user_id, output.revoked_sessions, output.revoked_dev_tokens
+                        )
+                    });
+                    ExitCode::SUCCESS
+                }
+                Err(e) => fail(json, e),
+            }
+        }
+        Command::Revoke { command } => {
+            let (user, id, sessions) = match command {
+                RevokeCommand::Sessions { user, id } => (user, id, true),
+                RevokeCommand::Tokens { user, id } => (user, id, false),
+            };
+            let user = match admin::find_user(&psql_client, &user).await {
+                Ok(user) => user,
+                Err(e) => return fail(json, e),
+            };
+            let revoked = match sessions {
+                true => admin::revoke_sessions(&psql_client, &user.user_id, id.as_ref()).await,
+                false => admin::revoke_dev_tokens(&psql_client, &user.user_id, id.as_ref()).await,
+            };
+            match revoked {
+                Ok(revoked) => {
+                    let output = RevokeOutput {
+                        user_id: user.user_id,
+                        revoked,
+                    };
+                    emit(json, &output, || format!("{} revoked for {}", revoked, user.username));
+                    ExitCode::SUCCESS
+                }
+                Err(e) => fail(json, e),
+            }
+        }
+        Command::Post {
+            command: PostCommand::Delete { post_ids },
+        } => {
+            let store = match storage::open(&StorageConfig::from_settings(&settings), &mongo_pool) {
+                Ok(store) => store,
+                Err(e) => return fail(json, format!("Failed to open blob storage: {}", e)),
+            };
+            let mut deleted = Vec::new();
+            for post_id in post_ids {
+                let result = admin::delete_post(
+                    &mut psql_client,
+                    &mongo_pool,
+                    store.as_ref(),
+                    destination,
+                    &post_id,
+                )
+                .await;
+                deleted.push(match result {
+                    Ok(filename) => DeletedPost {
+                        post_id,
+                        filename: Some(filename),
+                        error: None,
+                    },
+                    Err(e) => DeletedPost {
+                        post_id,
+                        filename: None,
+                        error: Some(e.to_string()),
+                    },
+                });
+            }
+            let failed = deleted.iter().any(|post| post.error.is_some());
+            let output = DeleteOutput { deleted };
+            emit(json, &output, || {
+                output
+                    .deleted
+                    .iter()
+                    .map(|post| match (&post.filename, &post.error) {
+                        (_, Some(error)) => format!("{}: {}", post.post_id, error),
+                        (Some(filename), None) => format!("{}: deleted {}", post.post_id, filename),
+                        (None, None) => format!("{}: deleted", post.post_id),
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            });
+            match failed {
+                true => ExitCode::FAILURE,
+                false => ExitCode::SUCCESS,
+            }
+        }
+        Command::Stats => match admin::stats(&psql_client, &mongo_pool).await {
+            Ok(stats) => {
+                emit(json, &stats, || {
+                    format!(
+                        "users: {} ({} active)\nsessions: {}\ndev tokens: {}\nposts: {} ({} documents)\nblobs: {} ({} bytes)\ntags: {}\npending repairs: {}",
+                        stats.users,
+                        stats.active_users,
+                        stats.active_sessions,
+                        stats.active_dev_tokens,
+                        stats.posts,
+                        stats.mongo_documents,
+                        stats.blobs,
+                        stats.blob_bytes,
+                        stats.tags,
+                        stats.pending_repairs
+                    )
+                });
+                ExitCode::SUCCESS
+            }
+            Err(e) => fail(json, e),
+        },
+    }
+}
+
+async fn set_active(
+    psql_client: &deadpool_postgres::Client,
+    user: &str,
+    active: bool,
+) -> Result<(admin::UserRef, u64, u64), AdminError> {
+    let user = admin::find_user(psql_client, user).await?;
+    let revoked = admin::set_active(psql_client, &user.user_id, active).await?;
+    Ok((user, revoked.sessions, revoked.dev_tokens))
+}
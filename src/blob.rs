@@ -0,0 +1,77 @@
+//! Content-addressed file storage.
+//!
+//! Uploaded files are stored once per SHA-256 in the configured [`BlobStore`]
+//! and shared by every post with the same bytes. `blob.ref_count` counts the posts
+//! referencing a blob, the bytes are removed when the last one is deleted.
+//! Posts stored before blobs existed have no `blob_hash` and live at `files.destination/<filename>`.
+//!
+//! [`BlobStore`]: crate::storage::BlobStore
+use deadpool_postgres::Transaction;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use tokio_postgres::Error;
+
+/// `ab/cd/<hash>`, two levels of two hex characters keep directories small
+pub fn blob_key(hash: &str) -> String {
+    format!("{}/{}/{}", &hash[0..2], &hash[2..4], hash)
+}
+
+/// SHA-256 and size of a file, blocking
+pub fn hash_file(path: &Path) -> io::Result<(String, u64)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut size = 0u64;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+    Ok((hex::encode(hasher.finalize()), size))
+}
+
+/// Add a reference to a blob, creating its row if needed.
+/// Returns true when the blob is new and its bytes have to be stored.
+pub async fn acquire(
+    transaction: &Transaction<'_>,
+    hash: &str,
+    size: u64,
+    content_type: &str,
+) -> Result<bool, Error> {
+    let row = transaction
+        .query_one(
+            "INSERT INTO blob (hash, size, content_type) VALUES ($1, $2, $3)
+            ON CONFLICT (hash) DO UPDATE SET ref_count = blob.ref_count + 1
+            RETURNING ref_count",
+            &[&hash, &(size as i64), &content_type],
+        )
+        .await?;
+    let ref_count: i32 = row.get(0);
+    Ok(ref_count == 1)
+}
+
+/// Drop a reference to a blob. Returns true when it was the last one and the
+/// bytes should be removed. The row stays locked until the transaction ends,
+/// so a concurrent upload of the same bytes waits for the removal.
+pub async fn release(transaction: &Transaction<'_>, hash: &str) -> Result<bool, Error> {
+    let row = transaction
+        .query_opt(
+            "UPDATE blob SET ref_count = ref_count - 1 WHERE hash = $1 RETURNING ref_count",
+            &[&hash],
+        )
+        .await?;
+    match row.map(|r| r.get::<_, i32>(0)) {
+        Some(count) if count <= 0 => {
+            transaction
+                .execute("DELETE FROM blob WHERE hash = $1", &[&hash])
+                .await?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
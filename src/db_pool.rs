@@ -1,16 +1,16 @@
 use deadpool_postgres::{Config, CreatePoolError, ManagerConfig, Pool, RecyclingMethod, Runtime};
-use mongodb::{Client, error::Error as MongoError, options::{ClientOptions, Credential, ServerAddress}};
+use mongodb::{Client, Database, error::Error as MongoError, options::{ClientOptions, Credential, ServerAddress}};
 use tokio_postgres::NoTls;
 
-use crate::{MONGODB_HOST, MONGODB_PASSWORD, MONGODB_PORT, MONGODB_USER, POSTGRES_DBNAME, POSTGRES_HOST, POSTGRES_PASSWORD, POSTGRES_PORT, POSTGRES_USER};
+use crate::settings::{MongoSettings, PostgresSettings};
 
-pub async fn create_psql_pool() -> Result<Pool,CreatePoolError> {
+pub async fn create_psql_pool(settings: &PostgresSettings) -> Result<Pool,CreatePoolError> {
     let mut cfg = Config::new();
-    cfg.user = Some(POSTGRES_USER.to_string());
-    cfg.password = Some(POSTGRES_PASSWORD.to_string());
-    cfg.host = Some(POSTGRES_HOST.to_string());
-    cfg.port = Some(POSTGRES_PORT);
-    cfg.dbname = Some(POSTGRES_DBNAME.to_string());
+    cfg.user = Some(settings.user.clone());
+    cfg.password = Some(settings.password.clone());
+    cfg.host = Some(settings.host.clone());
+    cfg.port = Some(settings.port);
+    cfg.dbname = Some(settings.dbname.clone());
     cfg.manager = Some(ManagerConfig {
         recycling_method: RecyclingMethod::Fast,
     });
@@ -18,19 +18,28 @@ pub async fn create_psql_pool() -> Result<Pool,CreatePoolError> {
     Ok(pool)
 }
 
-pub async fn create_mongo_pool() -> Result<Client,MongoError>{
+pub async fn create_mongo_pool(settings: &MongoSettings) -> Result<Client,MongoError>{
     let options = ClientOptions::builder()
-        .hosts(vec![ServerAddress::Tcp {host: MONGODB_HOST.to_string(),port: Some(MONGODB_PORT)}])
+        .hosts(vec![ServerAddress::Tcp {host: settings.host.clone(),port: Some(settings.port)}])
         .credential(
             Credential::builder()
-                .username(Some(MONGODB_USER.to_string()))
-                .password(Some(MONGODB_PASSWORD.to_string()))
+                .username(Some(settings.user.clone()))
+                .password(Some(settings.password.clone()))
                 .source(Some("admin".to_string()))
                 .build(),
         )
+        .default_database(Some(settings.dbname.clone()))
         .max_pool_size(Some(50))
         .min_pool_size(Some(5))
         .build();
     let client = Client::with_options(options)?;
     Ok(client)
-}
\ No newline at end of file
+}
+
+/// The application database, `mongodb.dbname` for clients from [`create_mongo_pool`]
+pub fn mongo_database(client: &Client) -> Database {
+    match client.default_database() {
+        Some(database) => database,
+        None => client.database(&MongoSettings::default().dbname),
+    }
+}
@@ -1,3 +1,5 @@
+use crate::scope::Scope;
+
 #[derive(Debug,)]
 pub enum DBType{
     Postgres,
@@ -7,6 +9,7 @@ pub enum DBType{
 pub enum DBError {
    QueryFailed(DBType),
    ConnectionFailed(DBType),
+   NotFound(DBType),
 }
 
 #[derive(Debug)]
@@ -14,6 +17,9 @@ pub enum AHError {
    InvalidCredential,
    UserInactive,
    AccountSuspended, 
+   PermissionDenied,
+   /// A dev token without the scope the route requires
+   MissingScope(Scope),
 }
 
 #[derive(Debug)]
@@ -37,6 +43,12 @@ impl std::fmt::Display for DBError{
                     DBType::Mongodb => write!(f, "Mongodb connection failed"),
                 }
             },
+            DBError::NotFound(db_type) => {
+                match db_type {
+                    DBType::Postgres => write!(f, "Postgres record not found"),
+                    DBType::Mongodb => write!(f, "Mongodb document not found"),
+                }
+            },
         }
     }
 }
@@ -48,6 +60,8 @@ impl std::fmt::Display for AHError{
             AHError::InvalidCredential => write!(f, "Invalid credential"),
             AHError::UserInactive => write!(f, "User account is inactive"),
             AHError::AccountSuspended => write!(f, "User account is suspended"),
+            AHError::PermissionDenied => write!(f, "Permission denied"),
+            AHError::MissingScope(scope) => write!(f, "Missing scope {}", scope),
         }
     }
 }
@@ -0,0 +1,576 @@
+//! Consistency check across the three places a post lives: the `post` table,
+//! the Mongo `post` collection and the blob store (or `files.destination` for posts
+//! stored before blobs existed).
+//!
+//! [`check`] only reads. [`repair`] re-checks every finding before touching it,
+//! but an upload in progress writes its blob before its row is committed, so the
+//! quarantine mode should be run while the server is stopped or idle. After the
+//! repairs every `repair_queue` entry with nothing left to fix is marked resolved.
+use crate::{
+    db_pool::mongo_database,
+    storage::BlobStore,
+    types::Post,
+    utility::uuid_to_binary,
+};
+use deadpool_postgres::Pool;
+use futures_util::StreamExt;
+use serde::Serialize;
+use mongodb::{
+    Client,
+    bson::{Document, RawBsonRef, doc},
+};
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+/// Where orphaned files are moved instead of being deleted
+pub fn quarantine_dir(destination: &Path) -> PathBuf {
+    destination.join("quarantine")
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Finding {
+    /// A `post` row without a Mongo document
+    MissingDocument {
+        post_id: Uuid,
+        user_id: Uuid,
+        filename: String,
+    },
+    /// A Mongo document without a `post` row
+    OrphanDocument { post_id: Uuid },
+    /// A `post` row whose bytes are not stored
+    MissingFile {
+        post_id: Uuid,
+        filename: String,
+        blob_hash: Option<String>,
+    },
+    /// `blob.ref_count` differs from the posts referencing it
+    RefCountMismatch {
+        hash: String,
+        recorded: i64,
+        actual: i64,
+    },
+    /// A stored blob without a `blob` row
+    OrphanBlob { hash: String },
+    /// A file in `files.destination` that no post owns
+    OrphanFile { path: PathBuf },
+    /// An unresolved `repair_queue` entry
+    PendingRepair {
+        repair_id: Uuid,
+        post_id: Uuid,
+        filename: String,
+        blob_hash: Option<String>,
+        failed_step: String,
+        detail: String,
+    },
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Finding::MissingDocument { post_id, filename, .. } => {
+                write!(f, "missing document: post {} ({}) has no MongoDB document", post_id, filename)
+            }
+            Finding::OrphanDocument { post_id } => {
+                write!(f, "orphan document: MongoDB document {} has no post row", post_id)
+            }
+            Finding::MissingFile {
+                post_id,
+                filename,
+                blob_hash,
+            } => match blob_hash {
+                Some(hash) => write!(f, "missing file: post {} ({}) references blob {} which is not stored", post_id, filename, hash),
+                None => write!(f, "missing file: post {} has no stored file {}", post_id, filename),
+            },
+            Finding::RefCountMismatch {
+                hash,
+                recorded,
+                actual,
+            } => write!(
+                f,
+                "ref count mismatch: blob {} records {} references but {} posts use it",
+                hash, recorded, actual
+            ),
+            Finding::OrphanBlob { hash } => {
+                write!(f, "orphan blob: {} is stored but has no blob row", hash)
+            }
+            Finding::OrphanFile { path } => {
+                write!(f, "orphan file: {} belongs to no post", path.display())
+            }
+            Finding::PendingRepair {
+                repair_id,
+                post_id,
+                filename,
+                failed_step,
+                detail,
+                ..
+            } => write!(
+                f,
+                "pending repair {}: {} step of post {} ({}): {}",
+                repair_id, failed_step, post_id, filename, detail
+            ),
+        }
+    }
+}
+
+fn to_io<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+async fn document_ids(mongo_pool: &Client) -> io::Result<HashSet<Uuid>> {
+    let mut found = mongo_database(mongo_pool)
+        .collection::<Document>("post")
+        .find(doc! {})
+        .projection(doc! {"post_id": 1, "_id": 0})
+        .await
+        .map_err(to_io)?;
+    let mut ids = HashSet::new();
+    while found.advance().await.map_err(to_io)? {
+        if let Ok(Some(RawBsonRef::Binary(bin))) = found.current().get("post_id")
+            && let Ok(id) = Uuid::from_slice(bin.bytes)
+        {
+            ids.insert(id);
+        }
+    }
+    Ok(ids)
+}
+
+/// Post id of a thumbnail, `{post_id}.thumb-{size}.webp`
+fn thumbnail_owner(name: &str) -> Option<Uuid> {
+    let (id, rest) = name.split_once('.')?;
+    match rest.starts_with("thumb-") {
+        true => Uuid::parse_str(id).ok(),
+        false => None,
+    }
+}
+
+/// Compare every store against the others
+pub async fn check(
+    psql_pool: &Pool,
+    mongo_pool: &Client,
+    store: &dyn BlobStore,
+    destination: &Path,
+) -> io::Result<Vec<Finding>> {
+    let client = psql_pool.get().await.map_err(to_io)?;
+    let mut findings = Vec::new();
+
+    let posts = client
+        .query("SELECT post_id, user_id, filename, blob_hash FROM post", &[])
+        .await
+        .map_err(to_io)?;
+    let documents = document_ids(mongo_pool).await?;
+    let stored: HashSet<String> = store.list().await?.into_iter().collect();
+    let mut post_ids = HashSet::new();
+    let mut legacy_files = HashSet::new();
+    for row in &posts {
+        let post_id: Uuid = row.get(0);
+        let filename: String = row.get(2);
+        let blob_hash: Option<String> = row.get(3);
+        post_ids.insert(post_id);
+        if !documents.contains(&post_id) {
+            findings.push(Finding::MissingDocument {
+                post_id,
+                user_id: row.get(1),
+                filename: filename.clone(),
+            });
+        }
+        let present = match &blob_hash {
+            Some(hash) => stored.contains(hash),
+            None => tokio::fs::try_exists(destination.join(&filename)).await?,
+        };
+        if !present {
+            findings.push(Finding::MissingFile {
+                post_id,
+                filename: filename.clone(),
+                blob_hash: blob_hash.clone(),
+            });
+        }
+        if blob_hash.is_none() {
+            legacy_files.insert(filename);
+        }
+    }
+    for post_id in &documents {
+        if !post_ids.contains(post_id) {
+            findings.push(Finding::OrphanDocument { post_id: *post_id });
+        }
+    }
+
+    let blobs = client
+        .query(
+            "SELECT b.hash, b.ref_count, COUNT(p.post_id) FROM blob b
+            LEFT JOIN post p ON p.blob_hash = b.hash
+            GROUP BY b.hash, b.ref_count",
+            &[],
+        )
+        .await
+        .map_err(to_io)?;
+    let mut blob_rows = HashSet::new();
+    for row in &blobs {
+        let hash: String = row.get(0);
+        let recorded = row.get::<_, i32>(1) as i64;
+        let actual: i64 = row.get(2);
+        if recorded != actual {
+            findings.push(Finding::RefCountMismatch {
+                hash: hash.clone(),
+                recorded,
+                actual,
+            });
+        }
+        blob_rows.insert(hash);
+    }
+    let mut orphan_blobs: Vec<&String> = stored.iter().filter(|h| !blob_rows.contains(*h)).collect();
+    orphan_blobs.sort();
+    for hash in orphan_blobs {
+        findings.push(Finding::OrphanBlob { hash: hash.clone() });
+    }
+
+    //only regular files at the top level, the blob, spool and tus directories are not ours
+    let mut orphan_files = Vec::new();
+    let mut entries = match tokio::fs::read_dir(destination).await {
+        Ok(entries) => Some(entries),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    while let Some(entries) = entries.as_mut()
+        && let Some(entry) = entries.next_entry().await?
+    {
+        if !entry.file_type().await?.is_file() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let owned = match thumbnail_owner(&name) {
+            Some(post_id) => post_ids.contains(&post_id),
+            None => legacy_files.contains(&name),
+        };
+        if !owned {
+            orphan_files.push(entry.path());
+        }
+    }
+    orphan_files.sort();
+    for path in orphan_files {
+        findings.push(Finding::OrphanFile { path });
+    }
+
+    let repairs = client
+        .query(
+            "SELECT repair_id, post_id, filename, blob_hash, failed_step, detail FROM repair_queue
+            WHERE resolved_at IS NULL ORDER BY created_at",
+            &[],
+        )
+        .await
+        .map_err(to_io)?;
+    for row in &repairs {
+        findings.push(Finding::PendingRepair {
+            repair_id: row.get(0),
+            post_id: row.get(1),
+            filename: row.get(2),
+            blob_hash: row.get(3),
+            failed_step: row.get(4),
+            detail: row.get(5),
+        });
+    }
+    Ok(findings)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepairMode {
+    /// Insert a metadata stub for rows without a Mongo document
+    Stubs,
+    /// Recount blob references, dropping rows nothing references
+    RefCounts,
+    /// Move orphaned blobs and files to [`quarantine_dir`]
+    Quarantine,
+}
+
+/// Outcome of one repair action
+pub struct Repaired {
+    pub finding: Finding,
+    pub result: io::Result<String>,
+}
+
+async fn insert_stub(
+    psql_pool: &Pool,
+    mongo_pool: &Client,
+    post_id: &Uuid,
+    user_id: &Uuid,
+) -> io::Result<String> {
+    let client = psql_pool.get().await.map_err(to_io)?;
+    let exists = client
+        .query_opt("SELECT 1 FROM post WHERE post_id = $1", &[post_id])
+        .await
+        .map_err(to_io)?;
+    if exists.is_none() {
+        return Ok("post no longer exists, skipped".to_string());
+    }
+    let coll = mongo_database(mongo_pool)
+        .collection::<Post>("post");
+    let found = coll
+        .find_one(doc! {"post_id": uuid_to_binary(post_id)})
+        .await
+        .map_err(to_io)?;
+    if found.is_some() {
+        return Ok("document exists now, skipped".to_string());
+    }
+    let stub = Post {
+        post_id: *post_id,
+        title: String::new(),
+        creator: String::new(),
+        source: String::new(),
+        description: String::new(),
+        uploader: *user_id,
+        stripped_metadata: Vec::new(),
+    };
+    coll.insert_one(stub).await.map_err(to_io)?;
+    Ok("inserted metadata stub".to_string())
+}
+
+/// Returns the new count, rows nothing references are deleted
+async fn recount(psql_pool: &Pool, hash: &str) -> io::Result<i64> {
+    let client = psql_pool.get().await.map_err(to_io)?;
+    let row = client
+        .query_opt(
+            "UPDATE blob SET ref_count = (SELECT COUNT(*) FROM post WHERE blob_hash = $1)
+            WHERE hash = $1 RETURNING ref_count",
+            &[&hash],
+        )
+        .await
+        .map_err(to_io)?;
+    let count = match row {
+        Some(row) => row.get::<_, i32>(0) as i64,
+        None => return Ok(0),
+    };
+    if count == 0 {
+        client
+            .execute(
+                "DELETE FROM blob WHERE hash = $1 AND NOT EXISTS (SELECT 1 FROM post WHERE blob_hash = $1)",
+                &[&hash],
+            )
+            .await
+            .map_err(to_io)?;
+    }
+    Ok(count)
+}
+
+async fn quarantine_blob(
+    psql_pool: &Pool,
+    store: &dyn BlobStore,
+    destination: &Path,
+    hash: &str,
+) -> io::Result<String> {
+    let client = psql_pool.get().await.map_err(to_io)?;
+    let row = client
+        .query_opt("SELECT 1 FROM blob WHERE hash = $1", &[&hash])
+        .await
+        .map_err(to_io)?;
+    if row.is_some() {
+        return Ok("blob row exists now, skipped".to_string());
+    }
+    let mut reader = match store.get(hash).await? {
+        Some(reader) => reader,
+        None => return Ok("already gone".to_string()),
+    };
+    let dir = quarantine_dir(destination).join("blobs");
+    tokio::fs::create_dir_all(&dir).await?;
+    let target = dir.join(hash);
+    let mut file = tokio::fs::File::create(&target).await?;
+    while let Some(chunk) = reader.stream.next().await {
+        file.write_all(&chunk?).await?;
+    }
+    file.flush().await?;
+    store.delete(hash).await?;
+    Ok(format!("moved to {}", target.display()))
+}
+
+async fn quarantine_file(destination: &Path, path: &Path) -> io::Result<String> {
+    let dir = quarantine_dir(destination).join("files");
+    tokio::fs::create_dir_all(&dir).await?;
+    let name = match path.file_name() {
+        Some(name) => name,
+        None => return Err(io::Error::other("not a file")),
+    };
+    let target = dir.join(name);
+    //rename fails across filesystems, fall back to a copy
+    if tokio::fs::rename(path, &target).await.is_err() {
+        tokio::fs::copy(path, &target).await?;
+        tokio::fs::remove_file(path).await?;
+    }
+    Ok(format!("moved to {}", target.display()))
+}
+
+/// Whether `finding` still shows state a queued repair left behind
+fn leaves_behind(finding: &Finding, post_id: &Uuid, filename: &str, blob_hash: Option<&str>) -> bool {
+    match finding {
+        Finding::MissingDocument { post_id: id, .. }
+        | Finding::OrphanDocument { post_id: id }
+        | Finding::MissingFile { post_id: id, .. } => id == post_id,
+        Finding::OrphanBlob { hash } => blob_hash == Some(hash.as_str()),
+        Finding::OrphanFile { path } => blob_hash.is_none() && path.file_name().is_some_and(|name| name == filename),
+        Finding::RefCountMismatch { .. } | Finding::PendingRepair { .. } => false,
+    }
+}
+
+/// The `repair_queue` entries among `findings` that nothing in `remaining` refers to
+fn settled_repairs<'a>(findings: &'a [Finding], remaining: &[Finding]) -> Vec<&'a Finding> {
+    findings
+        .iter()
+        .filter(|finding| match finding {
+            Finding::PendingRepair {
+                post_id,
+                filename,
+                blob_hash,
+                ..
+            } => !remaining
+                .iter()
+                .any(|left| leaves_behind(left, post_id, filename, blob_hash.as_deref())),
+            _ => false,
+        })
+        .collect()
+}
+
+/// Check again and mark the `repair_queue` entries whose post is consistent now
+async fn resolve_settled(
+    psql_pool: &Pool,
+    mongo_pool: &Client,
+    store: &dyn BlobStore,
+    destination: &Path,
+    findings: &[Finding],
+) -> Vec<Repaired> {
+    if !findings.iter().any(|f| matches!(f, Finding::PendingRepair { .. })) {
+        return Vec::new();
+    }
+    let remaining = match check(psql_pool, mongo_pool, store, destination).await {
+        Ok(remaining) => remaining,
+        Err(e) => {
+            eprintln!("Failed to re-check before resolving queued repairs: {}", e);
+            return Vec::new();
+        }
+    };
+    let mut resolved = Vec::new();
+    for finding in settled_repairs(findings, &remaining) {
+        let Finding::PendingRepair { repair_id, .. } = finding else {
+            continue;
+        };
+        let result = match psql_pool.get().await {
+            Ok(client) => client
+                .execute(
+                    "UPDATE repair_queue SET resolved_at = NOW() WHERE repair_id = $1 AND resolved_at IS NULL",
+                    &[repair_id],
+                )
+                .await
+                .map(|_| "nothing left to repair, resolved".to_string())
+                .map_err(to_io),
+            Err(e) => Err(to_io(e)),
+        };
+        resolved.push(Repaired {
+            finding: finding.clone(),
+            result,
+        });
+    }
+    resolved
+}
+
+/// Apply the `modes` that cover each finding, then resolve the queued repairs
+/// nothing is left of. Other findings no mode covers are left alone.
+pub async fn repair(
+    psql_pool: &Pool,
+    mongo_pool: &Client,
+    store: &dyn BlobStore,
+    destination: &Path,
+    findings: &[Finding],
+    modes: &[RepairMode],
+) -> Vec<Repaired> {
+    let mut repaired = Vec::new();
+    //blob rows dropped by the recount leave their bytes behind
+    let mut released = Vec::new();
+    for finding in findings {
+        let result = match finding {
+            Finding::MissingDocument {
+                post_id, user_id, ..
+            } if modes.contains(&RepairMode::Stubs) => {
+                insert_stub(psql_pool, mongo_pool, post_id, user_id).await
+            }
+            Finding::RefCountMismatch { hash, .. } if modes.contains(&RepairMode::RefCounts) => {
+                match recount(psql_pool, hash).await {
+                    Ok(0) => {
+                        released.push(hash.clone());
+                        Ok("removed unreferenced blob row".to_string())
+                    }
+                    Ok(count) => Ok(format!("ref count set to {}", count)),
+                    Err(e) => Err(e),
+                }
+            }
+            Finding::OrphanBlob { hash } if modes.contains(&RepairMode::Quarantine) => {
+                quarantine_blob(psql_pool, store, destination, hash).await
+            }
+            Finding::OrphanFile { path } if modes.contains(&RepairMode::Quarantine) => {
+                quarantine_file(destination, path).await
+            }
+            _ => continue,
+        };
+        repaired.push(Repaired {
+            finding: finding.clone(),
+            result,
+        });
+    }
+    if modes.contains(&RepairMode::Quarantine) {
+        for hash in released {
+            let result = quarantine_blob(psql_pool, store, destination, &hash).await;
+            repaired.push(Repaired {
+                finding: Finding::OrphanBlob { hash },
+                result,
+            });
+        }
+    }
+    repaired.extend(resolve_settled(psql_pool, mongo_pool, store, destination, findings).await);
+    repaired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(post_id: Uuid, blob_hash: Option<&str>, failed_step: &str) -> Finding {
+        Finding::PendingRepair {
+            repair_id: Uuid::new_v4(),
+            post_id,
+            filename: format!("{}.png", post_id),
+            blob_hash: blob_hash.map(str::to_string),
+            failed_step: failed_step.to_string(),
+            detail: String::new(),
+        }
+    }
+
+    #[test]
+    fn repaired_entries_leave_the_pending_set() {
+        let post_id = Uuid::new_v4();
+        let entry = pending(post_id, Some("abcd"), "file");
+        let findings = vec![Finding::OrphanBlob { hash: "abcd".to_string() }, entry.clone()];
+        //before the blob is quarantined the entry stays pending
+        let remaining = findings.clone();
+        assert!(settled_repairs(&findings, &remaining).is_empty());
+        //afterwards only the entry itself is found
+        let remaining = vec![entry];
+        let settled = settled_repairs(&findings, &remaining);
+        assert_eq!(settled.len(), 1);
+        assert!(matches!(settled[0], Finding::PendingRepair { post_id: id, .. } if *id == post_id));
+    }
+
+    #[test]
+    fn entries_stay_pending_while_their_post_is_inconsistent() {
+        let post_id = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        let findings = vec![pending(post_id, None, "mongodb"), pending(other, None, "postgres")];
+        let remaining = vec![
+            Finding::OrphanDocument { post_id },
+            Finding::OrphanFile {
+                path: PathBuf::from(format!("/files/{}.png", post_id)),
+            },
+        ];
+        let settled = settled_repairs(&findings, &remaining);
+        assert_eq!(settled.len(), 1);
+        assert!(matches!(settled[0], Finding::PendingRepair { post_id: id, .. } if *id == other));
+    }
+}
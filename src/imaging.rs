@@ -0,0 +1,124 @@
+pub mod analysis;
+pub mod cache;
+pub mod phash;
+pub mod strip;
+pub mod thumbnail;
+pub mod transform;
+
+use crate::storage::{self, BlobStore};
+use phash::SimilarityIndex;
+use crate::types::ImageAnalysis;
+use deadpool_postgres::Pool;
+use image::{DynamicImage, ImageDecoder, ImageReader, ImageResult, Limits};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Content types the image pipeline can decode
+pub const DECODABLE_TYPES: [&str; 4] = ["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+pub fn is_decodable(content_type: &str) -> bool {
+    DECODABLE_TYPES.contains(&content_type)
+}
+
+/// Content types whose metadata can be stripped on upload
+pub fn is_strippable(content_type: &str) -> bool {
+    matches!(content_type, "image/jpeg" | "image/png" | "image/webp")
+}
+
+/// Largest source width or height the pipeline will decode
+pub const MAX_SOURCE_DIMENSION: u32 = 16384;
+
+/// Decoder limits for untrusted sources
+pub fn source_limits() -> Limits {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_SOURCE_DIMENSION);
+    limits.max_image_height = Some(MAX_SOURCE_DIMENSION);
+    limits
+}
+
+/// Decode an image and rotate it upright according to its EXIF orientation
+pub fn decode_oriented(path: &Path) -> ImageResult<DynamicImage> {
+    let mut reader = ImageReader::open(path)?.with_guessed_format()?;
+    reader.limits(source_limits());
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+/// A freshly stored image post
+pub struct StoredImage {
+    pub post_id: Uuid,
+    pub filename: String,
+    pub blob_hash: String,
+    pub content_type: String,
+}
+
+/// Thumbnails and analysis of a freshly stored image, run in the background.
+/// Failures are logged, thumbnails are regenerated on demand later.
+/// With `similarity` the perceptual hash is computed here too, for images
+/// that were not on local disk during the upload.
+pub fn spawn_post_processing(
+    psql_pool: Pool,
+    store: Arc<dyn BlobStore>,
+    destination: PathBuf,
+    image: StoredImage,
+    similarity: Option<Arc<SimilarityIndex>>,
+) {
+    let StoredImage {
+        post_id,
+        filename,
+        blob_hash,
+        content_type,
+    } = image;
+    tokio::spawn(async move {
+        let source = match storage::fetch_local(store.as_ref(), &destination, &filename, Some(&blob_hash)).await {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("Failed to fetch {} for processing: {}", filename, e);
+                return;
+            }
+        };
+        let needs_hash = similarity.is_some();
+        let processed = tokio::task::spawn_blocking(move || -> ImageResult<(ImageAnalysis, Option<u64>)> {
+            let image = decode_oriented(&source.path)?;
+            let hash = needs_hash.then(|| phash::dhash(&image));
+            match thumbnail::write_all(&image, &destination, &post_id) {
+                Ok(_) => println!("Thumbnails generated for {}", post_id),
+                Err(e) => eprintln!("Thumbnail generation failed for {}: {}", post_id, e),
+            }
+            Ok((analysis::analyze(&source.path, &content_type, &image)?, hash))
+        })
+        .await;
+        let (result, hash) = match processed {
+            Ok(Ok(processed)) => processed,
+            Ok(Err(e)) => {
+                eprintln!("Image processing failed for {}: {}", post_id, e);
+                return;
+            }
+            Err(e) => {
+                eprintln!("Image processing task failed for {}: {}", post_id, e);
+                return;
+            }
+        };
+        let client = match psql_pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("Failed to get connection from pool: {}", e);
+                return;
+            }
+        };
+        match analysis::store(&client, &post_id, &result).await {
+            Ok(_) => println!("Analysis stored for {}", post_id),
+            Err(e) => eprintln!("Failed to store analysis for {}: {}", post_id, e),
+        }
+        if let (Some(similarity), Some(hash)) = (similarity, hash) {
+            match phash::store(&client, &post_id, hash).await {
+                Ok(_) => similarity.insert(post_id, hash),
+                Err(e) => eprintln!("Failed to store perceptual hash of {}: {}", post_id, e),
+            }
+        }
+    });
+}
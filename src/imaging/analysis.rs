@@ -0,0 +1,274 @@
+use crate::imaging::{is_decodable, source_limits};
+use crate::types::{AnalysisStatus, CameraInfo, ImageAnalysis};
+use exif::{Exif, In, Tag, Value};
+use image::{
+    AnimationDecoder, DynamicImage, Frames, ImageDecoder, ImageReader, ImageResult,
+    codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
+};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use uuid::Uuid;
+
+/// Number of colours reported in the palette
+pub const PALETTE_SIZE: usize = 5;
+
+/// Frames are counted up to this many, longer animations report this value
+pub const MAX_COUNTED_FRAMES: usize = 1000;
+
+/// Frames of an animated image. Each frame is decoded under [`source_limits`] and
+/// counting stops at [`MAX_COUNTED_FRAMES`], so a frame bomb cannot run unbounded.
+fn frame_count(path: &Path, content_type: &str) -> ImageResult<u32> {
+    let reader = BufReader::new(File::open(path)?);
+    let count = match content_type {
+        "image/gif" => {
+            let mut decoder = GifDecoder::new(reader)?;
+            decoder.set_limits(source_limits())?;
+            count_frames(decoder.into_frames())
+        }
+        "image/webp" => {
+            let mut decoder = WebPDecoder::new(reader)?;
+            decoder.set_limits(source_limits())?;
+            match decoder.has_animation() {
+                true => count_frames(decoder.into_frames()),
+                false => 1,
+            }
+        }
+        "image/png" => {
+            let mut decoder = PngDecoder::new(reader)?;
+            decoder.set_limits(source_limits())?;
+            match decoder.is_apng()? {
+                true => count_frames(decoder.apng()?.into_frames()),
+                false => 1,
+            }
+        }
+        _ => 1,
+    };
+    Ok(count.max(1) as u32)
+}
+
+/// Frames decoded before the first error, e.g. a truncated file or a frame over the limits
+fn count_frames(frames: Frames<'_>) -> usize {
+    frames
+        .take(MAX_COUNTED_FRAMES)
+        .take_while(|frame| frame.is_ok())
+        .count()
+}
+
+fn exif_text(exif: &Exif, tag: Tag) -> Option<String> {
+    let field = exif.get_field(tag, In::PRIMARY)?;
+    let text = match &field.value {
+        Value::Ascii(parts) => parts
+            .first()
+            .map(|p| String::from_utf8_lossy(p).trim_matches(char::from(0)).trim().to_string())?,
+        _ => field.display_value().with_unit(exif).to_string(),
+    };
+    match text.is_empty() {
+        true => None,
+        false => Some(text),
+    }
+}
+
+fn camera_info(path: &Path) -> Option<CameraInfo> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+    let camera = CameraInfo {
+        make: exif_text(&exif, Tag::Make),
+        model: exif_text(&exif, Tag::Model),
+        lens_model: exif_text(&exif, Tag::LensModel),
+        exposure_time: exif_text(&exif, Tag::ExposureTime),
+        f_number: exif_text(&exif, Tag::FNumber),
+        iso: exif_text(&exif, Tag::PhotographicSensitivity),
+        focal_length: exif_text(&exif, Tag::FocalLength),
+        taken_at: exif_text(&exif, Tag::DateTimeOriginal),
+    };
+    non_empty(camera)
+}
+
+fn non_empty(camera: CameraInfo) -> Option<CameraInfo> {
+    let empty = [
+        &camera.make,
+        &camera.model,
+        &camera.lens_model,
+        &camera.exposure_time,
+        &camera.f_number,
+        &camera.iso,
+        &camera.focal_length,
+        &camera.taken_at,
+    ]
+    .iter()
+    .all(|v| v.is_none());
+    match empty {
+        true => None,
+        false => Some(camera),
+    }
+}
+
+/// Most common colours as `#rrggbb`, from a 4-bit-per-channel histogram of a downscaled copy
+pub fn dominant_palette(image: &DynamicImage) -> Vec<String> {
+    let small = image.thumbnail(64, 64).to_rgb8();
+    let mut buckets: HashMap<(u8, u8, u8), (u64, [u64; 3])> = HashMap::new();
+    for pixel in small.pixels() {
+        let [r, g, b] = pixel.0;
+        let entry = buckets.entry((r >> 4, g >> 4, b >> 4)).or_insert((0, [0; 3]));
+        entry.0 += 1;
+        entry.1[0] += r as u64;
+        entry.1[1] += g as u64;
+        entry.1[2] += b as u64;
+    }
+    let mut buckets: Vec<(u64, [u64; 3])> = buckets.into_values().collect();
+    buckets.sort_by_key(|b| std::cmp::Reverse(b.0));
+    buckets
+        .iter()
+        .take(PALETTE_SIZE)
+        .map(|(count, sum)| {
+            format!(
+                "#{:02x}{:02x}{:02x}",
+                sum[0] / count,
+                sum[1] / count,
+                sum[2] / count
+            )
+        })
+        .collect()
+}
+
+/// Analyze a stored image, blocking. `image` is the decoded, upright copy.
+pub fn analyze(path: &Path, content_type: &str, image: &DynamicImage) -> ImageResult<ImageAnalysis> {
+    let mut reader = ImageReader::open(path)?.with_guessed_format()?;
+    reader.limits(source_limits());
+    let mut decoder = reader.into_decoder()?;
+    let orientation = exif_orientation(decoder.orientation()?);
+    let color_type = decoder.original_color_type();
+    let bit_depth = color_type.bits_per_pixel() / color_type.channel_count().max(1) as u16;
+    Ok(ImageAnalysis {
+        width: image.width(),
+        height: image.height(),
+        orientation,
+        bit_depth,
+        frame_count: frame_count(path, content_type).unwrap_or(1),
+        camera: camera_info(path),
+        palette: dominant_palette(image),
+    })
+}
+
+fn exif_orientation(orientation: image::metadata::Orientation) -> u8 {
+    use image::metadata::Orientation::*;
+    match orientation {
+        NoTransforms => 1,
+        FlipHorizontal => 2,
+        Rotate180 => 3,
+        FlipVertical => 4,
+        Rotate90FlipH => 5,
+        Rotate90 => 6,
+        Rotate270FlipH => 7,
+        Rotate270 => 8,
+    }
+}
+
+pub async fn store(
+    psql_client: &deadpool_postgres::Client,
+    post_id: &Uuid,
+    analysis: &ImageAnalysis,
+) -> Result<(), tokio_postgres::Error> {
+    let camera = analysis.camera.clone().unwrap_or_default();
+    let query = r#"
+        INSERT INTO "post_analysis" (post_id, width, height, orientation, bit_depth, frame_count,
+            camera_make, camera_model, lens_model, exposure_time, f_number, iso, focal_length, taken_at, palette)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)
+        ON CONFLICT (post_id) DO UPDATE SET
+            width = EXCLUDED.width, height = EXCLUDED.height, orientation = EXCLUDED.orientation,
+            bit_depth = EXCLUDED.bit_depth, frame_count = EXCLUDED.frame_count,
+            camera_make = EXCLUDED.camera_make, camera_model = EXCLUDED.camera_model,
+            lens_model = EXCLUDED.lens_model, exposure_time = EXCLUDED.exposure_time,
+            f_number = EXCLUDED.f_number, iso = EXCLUDED.iso, focal_length = EXCLUDED.focal_length,
+            taken_at = EXCLUDED.taken_at, palette = EXCLUDED.palette, analyzed_at = NOW()
+    "#;
+    psql_client
+        .execute(
+            query,
+            &[
+                post_id,
+                &(analysis.width as i32),
+                &(analysis.height as i32),
+                &(analysis.orientation as i16),
+                &(analysis.bit_depth as i16),
+                &(analysis.frame_count as i32),
+                &camera.make,
+                &camera.model,
+                &camera.lens_model,
+                &camera.exposure_time,
+                &camera.f_number,
+                &camera.iso,
+                &camera.focal_length,
+                &camera.taken_at,
+                &analysis.palette,
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+/// Status of a post's analysis given its content type and whether a result is stored
+pub fn status(content_type: &str, analysed: bool) -> AnalysisStatus {
+    match (analysed, is_decodable(content_type)) {
+        (true, _) => AnalysisStatus::Complete,
+        (false, true) => AnalysisStatus::Pending,
+        (false, false) => AnalysisStatus::Unsupported,
+    }
+}
+
+pub async fn fetch(
+    psql_client: &deadpool_postgres::Client,
+    post_id: &Uuid,
+) -> Result<(AnalysisStatus, Option<ImageAnalysis>), tokio_postgres::Error> {
+    let query = r#"
+        SELECT a.width, a.height, a.orientation, a.bit_depth, a.frame_count, a.camera_make,
+            a.camera_model, a.lens_model, a.exposure_time, a.f_number, a.iso, a.focal_length,
+            a.taken_at, a.palette, p.content_type
+        FROM "post" p LEFT JOIN "post_analysis" a ON a.post_id = p.post_id
+        WHERE p.post_id = $1
+    "#;
+    let row = match psql_client.query_opt(query, &[post_id]).await? {
+        Some(row) => row,
+        None => return Ok((AnalysisStatus::Pending, None)),
+    };
+    let content_type: String = row.get(14);
+    let width = match row.get::<_, Option<i32>>(0) {
+        Some(width) => width,
+        None => return Ok((status(&content_type, false), None)),
+    };
+    let camera = CameraInfo {
+        make: row.get(5),
+        model: row.get(6),
+        lens_model: row.get(7),
+        exposure_time: row.get(8),
+        f_number: row.get(9),
+        iso: row.get(10),
+        focal_length: row.get(11),
+        taken_at: row.get(12),
+    };
+    let analysis = ImageAnalysis {
+        width: width as u32,
+        height: row.get::<_, i32>(1) as u32,
+        orientation: row.get::<_, i16>(2) as u8,
+        bit_depth: row.get::<_, i16>(3) as u16,
+        frame_count: row.get::<_, i32>(4) as u32,
+        camera: non_empty(camera),
+        palette: row.get(13),
+    };
+    Ok((status(&content_type, true), Some(analysis)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undecodable_types_report_unsupported() {
+        assert_eq!(status("image/avif", false), AnalysisStatus::Unsupported);
+        assert_eq!(status("video/mp4", false), AnalysisStatus::Unsupported);
+        assert_eq!(status("image/png", false), AnalysisStatus::Pending);
+        assert_eq!(status("image/png", true), AnalysisStatus::Complete);
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tokio::sync::Semaphore;
+use uuid::Uuid;
+
+/// Concurrent transforms allowed at once, the rest wait for a permit
+pub const MAX_CONCURRENT_TRANSFORMS: usize = 2;
+
+struct Entry {
+    size: u64,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, Entry>,
+    //last_used tick -> file name, oldest first
+    order: BTreeMap<u64, String>,
+    total_bytes: u64,
+    tick: u64,
+}
+
+impl CacheState {
+    fn touch(&mut self, name: &str) -> bool {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(name) {
+            Some(entry) => {
+                self.order.remove(&entry.last_used);
+                entry.last_used = tick;
+                self.order.insert(tick, name.to_string());
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, name: String, size: u64) {
+        if let Some(old) = self.entries.remove(&name) {
+            self.order.remove(&old.last_used);
+            self.total_bytes -= old.size;
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, name.clone());
+        self.entries.insert(
+            name,
+            Entry {
+                size,
+                last_used: self.tick,
+            },
+        );
+        self.total_bytes += size;
+    }
+
+    /// Pop least recently used entries until the cache fits in `max_bytes`.
+    /// The newest entry is always kept so it can still be served.
+    fn evict(&mut self, max_bytes: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.total_bytes > max_bytes && self.order.len() > 1 {
+            let Some((_, name)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&name) {
+                self.total_bytes -= entry.size;
+            }
+            evicted.push(name);
+        }
+        evicted
+    }
+}
+
+/// Size-capped on-disk cache of transformed images with LRU eviction
+pub struct DerivedCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    state: Mutex<CacheState>,
+    pub permits: Semaphore,
+}
+
+impl DerivedCache {
+    /// Open the cache directory and index files left from a previous run, oldest first
+    pub fn open(dir: &Path, max_bytes: u64) -> std::io::Result<DerivedCache> {
+        let dir = dir.to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        let mut found: Vec<(SystemTime, String, u64)> = Vec::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.ends_with(".partial") {
+                let _ = std::fs::remove_file(entry.path());
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                let used = metadata
+                    .accessed()
+                    .or_else(|_| metadata.modified())
+                    .unwrap_or(SystemTime::UNIX_EPOCH);
+                found.push((used, name, metadata.len()));
+            }
+        }
+        found.sort();
+        let mut state = CacheState::default();
+        for (_, name, size) in found {
+            state.insert(name, size);
+        }
+        let cache = DerivedCache {
+            dir,
+            max_bytes,
+            state: Mutex::new(state),
+            permits: Semaphore::new(MAX_CONCURRENT_TRANSFORMS),
+        };
+        cache.remove_evicted();
+        Ok(cache)
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// Unique temporary path to write a new entry to before `insert`
+    pub fn partial_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}.partial", name, Uuid::new_v4()))
+    }
+
+    /// Path of a cached entry, marking it as recently used
+    pub fn get(&self, name: &str) -> Option<PathBuf> {
+        let hit = self.state.lock().map(|mut s| s.touch(name)).unwrap_or(false);
+        match hit && self.path(name).exists() {
+            true => Some(self.path(name)),
+            false => None,
+        }
+    }
+
+    /// Move a finished `partial` file into the cache and evict old entries
+    pub fn insert(&self, name: &str, partial: &Path) -> std::io::Result<PathBuf> {
+        let path = self.path(name);
+        std::fs::rename(partial, &path)?;
+        let size = std::fs::metadata(&path)?.len();
+        if let Ok(mut state) = self.state.lock() {
+            state.insert(name.to_string(), size);
+        }
+        self.remove_evicted();
+        Ok(path)
+    }
+
+    fn remove_evicted(&self) {
+        let evicted = match self.state.lock() {
+            Ok(mut state) => state.evict(self.max_bytes),
+            Err(_) => return,
+        };
+        for name in evicted {
+            if let Err(e) = std::fs::remove_file(self.path(&name)) {
+                eprintln!("Failed to evict {} from derived cache: {}", name, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use super::decode_oriented;
+use deadpool_postgres::Pool;
+use image::{DynamicImage, ImageResult, imageops::FilterType};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use uuid::Uuid;
+
+/// Hamming distance at or below which an upload is reported as a possible duplicate
+pub const DUPLICATE_DISTANCE: u32 = 6;
+/// Default and maximum distance for `GET /item/{id}/similar`
+pub const SIMILAR_DISTANCE: u32 = 12;
+pub const MAX_SIMILAR_DISTANCE: u32 = 20;
+
+/// 64-bit difference hash: each bit says whether a pixel of a 9x8 grayscale
+/// copy is brighter than its right neighbour
+pub fn dhash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y).0[0] > small.get_pixel(x + 1, y).0[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// Decode a stored image and hash it, blocking
+pub fn hash_file(path: &Path) -> ImageResult<u64> {
+    Ok(dhash(&decode_oriented(path)?))
+}
+
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+struct Node {
+    hash: u64,
+    post_ids: Vec<Uuid>,
+    children: HashMap<u32, usize>,
+}
+
+/// BK-tree over Hamming distance, nodes live in an arena
+#[derive(Default)]
+struct BkTree {
+    nodes: Vec<Node>,
+}
+
+impl BkTree {
+    fn insert(&mut self, hash: u64, post_id: Uuid) {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node {
+                hash,
+                post_ids: vec![post_id],
+                children: HashMap::new(),
+            });
+            return;
+        }
+        let mut current = 0;
+        loop {
+            let d = distance(self.nodes[current].hash, hash);
+            if d == 0 {
+                self.nodes[current].post_ids.push(post_id);
+                return;
+            }
+            match self.nodes[current].children.get(&d) {
+                Some(next) => current = *next,
+                None => {
+                    let index = self.nodes.len();
+                    self.nodes.push(Node {
+                        hash,
+                        post_ids: vec![post_id],
+                        children: HashMap::new(),
+                    });
+                    self.nodes[current].children.insert(d, index);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn find(&self, hash: u64, max_distance: u32) -> Vec<(Uuid, u32)> {
+        let mut found = Vec::new();
+        if self.nodes.is_empty() {
+            return found;
+        }
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            let d = distance(node.hash, hash);
+            if d <= max_distance {
+                found.extend(node.post_ids.iter().map(|id| (*id, d)));
+            }
+            for (edge, child) in &node.children {
+                if edge.abs_diff(d) <= max_distance {
+                    stack.push(*child);
+                }
+            }
+        }
+        found
+    }
+}
+
+/// Removed posts still in the tree before it is rebuilt without them
+pub const REBUILD_THRESHOLD: usize = 1024;
+/// How often the index is reloaded from `post_phash`, which also drops posts
+/// deleted by other processes such as `mediapub-admin post delete`
+pub const RELOAD_INTERVAL_SECS: u64 = 3600;
+
+#[derive(Default)]
+struct IndexState {
+    tree: BkTree,
+    hashes: HashMap<Uuid, u64>,
+    //BK-trees cannot delete, removed posts are filtered out on lookup until a rebuild
+    removed: HashSet<Uuid>,
+    //changes made while a reload is reading `post_phash`, replayed on top of it
+    reloading: bool,
+    inserted_during_reload: HashMap<Uuid, u64>,
+    removed_during_reload: HashSet<Uuid>,
+}
+
+impl IndexState {
+    fn from_hashes(hashes: HashMap<Uuid, u64>) -> IndexState {
+        let mut state = IndexState {
+            hashes,
+            ..Default::default()
+        };
+        state.rebuild();
+        state
+    }
+
+    /// Build a fresh tree from `hashes`, dropping the removed posts
+    fn rebuild(&mut self) {
+        let mut tree = BkTree::default();
+        for (post_id, hash) in &self.hashes {
+            tree.insert(*hash, *post_id);
+        }
+        self.tree = tree;
+        self.removed.clear();
+    }
+}
+
+/// Shared in-memory index of perceptual hashes for near-duplicate lookup
+#[derive(Default)]
+pub struct SimilarityIndex {
+    state: RwLock<IndexState>,
+}
+
+impl SimilarityIndex {
+    /// Build the index from `post_phash`
+    pub async fn load(psql_pool: &Pool) -> Result<SimilarityIndex, String> {
+        let index = SimilarityIndex::default();
+        index.reload(psql_pool).await?;
+        Ok(index)
+    }
+
+    /// Rebuild the index from `post_phash`, keeping changes made while it was read
+    pub async fn reload(&self, psql_pool: &Pool) -> Result<usize, String> {
+        if let Ok(mut state) = self.state.write() {
+            state.reloading = true;
+            state.inserted_during_reload.clear();
+            state.removed_during_reload.clear();
+        }
+        let rows = match psql_pool.get().await {
+            Ok(client) => client
+                .query("SELECT post_id, hash FROM post_phash", &[])
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        let mut state = self.state.write().map_err(|e| e.to_string())?;
+        let rows = match rows {
+            Ok(rows) => rows,
+            Err(e) => {
+                state.reloading = false;
+                return Err(e);
+            }
+        };
+        let mut hashes: HashMap<Uuid, u64> = rows
+            .iter()
+            .map(|row| (row.get(0), row.get::<_, i64>(1) as u64))
+            .collect();
+        hashes.extend(state.inserted_during_reload.drain());
+        for post_id in state.removed_during_reload.drain() {
+            hashes.remove(&post_id);
+        }
+        *state = IndexState::from_hashes(hashes);
+        Ok(state.hashes.len())
+    }
+
+    pub fn insert(&self, post_id: Uuid, hash: u64) {
+        if let Ok(mut state) = self.state.write() {
+            state.tree.insert(hash, post_id);
+            state.hashes.insert(post_id, hash);
+            state.removed.remove(&post_id);
+            if state.reloading {
+                state.removed_during_reload.remove(&post_id);
+                state.inserted_during_reload.insert(post_id, hash);
+            }
+        }
+    }
+
+    pub fn remove(&self, post_id: &Uuid) {
+        let mut state = match self.state.write() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        if state.reloading {
+            state.inserted_during_reload.remove(post_id);
+            state.removed_during_reload.insert(*post_id);
+        }
+        if state.hashes.remove(post_id).is_none() {
+            return;
+        }
+        state.removed.insert(*post_id);
+        if state.removed.len() >= REBUILD_THRESHOLD {
+            state.rebuild();
+        }
+    }
+
+    pub fn hash_of(&self, post_id: &Uuid) -> Option<u64> {
+        self.state.read().ok()?.hashes.get(post_id).copied()
+    }
+
+    /// Posts within `max_distance` of `hash`, closest first
+    pub fn find(&self, hash: u64, max_distance: u32) -> Vec<(Uuid, u32)> {
+        let state = match self.state.read() {
+            Ok(state) => state,
+            Err(_) => return Vec::new(),
+        };
+        let mut found: Vec<(Uuid, u32)> = state
+            .tree
+            .find(hash, max_distance)
+            .into_iter()
+            .filter(|(id, _)| !state.removed.contains(id))
+            .collect();
+        found.sort_by_key(|(id, d)| (*d, *id));
+        found
+    }
+}
+
+pub async fn store(
+    psql_client: &deadpool_postgres::Client,
+    post_id: &Uuid,
+    hash: u64,
+) -> Result<(), tokio_postgres::Error> {
+    psql_client
+        .execute(
+            "INSERT INTO post_phash (post_id, hash) VALUES ($1, $2) ON CONFLICT (post_id) DO UPDATE SET hash = EXCLUDED.hash",
+            &[post_id, &(hash as i64)],
+        )
+        .await?;
+    Ok(())
+}
+
+/// Reload the index every `RELOAD_INTERVAL_SECS`
+pub fn spawn_reloader(psql_pool: Pool, index: Arc<SimilarityIndex>) {
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(std::time::Duration::from_secs(RELOAD_INTERVAL_SECS));
+        //the first tick completes at once, the index was just loaded
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = index.reload(&psql_pool).await {
+                eprintln!("Failed to reload perceptual hash index: {}", e);
+            }
+        }
+    });
+}
@@ -0,0 +1,593 @@
+//! Removal of location and device-identifying metadata from JPEG, PNG and WebP files.
+//! Images are rewritten at the container level, pixel data is never re-encoded.
+//! Remaining EXIF fields (orientation included) are written back unchanged.
+use exif::{Context, Field, In, Tag, Value, experimental::Writer};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// EXIF tags that identify a specific device or person
+const IDENTIFYING_TAGS: [Tag; 6] = [
+    Tag::BodySerialNumber,
+    Tag::LensSerialNumber,
+    Tag::CameraOwnerName,
+    Tag::ImageUniqueID,
+    Tag::MakerNote,
+    //HostComputer, not predefined by the exif crate
+    Tag(Context::Tiff, 0x013c),
+];
+
+const EXIF_PREFIX: &[u8] = b"Exif\0\0";
+const XMP_PREFIX: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const XMP_EXTENSION_PREFIX: &[u8] = b"http://ns.adobe.com/xmp/extension/\0";
+
+#[derive(Debug)]
+pub enum StripError {
+    Malformed(&'static str),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for StripError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StripError::Malformed(what) => write!(f, "malformed file: {}", what),
+            StripError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+impl std::error::Error for StripError {}
+
+fn record(removed: &mut Vec<String>, name: String) {
+    if !removed.contains(&name) {
+        removed.push(name);
+    }
+}
+
+/// Filter a raw TIFF/EXIF block. Returns the rewritten block, or `None`
+/// when nothing worth keeping is left.
+fn filter_exif(tiff: &[u8], removed: &mut Vec<String>) -> Option<Vec<u8>> {
+    let mut dropped = Vec::new();
+    let kept = filter_exif_fields(tiff, &mut dropped);
+    for name in dropped {
+        record(removed, name);
+    }
+    kept
+}
+
+fn filter_exif_fields(tiff: &[u8], removed: &mut Vec<String>) -> Option<Vec<u8>> {
+    let exif = match exif::Reader::new().read_raw(tiff.to_vec()) {
+        Ok(exif) => exif,
+        Err(_) => {
+            record(removed, "EXIF (unreadable)".to_string());
+            return None;
+        }
+    };
+    let mut kept: Vec<&Field> = Vec::new();
+    for field in exif.fields() {
+        let Tag(context, _) = field.tag;
+        let drop = field.ifd_num != In::PRIMARY
+            || context == Context::Gps
+            || IDENTIFYING_TAGS.contains(&field.tag)
+            || matches!(field.value, Value::Unknown(..));
+        match drop {
+            true => match field.ifd_num == In::PRIMARY {
+                true => record(removed, field.tag.to_string()),
+                false => record(removed, "EXIF thumbnail".to_string()),
+            },
+            false => kept.push(field),
+        }
+    }
+    if removed.is_empty() {
+        return Some(tiff.to_vec());
+    }
+    if kept.is_empty() {
+        return None;
+    }
+    let mut writer = Writer::new();
+    for field in kept {
+        writer.push_field(field);
+    }
+    let mut out = Cursor::new(Vec::new());
+    match writer.write(&mut out, exif.little_endian()) {
+        Ok(_) => Some(out.into_inner()),
+        Err(e) => {
+            eprintln!("Failed to rewrite EXIF: {}", e);
+            record(removed, "EXIF".to_string());
+            None
+        }
+    }
+}
+
+/// Fill `buf` as far as the input allows, returning how many bytes were read
+fn read_up_to<R: Read>(input: &mut R, buf: &mut [u8]) -> Result<usize, StripError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(StripError::Io(e)),
+        }
+    }
+    Ok(filled)
+}
+
+/// Read exactly `length` bytes, `truncated` when the input ends first
+fn read_payload<R: Read>(input: &mut R, length: usize, truncated: &'static str) -> Result<Vec<u8>, StripError> {
+    let mut payload = vec![0u8; length];
+    match read_up_to(input, &mut payload)? == length {
+        true => Ok(payload),
+        false => Err(StripError::Malformed(truncated)),
+    }
+}
+
+/// Copy exactly `length` bytes to `out`, or discard them without one
+fn pass_through<R: Read, W: Write>(
+    input: &mut R,
+    out: Option<&mut W>,
+    length: u64,
+    truncated: &'static str,
+) -> Result<(), StripError> {
+    let mut limited = input.take(length);
+    let copied = match out {
+        Some(out) => io::copy(&mut limited, out),
+        None => io::copy(&mut limited, &mut io::sink()),
+    }
+    .map_err(StripError::Io)?;
+    match copied == length {
+        true => Ok(()),
+        false => Err(StripError::Malformed(truncated)),
+    }
+}
+
+fn write<W: Write>(out: &mut W, bytes: &[u8]) -> Result<(), StripError> {
+    out.write_all(bytes).map_err(StripError::Io)
+}
+
+/// Write a rewritten EXIF block as an APP1 segment. A block that grew past what a
+/// segment can hold is dropped whole and reported as removed EXIF.
+fn write_jpeg_exif<W: Write>(out: &mut W, tiff: &[u8], removed: &mut Vec<String>) -> Result<(), StripError> {
+    let new_length = 2 + EXIF_PREFIX.len() + tiff.len();
+    if new_length > u16::MAX as usize {
+        eprintln!("Rewritten EXIF is {} bytes, too large for a JPEG segment, dropped", tiff.len());
+        record(removed, "EXIF".to_string());
+        return Ok(());
+    }
+    write(out, &[0xff, 0xe1])?;
+    write(out, &(new_length as u16).to_be_bytes())?;
+    write(out, EXIF_PREFIX)?;
+    write(out, tiff)
+}
+
+fn strip_jpeg<R: Read, W: Write>(input: &mut R, out: &mut W, removed: &mut Vec<String>) -> Result<(), StripError> {
+    let mut soi = [0u8; 2];
+    if read_up_to(input, &mut soi)? < 2 || soi != [0xff, 0xd8] {
+        return Err(StripError::Malformed("missing JPEG SOI"));
+    }
+    write(out, &soi)?;
+    let mut byte = [0u8; 1];
+    loop {
+        if read_up_to(input, &mut byte)? == 0 {
+            return Ok(());
+        }
+        if byte[0] != 0xff {
+            return Err(StripError::Malformed("expected JPEG marker"));
+        }
+        //any number of 0xFF fill bytes may come before the marker code
+        let marker = loop {
+            if read_up_to(input, &mut byte)? == 0 {
+                return Err(StripError::Malformed("truncated JPEG segment"));
+            }
+            if byte[0] != 0xff {
+                break byte[0];
+            }
+        };
+        match marker {
+            //start of scan, the rest is entropy-coded data
+            0xda => {
+                write(out, &[0xff, marker])?;
+                io::copy(input, out).map_err(StripError::Io)?;
+                return Ok(());
+            }
+            //markers without a length: TEM, RSTn and EOI
+            0x01 | 0xd0..=0xd7 => {
+                write(out, &[0xff, marker])?;
+                continue;
+            }
+            0xd9 => return write(out, &[0xff, marker]),
+            _ => {}
+        }
+        let mut length = [0u8; 2];
+        if read_up_to(input, &mut length)? < 2 {
+            return Err(StripError::Malformed("truncated JPEG segment"));
+        }
+        let header = [0xff, marker, length[0], length[1]];
+        let length = u16::from_be_bytes(length) as usize;
+        if length < 2 {
+            return Err(StripError::Malformed("truncated JPEG segment"));
+        }
+        //segments are at most 64 KiB, the only part of a JPEG held in memory
+        let payload = read_payload(input, length - 2, "truncated JPEG segment")?;
+        match marker {
+            0xe1 if payload.starts_with(EXIF_PREFIX) => {
+                if let Some(tiff) = filter_exif(&payload[EXIF_PREFIX.len()..], removed) {
+                    write_jpeg_exif(out, &tiff, removed)?;
+                }
+            }
+            0xe1 if payload.starts_with(XMP_PREFIX) || payload.starts_with(XMP_EXTENSION_PREFIX) => {
+                record(removed, "XMP".to_string());
+            }
+            //Photoshop IRB, carries IPTC
+            0xed => record(removed, "IPTC".to_string()),
+            _ => {
+                write(out, &header)?;
+                write(out, &payload)?;
+            }
+        }
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xedb8_8320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+fn write_png_chunk<W: Write>(out: &mut W, kind: &[u8], data: &[u8]) -> Result<(), StripError> {
+    let mut crc_input = kind.to_vec();
+    crc_input.extend_from_slice(data);
+    write(out, &(data.len() as u32).to_be_bytes())?;
+    write(out, &crc_input)?;
+    write(out, &crc32(&crc_input).to_be_bytes())
+}
+
+/// Largest metadata chunk read into memory, larger ones are rejected as malformed
+const MAX_METADATA_CHUNK: usize = 16 * 1024 * 1024;
+/// PNG text keywords are 1 to 79 bytes followed by a null separator
+const PNG_KEYWORD_LENGTH: usize = 80;
+
+fn strip_png<R: Read, W: Write>(input: &mut R, out: &mut W, removed: &mut Vec<String>) -> Result<(), StripError> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    let mut signature = [0u8; 8];
+    if read_up_to(input, &mut signature)? < signature.len() || signature != SIGNATURE {
+        return Err(StripError::Malformed("missing PNG signature"));
+    }
+    write(out, SIGNATURE)?;
+    loop {
+        let mut header = [0u8; 8];
+        if read_up_to(input, &mut header)? < header.len() {
+            return Ok(());
+        }
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let kind = [header[4], header[5], header[6], header[7]];
+        //the payload followed by its CRC
+        let rest = length as u64 + 4;
+        match &kind {
+            b"eXIf" => {
+                if length > MAX_METADATA_CHUNK {
+                    return Err(StripError::Malformed("oversized PNG eXIf chunk"));
+                }
+                let payload = read_payload(input, length, "truncated PNG chunk")?;
+                pass_through::<R, W>(input, None, 4, "truncated PNG chunk")?;
+                if let Some(tiff) = filter_exif(&payload, removed) {
+                    write_png_chunk(out, &kind, &tiff)?;
+                }
+            }
+            b"tEXt" | b"zTXt" | b"iTXt" => {
+                let prefix = read_payload(input, length.min(PNG_KEYWORD_LENGTH), "truncated PNG chunk")?;
+                let remaining = rest - prefix.len() as u64;
+                let keyword = prefix.split(|b| *b == 0).next().unwrap_or(&[]);
+                let dropped = match keyword {
+                    b"XML:com.adobe.xmp" => Some("XMP".to_string()),
+                    //ImageMagick keeps whole EXIF/IPTC blocks as hex text
+                    k if k.starts_with(b"Raw profile type") => Some(String::from_utf8_lossy(k).to_string()),
+                    _ => None,
+                };
+                match dropped {
+                    Some(name) => {
+                        record(removed, name);
+                        pass_through::<R, W>(input, None, remaining, "truncated PNG chunk")?;
+                    }
+                    None => {
+                        write(out, &header)?;
+                        write(out, &prefix)?;
+                        pass_through(input, Some(&mut *out), remaining, "truncated PNG chunk")?;
+                    }
+                }
+            }
+            _ => {
+                write(out, &header)?;
+                pass_through(input, Some(&mut *out), rest, "truncated PNG chunk")?;
+            }
+        }
+        if &kind == b"IEND" {
+            return Ok(());
+        }
+    }
+}
+
+/// Rewritten in one pass, the RIFF size and VP8X flags are patched at the end
+fn strip_webp<R: Read, W: Write + Seek>(input: &mut R, out: &mut W, removed: &mut Vec<String>) -> Result<(), StripError> {
+    let mut header = [0u8; 12];
+    if read_up_to(input, &mut header)? < header.len() || !header.starts_with(b"RIFF") || &header[8..12] != b"WEBP" {
+        return Err(StripError::Malformed("missing WebP header"));
+    }
+    let start = out.stream_position().map_err(StripError::Io)?;
+    write(out, b"RIFF\0\0\0\0WEBP")?;
+    let mut vp8x_flags: Option<(u64, u8)> = None;
+    let mut has_exif = false;
+    loop {
+        let mut chunk = [0u8; 8];
+        if read_up_to(input, &mut chunk)? < chunk.len() {
+            break;
+        }
+        let kind = [chunk[0], chunk[1], chunk[2], chunk[3]];
+        let length = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as usize;
+        match &kind {
+            b"EXIF" => {
+                if length > MAX_METADATA_CHUNK {
+                    return Err(StripError::Malformed("oversized WebP EXIF chunk"));
+                }
+                let payload = read_payload(input, length, "truncated WebP chunk")?;
+                let tiff = payload.strip_prefix(EXIF_PREFIX).unwrap_or(&payload);
+                if let Some(tiff) = filter_exif(tiff, removed) {
+                    write(out, &kind)?;
+                    write(out, &(tiff.len() as u32).to_le_bytes())?;
+                    write(out, &tiff)?;
+                    if tiff.len() & 1 == 1 {
+                        write(out, &[0])?;
+                    }
+                    has_exif = true;
+                }
+            }
+            b"XMP " => {
+                record(removed, "XMP".to_string());
+                pass_through::<R, W>(input, None, length as u64, "truncated WebP chunk")?;
+            }
+            _ => {
+                if &kind == b"VP8X" && length > 0 {
+                    let position = out.stream_position().map_err(StripError::Io)? + 8;
+                    let mut flags = [0u8; 1];
+                    if read_up_to(input, &mut flags)? < 1 {
+                        return Err(StripError::Malformed("truncated WebP chunk"));
+                    }
+                    write(out, &chunk)?;
+                    write(out, &flags)?;
+                    pass_through(input, Some(&mut *out), length as u64 - 1, "truncated WebP chunk")?;
+                    vp8x_flags = Some((position, flags[0]));
+                } else {
+                    write(out, &chunk)?;
+                    pass_through(input, Some(&mut *out), length as u64, "truncated WebP chunk")?;
+                }
+                if length & 1 == 1 {
+                    write(out, &[0])?;
+                }
+            }
+        }
+        //a missing pad byte at the very end is tolerated
+        if length & 1 == 1 {
+            read_up_to(input, &mut [0u8; 1])?;
+        }
+    }
+    let end = out.stream_position().map_err(StripError::Io)?;
+    let patch = |out: &mut W, position: u64, bytes: &[u8]| -> Result<(), StripError> {
+        out.seek(SeekFrom::Start(position)).map_err(StripError::Io)?;
+        write(out, bytes)
+    };
+    patch(out, start + 4, &((end - start - 8) as u32).to_le_bytes())?;
+    //VP8X flags: 0x08 EXIF present, 0x04 XMP present
+    if let Some((position, mut flags)) = vp8x_flags {
+        flags &= !0x04;
+        if !has_exif {
+            flags &= !0x08;
+        }
+        patch(out, position, &[flags])?;
+    }
+    out.seek(SeekFrom::Start(end)).map_err(StripError::Io)?;
+    Ok(())
+}
+
+fn is_supported(content_type: &str) -> bool {
+    matches!(content_type, "image/jpeg" | "image/png" | "image/webp")
+}
+
+/// Copy `input` to `out` without its metadata. Returns the removed fields.
+fn strip_stream<R: Read, W: Write + Seek>(
+    input: &mut R,
+    out: &mut W,
+    content_type: &str,
+) -> Result<Vec<String>, StripError> {
+    let mut removed = Vec::new();
+    match content_type {
+        "image/jpeg" => strip_jpeg(input, out, &mut removed)?,
+        "image/png" => strip_png(input, out, &mut removed)?,
+        "image/webp" => strip_webp(input, out, &mut removed)?,
+        _ => {
+            io::copy(input, out).map_err(StripError::Io)?;
+        }
+    }
+    Ok(removed)
+}
+
+/// Strip metadata from an in-memory image. Returns the new bytes and the removed fields.
+/// Types other than JPEG, PNG and WebP are returned untouched.
+pub fn strip(data: &[u8], content_type: &str) -> Result<(Vec<u8>, Vec<String>), StripError> {
+    let mut out = Cursor::new(Vec::with_capacity(data.len()));
+    let removed = strip_stream(&mut &data[..], &mut out, content_type)?;
+    Ok((out.into_inner(), removed))
+}
+
+/// Strip metadata from a file in place, blocking. Returns the removed fields.
+/// The file is streamed into a sibling `.strip` file that replaces it when
+/// anything was removed, only metadata segments are held in memory.
+pub fn strip_file(path: &Path, content_type: &str) -> Result<Vec<String>, StripError> {
+    if !is_supported(content_type) {
+        return Ok(Vec::new());
+    }
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".strip");
+    let temporary = PathBuf::from(temporary);
+    let result = File::open(path).and_then(|source| Ok((source, File::create(&temporary)?)));
+    let (source, target) = result.map_err(StripError::Io)?;
+    let mut output = BufWriter::new(target);
+    let stripped = strip_stream(&mut BufReader::new(source), &mut output, content_type)
+        .and_then(|removed| output.flush().map(|_| removed).map_err(StripError::Io));
+    let replaced = match stripped {
+        Ok(removed) if !removed.is_empty() => std::fs::rename(&temporary, path)
+            .map(|_| removed)
+            .map_err(StripError::Io),
+        other => other,
+    };
+    match std::fs::remove_file(&temporary) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            eprintln!("Failed to remove {}: {}", temporary.display(), e);
+        }
+        _ => {}
+    }
+    replaced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xff, marker];
+        segment.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    fn png_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = Vec::new();
+        write_png_chunk(&mut chunk, kind, data).unwrap();
+        chunk
+    }
+
+    fn webp_chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = kind.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        if data.len() & 1 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn xmp_payload() -> Vec<u8> {
+        let mut payload = XMP_PREFIX.to_vec();
+        payload.extend_from_slice(b"<x:xmpmeta/>");
+        payload
+    }
+
+    #[test]
+    fn removes_xmp_from_jpeg_and_keeps_the_scan() {
+        let app0 = jpeg_segment(0xe0, b"JFIF\0\x01\x01");
+        let scan = [0xff, 0xda, 0x00, 0x02, 0x12, 0x34, 0xff, 0xd9];
+        let mut jpeg = vec![0xff, 0xd8];
+        jpeg.extend_from_slice(&app0);
+        jpeg.extend_from_slice(&jpeg_segment(0xe1, &xmp_payload()));
+        jpeg.extend_from_slice(&scan);
+        let (out, removed) = strip(&jpeg, "image/jpeg").unwrap();
+        let mut expected = vec![0xff, 0xd8];
+        expected.extend_from_slice(&app0);
+        expected.extend_from_slice(&scan);
+        assert_eq!(out, expected);
+        assert_eq!(removed, ["XMP"]);
+    }
+
+    #[test]
+    fn removes_xmp_text_from_png_and_keeps_other_chunks() {
+        let ihdr = png_chunk(b"IHDR", &[0; 13]);
+        let comment = png_chunk(b"tEXt", b"Comment\0hello");
+        let idat = png_chunk(b"IDAT", &[1, 2, 3]);
+        let iend = png_chunk(b"IEND", &[]);
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        for chunk in [&ihdr, &png_chunk(b"iTXt", b"XML:com.adobe.xmp\0\0\0\0\0<x/>"), &comment, &idat, &iend] {
+            png.extend_from_slice(chunk);
+        }
+        let (out, removed) = strip(&png, "image/png").unwrap();
+        let mut expected = b"\x89PNG\r\n\x1a\n".to_vec();
+        for chunk in [&ihdr, &comment, &idat, &iend] {
+            expected.extend_from_slice(chunk);
+        }
+        assert_eq!(out, expected);
+        assert_eq!(removed, ["XMP"]);
+    }
+
+    #[test]
+    fn patches_riff_size_and_vp8x_flags_of_webp() {
+        let mut body = b"WEBP".to_vec();
+        body.extend_from_slice(&webp_chunk(b"VP8X", &[0x04, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+        body.extend_from_slice(&webp_chunk(b"VP8L", &[1, 2, 3]));
+        body.extend_from_slice(&webp_chunk(b"XMP ", b"<x/>"));
+        let mut webp = b"RIFF".to_vec();
+        webp.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        webp.extend_from_slice(&body);
+        let (out, removed) = strip(&webp, "image/webp").unwrap();
+        assert_eq!(removed, ["XMP"]);
+        assert_eq!(u32::from_le_bytes(out[4..8].try_into().unwrap()) as usize, out.len() - 8);
+        assert_eq!(out[20], 0);
+        assert!(!out.windows(4).any(|w| w == b"XMP "));
+    }
+
+    #[test]
+    fn rejects_truncated_segments() {
+        let mut jpeg = vec![0xff, 0xd8];
+        jpeg.extend_from_slice(&jpeg_segment(0xe0, b"JFIF\0\x01\x01")[..6]);
+        assert!(matches!(strip(&jpeg, "image/jpeg"), Err(StripError::Malformed(_))));
+    }
+
+    #[test]
+    fn strips_files_in_place() {
+        let dir = std::env::temp_dir().join(format!("mediapub-strip-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("upload");
+        let mut jpeg = vec![0xff, 0xd8];
+        jpeg.extend_from_slice(&jpeg_segment(0xe1, &xmp_payload()));
+        jpeg.extend_from_slice(&[0xff, 0xda, 0x00, 0x02, 0xff, 0xd9]);
+        std::fs::write(&path, &jpeg).unwrap();
+        assert_eq!(strip_file(&path, "image/jpeg").unwrap(), ["XMP"]);
+        assert_eq!(std::fs::read(&path).unwrap(), [0xff, 0xd8, 0xff, 0xda, 0x00, 0x02, 0xff, 0xd9]);
+        //nothing left to remove, the file is kept as it is
+        assert!(strip_file(&path, "image/jpeg").unwrap().is_empty());
+        let entries = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(entries, 1);
+    }
+
+    #[test]
+    fn skips_fill_bytes_before_markers() {
+        let app0 = jpeg_segment(0xe0, b"JFIF\0\x01\x01");
+        let mut jpeg = vec![0xff, 0xd8, 0xff, 0xff];
+        jpeg.extend_from_slice(&app0);
+        jpeg.extend_from_slice(&[0xff, 0xff, 0xff]);
+        jpeg.extend_from_slice(&jpeg_segment(0xe1, &xmp_payload()));
+        jpeg.extend_from_slice(&[0xff, 0xda, 0x00, 0x02, 0xff, 0xd9]);
+        let (out, removed) = strip(&jpeg, "image/jpeg").unwrap();
+        let mut expected = vec![0xff, 0xd8];
+        expected.extend_from_slice(&app0);
+        expected.extend_from_slice(&[0xff, 0xda, 0x00, 0x02, 0xff, 0xd9]);
+        assert_eq!(out, expected);
+        assert_eq!(removed, ["XMP"]);
+    }
+
+    #[test]
+    fn reports_exif_too_large_for_a_segment() {
+        let mut out = Vec::new();
+        let mut removed = Vec::new();
+        write_jpeg_exif(&mut out, &vec![0; u16::MAX as usize], &mut removed).unwrap();
+        assert!(out.is_empty());
+        assert_eq!(removed, ["EXIF"]);
+        write_jpeg_exif(&mut out, &[0; 8], &mut removed).unwrap();
+        assert_eq!(out.len(), 4 + EXIF_PREFIX.len() + 8);
+    }
+}
@@ -0,0 +1,107 @@
+use crate::imaging::decode_oriented;
+use image::{DynamicImage, ImageFormat, ImageResult};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbSize {
+    Small,
+    Medium,
+    Large,
+}
+
+impl ThumbSize {
+    pub const ALL: [ThumbSize; 3] = [ThumbSize::Small, ThumbSize::Medium, ThumbSize::Large];
+
+    pub fn parse(raw: &str) -> Option<ThumbSize> {
+        match raw {
+            "small" => Some(ThumbSize::Small),
+            "medium" => Some(ThumbSize::Medium),
+            "large" => Some(ThumbSize::Large),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ThumbSize::Small => "small",
+            ThumbSize::Medium => "medium",
+            ThumbSize::Large => "large",
+        }
+    }
+
+    /// Longest edge in pixels
+    pub fn max_edge(&self) -> u32 {
+        match self {
+            ThumbSize::Small => 160,
+            ThumbSize::Medium => 480,
+            ThumbSize::Large => 1024,
+        }
+    }
+}
+
+/// Thumbnails live in `files.destination` as `{post_id}.thumb-{size}.webp`
+pub fn thumbnail_path(destination: &Path, post_id: &Uuid, size: ThumbSize) -> PathBuf {
+    destination.join(format!("{}.thumb-{}.webp", post_id, size.name()))
+}
+
+fn write_thumbnail(
+    image: &DynamicImage,
+    destination: &Path,
+    post_id: &Uuid,
+    size: ThumbSize,
+) -> ImageResult<PathBuf> {
+    let edge = size.max_edge();
+    let resized = match image.width() > edge || image.height() > edge {
+        true => image.thumbnail(edge, edge),
+        false => image.clone(),
+    };
+    let path = thumbnail_path(destination, post_id, size);
+    //write under a unique temporary name so a half-written thumbnail is never served
+    //and concurrent regenerations of the same thumbnail do not share a file
+    let partial = destination.join(format!(
+        "{}.thumb-{}.{}.partial",
+        post_id,
+        size.name(),
+        Uuid::new_v4()
+    ));
+    let written = DynamicImage::ImageRgba8(resized.to_rgba8())
+        .save_with_format(&partial, ImageFormat::WebP)
+        .and_then(|_| std::fs::rename(&partial, &path).map_err(image::ImageError::IoError));
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&partial);
+        return Err(e);
+    }
+    Ok(path)
+}
+
+/// Generate a single thumbnail, blocking
+pub fn generate(
+    source: &Path,
+    destination: &Path,
+    post_id: &Uuid,
+    size: ThumbSize,
+) -> ImageResult<PathBuf> {
+    let image = decode_oriented(source)?;
+    write_thumbnail(&image, destination, post_id, size)
+}
+
+/// Write every thumbnail size from an already decoded image, blocking
+pub fn write_all(image: &DynamicImage, destination: &Path, post_id: &Uuid) -> ImageResult<()> {
+    for size in ThumbSize::ALL {
+        write_thumbnail(image, destination, post_id, size)?;
+    }
+    Ok(())
+}
+
+/// Remove every thumbnail of a post, missing files are ignored
+pub async fn remove_all(destination: &Path, post_id: &Uuid) -> std::io::Result<()> {
+    for size in ThumbSize::ALL {
+        match tokio::fs::remove_file(thumbnail_path(destination, post_id, size)).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,260 @@
+use crate::imaging::decode_oriented;
+use image::{
+    DynamicImage, ImageEncoder, ImageResult,
+    codecs::{avif::AvifEncoder, jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+    imageops::FilterType,
+};
+use sha2::{Digest, Sha256};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Largest width or height a caller may request
+pub const MAX_DIMENSION: u32 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fit {
+    /// Fit inside the box keeping the aspect ratio, never upscales
+    Contain,
+    /// Fill the box keeping the aspect ratio, cropping the overflow
+    Cover,
+    /// Stretch to exactly the requested box
+    Fill,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Webp,
+    Png,
+    Jpeg,
+    Avif,
+}
+
+impl OutputFormat {
+    pub fn parse(raw: &str) -> Option<OutputFormat> {
+        match raw {
+            "webp" => Some(OutputFormat::Webp),
+            "png" => Some(OutputFormat::Png),
+            "jpeg" | "jpg" => Some(OutputFormat::Jpeg),
+            "avif" => Some(OutputFormat::Avif),
+            _ => None,
+        }
+    }
+
+    /// Format to keep when the caller does not ask for one
+    pub fn from_source(ext: &str) -> OutputFormat {
+        match ext {
+            "jpg" | "jpeg" => OutputFormat::Jpeg,
+            "webp" => OutputFormat::Webp,
+            _ => OutputFormat::Png,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Webp => "webp",
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Avif => "avif",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransformParams {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+    pub format: OutputFormat,
+    pub crop: Option<Crop>,
+}
+
+impl TransformParams {
+    /// Validate raw query values. Returns `Ok(None)` when no transform was requested.
+    pub fn parse(
+        w: Option<u32>,
+        h: Option<u32>,
+        fit: Option<&str>,
+        fmt: Option<&str>,
+        crop: Option<&str>,
+        source_ext: &str,
+    ) -> Result<Option<TransformParams>, String> {
+        if w.is_none() && h.is_none() && fit.is_none() && fmt.is_none() && crop.is_none() {
+            return Ok(None);
+        }
+        for (name, value) in [("w", w), ("h", h)] {
+            if let Some(v) = value
+                && (v == 0 || v > MAX_DIMENSION)
+            {
+                return Err(format!("{} must be between 1 and {}", name, MAX_DIMENSION));
+            }
+        }
+        let fit = match fit {
+            None | Some("contain") => Fit::Contain,
+            Some("cover") => Fit::Cover,
+            Some("fill") => Fit::Fill,
+            Some(other) => return Err(format!("unknown fit '{}'", other)),
+        };
+        if fit != Fit::Contain && (w.is_none() || h.is_none()) {
+            return Err("fit=cover and fit=fill need both w and h".to_string());
+        }
+        let format = match fmt {
+            None => OutputFormat::from_source(source_ext),
+            Some(raw) => match OutputFormat::parse(raw) {
+                Some(format) => format,
+                None => return Err(format!("unsupported format '{}'", raw)),
+            },
+        };
+        let crop = match crop {
+            None => None,
+            Some(raw) => {
+                let values: Vec<u32> = raw
+                    .split(',')
+                    .map(|v| v.trim().parse::<u32>())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| "crop must be x,y,width,height".to_string())?;
+                match values[..] {
+                    [_, _, width, height] if width > MAX_DIMENSION || height > MAX_DIMENSION => {
+                        return Err(format!(
+                            "crop width and height must be at most {}",
+                            MAX_DIMENSION
+                        ));
+                    }
+                    [x, y, width, height] if width > 0 && height > 0 => Some(Crop {
+                        x,
+                        y,
+                        width,
+                        height,
+                    }),
+                    _ => return Err("crop must be x,y,width,height".to_string()),
+                }
+            }
+        };
+        Ok(Some(TransformParams {
+            width: w,
+            height: h,
+            fit,
+            format,
+            crop,
+        }))
+    }
+
+    /// Cache file name for this transform of `source`
+    pub fn cache_name(&self, source: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(format!("{}|{:?}", source, self).as_bytes());
+        format!("{}.{}", hex::encode(hasher.finalize()), self.format.extension())
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self.format {
+            OutputFormat::Webp => "image/webp",
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Avif => "image/avif",
+        }
+    }
+}
+
+/// The result is never larger than `MAX_DIMENSION` on either edge. Without `w` and
+/// `h`, e.g. a format change alone, a larger source is scaled down to fit.
+fn resize(image: DynamicImage, params: &TransformParams) -> DynamicImage {
+    let (width, height) = (image.width(), image.height());
+    match (params.fit, params.width, params.height) {
+        (Fit::Cover, Some(w), Some(h)) => image.resize_to_fill(w, h, FilterType::Lanczos3),
+        (Fit::Fill, Some(w), Some(h)) => image.resize_exact(w, h, FilterType::Lanczos3),
+        (_, w, h) => {
+            let w = w.unwrap_or(MAX_DIMENSION).min(width);
+            let h = h.unwrap_or(MAX_DIMENSION).min(height);
+            if w == width && h == height {
+                return image;
+            }
+            image.resize(w, h, FilterType::Lanczos3)
+        }
+    }
+}
+
+fn encode(image: &DynamicImage, format: OutputFormat, writer: impl Write) -> ImageResult<()> {
+    match format {
+        OutputFormat::Jpeg => {
+            let rgb = image.to_rgb8();
+            JpegEncoder::new_with_quality(writer, 85).write_image(
+                &rgb,
+                rgb.width(),
+                rgb.height(),
+                image::ExtendedColorType::Rgb8,
+            )
+        }
+        OutputFormat::Png => {
+            let rgba = image.to_rgba8();
+            PngEncoder::new(writer).write_image(
+                &rgba,
+                rgba.width(),
+                rgba.height(),
+                image::ExtendedColorType::Rgba8,
+            )
+        }
+        OutputFormat::Webp => {
+            let rgba = image.to_rgba8();
+            WebPEncoder::new_lossless(writer).write_image(
+                &rgba,
+                rgba.width(),
+                rgba.height(),
+                image::ExtendedColorType::Rgba8,
+            )
+        }
+        OutputFormat::Avif => {
+            let rgba = image.to_rgba8();
+            AvifEncoder::new_with_speed_quality(writer, 8, 70).write_image(
+                &rgba,
+                rgba.width(),
+                rgba.height(),
+                image::ExtendedColorType::Rgba8,
+            )
+        }
+    }
+}
+
+/// Error raised when a crop does not fit inside the source image
+#[derive(Debug)]
+pub enum TransformError {
+    CropOutOfBounds,
+    Image(image::ImageError),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for TransformError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransformError::CropOutOfBounds => write!(f, "crop is outside of the image"),
+            TransformError::Image(e) => write!(f, "image error: {}", e),
+            TransformError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+impl std::error::Error for TransformError {}
+
+/// Apply `params` to `source` and write the result to `dest`, blocking
+pub fn apply(source: &Path, params: &TransformParams, dest: &Path) -> Result<(), TransformError> {
+    let mut image = decode_oriented(source).map_err(TransformError::Image)?;
+    if let Some(crop) = params.crop {
+        let fits_x = crop.x.checked_add(crop.width).is_some_and(|r| r <= image.width());
+        let fits_y = crop.y.checked_add(crop.height).is_some_and(|b| b <= image.height());
+        if !fits_x || !fits_y {
+            return Err(TransformError::CropOutOfBounds);
+        }
+        image = image.crop_imm(crop.x, crop.y, crop.width, crop.height);
+    }
+    let image = resize(image, params);
+    let file = std::fs::File::create(dest).map_err(TransformError::Io)?;
+    let mut writer = BufWriter::new(file);
+    encode(&image, params.format, &mut writer).map_err(TransformError::Image)?;
+    writer.flush().map_err(TransformError::Io)
+}
@@ -0,0 +1,674 @@
+//! Turning uploaded bytes into a stored post.
+//!
+//! Incoming bytes are hashed and sniffed as they arrive, so memory use does not grow
+//! with the file size. Where they go depends on the media type found in the first bytes:
+//!
+//! - JPEG, PNG and WebP may have their metadata stripped, which rewrites the file, so
+//!   they are written to a spool file under `files.destination/spool`.
+//! - Everything else is streamed straight into the blob store under a temporary
+//!   `staged-<uuid>` key and renamed to its digest once the post is created.
+//!
+//! [`Ingest::prepare`] then checks and cleans the file and [`Ingest::create_posts`]
+//! moves the files to their blob keys and records the posts, all of them or none,
+//! see [`batch`]. `files.destination` only needs room for the concurrent uploads of
+//! strippable images and resumable uploads, which are always assembled on disk.
+//! Staged images are perceptually hashed after they are stored when the backend
+//! is not local, so their upload reports no possible duplicates.
+pub mod batch;
+
+use crate::{
+    blob,
+    imaging::{
+        is_decodable, is_strippable,
+        phash::{self, DUPLICATE_DISTANCE, SimilarityIndex},
+        StoredImage, spawn_post_processing, strip,
+    },
+    repair,
+    sniff::{MediaType, SNIFF_LENGTH, matches_claim, sniff, sniff_file},
+    storage::BlobStore,
+    types::{DuplicateMatch, UploadJson},
+};
+use batch::{MongoPosts, PgRecords};
+use bytes::Bytes;
+use deadpool_postgres::{Client, Pool};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub enum IngestError {
+    /// The file is larger than the limit in bytes
+    TooLarge(u64),
+    Unsupported(String),
+    Mismatch {
+        filename: String,
+        claimed: String,
+        detected: &'static str,
+    },
+    Unreadable {
+        filename: String,
+        mime: &'static str,
+    },
+    /// Server-side failure, already logged. Holds the message shown to the client.
+    Internal(&'static str),
+}
+
+impl std::fmt::Display for IngestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IngestError::TooLarge(limit) => {
+                write!(f, "file is larger than the limit of {} bytes.", limit)
+            }
+            IngestError::Unsupported(filename) => write!(
+                f,
+                "{} is not an allowed media type (png, jpeg, gif, webp, avif, mp4, webm).",
+                filename
+            ),
+            IngestError::Mismatch {
+                filename,
+                claimed,
+                detected,
+            } => write!(
+                f,
+                "{} is declared as {} but its content is {}.",
+                filename, claimed, detected
+            ),
+            IngestError::Unreadable { filename, mime } => {
+                write!(f, "{} could not be read as {}.", filename, mime)
+            }
+            IngestError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+impl std::error::Error for IngestError {}
+
+fn spool_dir(destination: &Path) -> PathBuf {
+    destination.join("spool")
+}
+
+/// Chunks in flight between a [`Spool`] and the blob store
+const STAGING_CHUNKS: usize = 8;
+
+/// Where a [`Spool`] writes
+enum Sink {
+    /// Too few bytes arrived to tell the media type
+    Undecided,
+    Disk(tokio::fs::File),
+    /// Chunks for a `put_stream` running in the background, `None` ends the stream
+    Store {
+        sender: mpsc::Sender<Option<Bytes>>,
+        task: JoinHandle<io::Result<()>>,
+    },
+}
+
+/// A file being received chunk by chunk, see the module docs
+pub struct Spool {
+    destination: PathBuf,
+    store: Arc<dyn BlobStore>,
+    sink: Sink,
+    spooled: Option<SpooledFile>,
+    size: u64,
+    hasher: Sha256,
+    head: Vec<u8>,
+    limit: u64,
+}
+
+impl Spool {
+    pub fn new(destination: &Path, store: Arc<dyn BlobStore>, limit: u64) -> Spool {
+        Spool {
+            destination: destination.to_path_buf(),
+            store,
+            sink: Sink::Undecided,
+            spooled: None,
+            size: 0,
+            hasher: Sha256::new(),
+            head: Vec::with_capacity(SNIFF_LENGTH),
+            limit,
+        }
+    }
+
+    pub async fn write(&mut self, chunk: Bytes) -> Result<(), IngestError> {
+        self.size += chunk.len() as u64;
+        if self.size > self.limit {
+            return Err(IngestError::TooLarge(self.limit));
+        }
+        self.hasher.update(&chunk);
+        if let Sink::Undecided = self.sink {
+            self.head.extend_from_slice(&chunk);
+            if self.head.len() < SNIFF_LENGTH {
+                return Ok(());
+            }
+            self.open_sink().await?;
+            let head = Bytes::from(std::mem::take(&mut self.head));
+            //kept for `sniffed` and `finish`
+            self.head = head[..SNIFF_LENGTH].to_vec();
+            return self.send(head).await;
+        }
+        self.send(chunk).await
+    }
+
+    /// Bytes that are rewritten by stripping go to local disk, everything else
+    /// straight to the blob store under a temporary key
+    async fn open_sink(&mut self) -> Result<(), IngestError> {
+        //unknown content is rejected before much of it is read, it never reaches the store
+        let staged = self.head.len() >= SNIFF_LENGTH
+            && matches!(sniff(&self.head), Some(media_type) if !is_strippable(media_type.mime));
+        let (sink, location) = match staged {
+            false => {
+                let path = spool_dir(&self.destination).join(Uuid::new_v4().to_string());
+                let created = match tokio::fs::create_dir_all(spool_dir(&self.destination)).await {
+                    Ok(_) => tokio::fs::File::create(&path).await,
+                    Err(e) => Err(e),
+                };
+                match created {
+                    Ok(file) => (Sink::Disk(file), Location::Disk(path)),
+                    Err(e) => {
+                        eprintln!("Failed to create spool file: {}", e);
+                        return Err(IngestError::Internal("Failed to save uploaded file."));
+                    }
+                }
+            }
+            true => {
+                let key = format!("staged-{}", Uuid::new_v4().simple());
+                let (sender, receiver) = mpsc::channel(STAGING_CHUNKS);
+                let task = tokio::spawn(stage(self.store.clone(), key.clone(), receiver));
+                (
+                    Sink::Store { sender, task },
+                    Location::Staged(StagedBlob {
+                        key,
+                        store: self.store.clone(),
+                        moved: false,
+                    }),
+                )
+            }
+        };
+        self.sink = sink;
+        self.spooled = Some(SpooledFile {
+            location,
+            size: 0,
+            digest: String::new(),
+            media_type: None,
+        });
+        Ok(())
+    }
+
+    async fn send(&mut self, chunk: Bytes) -> Result<(), IngestError> {
+        let sent = match &mut self.sink {
+            Sink::Undecided => Ok(()),
+            Sink::Disk(file) => file.write_all(&chunk).await.map_err(|e| e.to_string()),
+            //the receiver is gone once the store failed, the error is logged there
+            Sink::Store { sender, .. } => sender.send(Some(chunk)).await.map_err(|e| e.to_string()),
+        };
+        if let Err(e) = sent {
+            eprintln!("Failed to write uploaded file: {}", e);
+            return Err(IngestError::Internal("Failed to save uploaded file."));
+        }
+        Ok(())
+    }
+
+    /// Media type detected from the first bytes, once enough of them arrived
+    pub fn sniffed(&self) -> Option<Option<MediaType>> {
+        match self.head.len() >= SNIFF_LENGTH {
+            true => Some(sniff(&self.head)),
+            false => None,
+        }
+    }
+
+    pub async fn finish(mut self) -> Result<SpooledFile, IngestError> {
+        //files shorter than the sniffed prefix are spooled to disk
+        if let Sink::Undecided = self.sink {
+            self.open_sink().await?;
+            let head = Bytes::from(self.head.clone());
+            self.send(head).await?;
+        }
+        let finished = match std::mem::replace(&mut self.sink, Sink::Undecided) {
+            Sink::Undecided => Ok(()),
+            Sink::Disk(mut file) => file.flush().await.map_err(|e| e.to_string()),
+            Sink::Store { sender, task } => match sender.send(None).await {
+                Ok(_) => match task.await {
+                    Ok(Ok(_)) => Ok(()),
+                    Ok(Err(e)) => Err(e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                },
+                Err(e) => Err(e.to_string()),
+            },
+        };
+        if let Err(e) = finished {
+            eprintln!("Failed to save uploaded file: {}", e);
+            return Err(IngestError::Internal("Failed to save uploaded file."));
+        }
+        let mut spooled = match self.spooled.take() {
+            Some(spooled) => spooled,
+            None => return Err(IngestError::Internal("Failed to save uploaded file.")),
+        };
+        spooled.size = self.size;
+        spooled.digest = hex::encode(self.hasher.finalize());
+        spooled.media_type = sniff(&self.head);
+        Ok(spooled)
+    }
+}
+
+/// Feed the chunks from `receiver` into `store` under `key`. A spool dropped
+/// before [`Spool::finish`] ends the stream with an error so nothing partial is kept.
+async fn stage(
+    store: Arc<dyn BlobStore>,
+    key: String,
+    receiver: mpsc::Receiver<Option<Bytes>>,
+) -> io::Result<()> {
+    let stream = futures_util::stream::unfold(Some(receiver), |receiver| async move {
+        let mut receiver = receiver?;
+        match receiver.recv().await {
+            Some(Some(chunk)) => Some((Ok(chunk), Some(receiver))),
+            Some(None) => None,
+            None => Some((
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof, "upload was cut short")),
+                None,
+            )),
+        }
+    });
+    let result = store.put_stream(&key, Box::pin(stream)).await;
+    if let Err(e) = &result {
+        eprintln!("Failed to stream upload to {}: {}", key, e);
+        if let Err(e) = store.delete(&key).await {
+            eprintln!("Failed to remove {}: {}", key, e);
+        }
+    }
+    result
+}
+
+/// Bytes in the blob store under a temporary key, removed on drop unless they were moved
+pub struct StagedBlob {
+    pub key: String,
+    store: Arc<dyn BlobStore>,
+    moved: bool,
+}
+
+impl Drop for StagedBlob {
+    fn drop(&mut self) {
+        if self.moved {
+            return;
+        }
+        let key = std::mem::take(&mut self.key);
+        let store = self.store.clone();
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(async move {
+                    if let Err(e) = store.delete(&key).await {
+                        eprintln!("Failed to remove {}: {}", key, e);
+                    }
+                });
+            }
+            Err(_) => eprintln!("Failed to remove {}: no runtime", key),
+        }
+    }
+}
+
+pub enum Location {
+    /// A spool file, removed on drop unless it was moved into the blob store
+    Disk(PathBuf),
+    Staged(StagedBlob),
+}
+
+/// A complete file, on local disk or already in the blob store
+pub struct SpooledFile {
+    pub location: Location,
+    pub size: u64,
+    /// SHA-256 of the bytes as received
+    pub digest: String,
+    pub media_type: Option<MediaType>,
+}
+
+impl SpooledFile {
+    /// Take over a complete file written elsewhere, hashing and sniffing it
+    pub async fn adopt(path: PathBuf) -> Result<SpooledFile, IngestError> {
+        let target = path.clone();
+        let inspected = blocking(
+            move || -> io::Result<(String, u64, Option<MediaType>)> {
+                let (digest, size) = blob::hash_file(&target)?;
+                Ok((digest, size, sniff_file(&target)?))
+            },
+            "Hash",
+        )
+        .await?;
+        match inspected {
+            Ok((digest, size, media_type)) => Ok(SpooledFile {
+                location: Location::Disk(path),
+                size,
+                digest,
+                media_type,
+            }),
+            Err(e) => {
+                eprintln!("{} failed to read: {}", path.display(), e);
+                Err(IngestError::Internal("Failed to read uploaded file."))
+            }
+        }
+    }
+
+    /// Where the bytes can be read on this machine, `None` for bytes staged in a remote store
+    pub fn local_path(&self) -> Option<PathBuf> {
+        match &self.location {
+            Location::Disk(path) => Some(path.clone()),
+            Location::Staged(staged) => staged.store.local_path(&staged.key),
+        }
+    }
+
+    /// Store the bytes under their digest
+    pub async fn store_into(&mut self, store: &dyn BlobStore) -> io::Result<()> {
+        match &mut self.location {
+            Location::Disk(path) => store.put(&self.digest, path).await,
+            Location::Staged(staged) => {
+                store.rename(&staged.key, &self.digest).await?;
+                staged.moved = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// Remove bytes staged in the blob store, for files whose digest is already stored
+    pub async fn discard(&mut self) -> io::Result<()> {
+        if let Location::Staged(staged) = &mut self.location {
+            staged.store.delete(&staged.key).await?;
+            staged.moved = true;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SpooledFile {
+    fn drop(&mut self) {
+        if let Location::Disk(path) = &self.location {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    eprintln!("Failed to remove {}: {}", path.display(), e);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// A checked file ready to be stored
+pub struct Prepared {
+    pub file: SpooledFile,
+    pub media_type: MediaType,
+    /// Metadata fields removed from the file
+    pub stripped_metadata: Vec<String>,
+    pub phash: Option<u64>,
+}
+
+pub struct CreatedPost {
+    pub post_id: Uuid,
+    /// Public name, `{post_id}.{ext}`
+    pub filename: String,
+    pub possible_duplicates: Vec<DuplicateMatch>,
+}
+
+/// Everything a new post touches
+pub struct Ingest {
+    pub psql_pool: Pool,
+    pub mongo_pool: mongodb::Client,
+    pub store: Arc<dyn BlobStore>,
+    pub similarity: Arc<SimilarityIndex>,
+    /// `files.destination`, where thumbnails are written
+    pub destination: PathBuf,
+}
+
+async fn blocking<T: Send + 'static>(
+    task: impl FnOnce() -> T + Send + 'static,
+    what: &str,
+) -> Result<T, IngestError> {
+    tokio::task::spawn_blocking(task).await.map_err(|e| {
+        eprintln!("{} task failed: {}", what, e);
+        IngestError::Internal("Failed to process uploaded file.")
+    })
+}
+
+impl Ingest {
+    /// Check the detected type against `claimed`, strip metadata when asked
+    /// and compute the hashes of what will be stored
+    pub async fn prepare(
+        &self,
+        mut file: SpooledFile,
+        filename: &str,
+        claimed: Option<&str>,
+        strip_enabled: bool,
+    ) -> Result<Prepared, IngestError> {
+        let detected = match file.media_type {
+            Some(media_type) => media_type,
+            None => return Err(IngestError::Unsupported(filename.to_string())),
+        };
+        if let Some(claimed) = claimed
+            && !matches_claim(&detected, claimed)
+        {
+            return Err(IngestError::Mismatch {
+                filename: filename.to_string(),
+                claimed: claimed.to_string(),
+                detected: detected.mime,
+            });
+        }
+        let local_path = file.local_path();
+        let mut stripped_metadata = Vec::new();
+        //strippable types are always spooled to disk
+        if strip_enabled
+            && is_strippable(detected.mime)
+            && let Some(path) = local_path.clone()
+        {
+            let mime = detected.mime;
+            let target = path.clone();
+            stripped_metadata = match blocking(move || strip::strip_file(&target, mime), "Metadata strip").await? {
+                Ok(removed) => removed,
+                Err(e) => {
+                    eprintln!("{} failed to strip metadata: {}", filename, e);
+                    return Err(IngestError::Unreadable {
+                        filename: filename.to_string(),
+                        mime: detected.mime,
+                    });
+                }
+            };
+            if !stripped_metadata.is_empty() {
+                println!("{} stripped metadata: {}", filename, stripped_metadata.join(", "));
+                //the stored bytes are what gets deduplicated
+                match blocking(move || blob::hash_file(&path), "Hash").await? {
+                    Ok((digest, size)) => {
+                        file.digest = digest;
+                        file.size = size;
+                    }
+                    Err(e) => {
+                        eprintln!("{} failed to read: {}", filename, e);
+                        return Err(IngestError::Internal("Failed to read uploaded file."));
+                    }
+                }
+            }
+        }
+        //orientation is kept by stripping so the pixels hash the same.
+        //Bytes staged in a remote store are hashed by `spawn_post_processing`.
+        let phash = match (is_decodable(detected.mime), local_path) {
+            (true, Some(path)) => {
+                match blocking(move || phash::hash_file(&path), "Perceptual hash").await? {
+                    Ok(hash) => Some(hash),
+                    Err(e) => {
+                        eprintln!("{} failed to hash: {}", filename, e);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        Ok(Prepared {
+            file,
+            media_type: detected,
+            stripped_metadata,
+            phash,
+        })
+    }
+
+    /// Store the bytes as blobs and record the posts in Postgres and Mongo.
+    /// Either every post is created or none of them; anything a failed batch
+    /// could not clean up is queued in `repair_queue`.
+    pub async fn create_posts(
+        &self,
+        postgres: &mut Client,
+        user_id: &Uuid,
+        items: Vec<(Prepared, UploadJson)>,
+    ) -> Result<Vec<CreatedPost>, IngestError> {
+        let transaction = match postgres.transaction().await {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("PostgreSQL Error: {}", e);
+                return Err(IngestError::Internal(
+                    "Failed to store post metadata in database.",
+                ));
+            }
+        };
+        let documents = MongoPosts::new(&self.mongo_pool);
+        let stored = batch::store_all(
+            Box::new(PgRecords(transaction)),
+            &documents,
+            self.store.as_ref(),
+            user_id,
+            items,
+        )
+        .await;
+        let stored = match stored {
+            Ok(stored) => stored,
+            Err(failure) => {
+                for leftover in &failure.leftovers {
+                    repair::enqueue(
+                        postgres,
+                        &leftover.post_id,
+                        &leftover.filename,
+                        leftover.blob_hash.as_deref(),
+                        leftover.step,
+                        &leftover.detail,
+                    )
+                    .await;
+                }
+                return Err(failure.error);
+            }
+        };
+
+        let mut created = Vec::new();
+        for post in stored {
+            let mut possible_duplicates = Vec::new();
+            if let Some(hash) = post.phash {
+                for (existing, distance) in self.similarity.find(hash, DUPLICATE_DISTANCE) {
+                    possible_duplicates.push(DuplicateMatch {
+                        file: post.filename.clone(),
+                        post_id: existing,
+                        distance,
+                    });
+                }
+                match phash::store(postgres, &post.post_id, hash).await {
+                    Ok(_) => self.similarity.insert(post.post_id, hash),
+                    Err(e) => eprintln!("Failed to store perceptual hash of {}: {}", post.post_id, e),
+                }
+            }
+            if is_decodable(&post.content_type) {
+                spawn_post_processing(
+                    self.psql_pool.clone(),
+                    self.store.clone(),
+                    self.destination.clone(),
+                    StoredImage {
+                        post_id: post.post_id,
+                        filename: post.filename.clone(),
+                        blob_hash: post.blob_hash,
+                        content_type: post.content_type,
+                    },
+                    post.phash.is_none().then(|| self.similarity.clone()),
+                );
+            }
+            created.push(CreatedPost {
+                post_id: post.post_id,
+                filename: post.filename,
+                possible_duplicates,
+            });
+        }
+        Ok(created)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::local::LocalStore;
+
+    struct Fixture {
+        dir: PathBuf,
+        store: Arc<dyn BlobStore>,
+    }
+
+    impl Fixture {
+        fn new() -> Fixture {
+            let dir = std::env::temp_dir().join(format!("mediapub-spool-{}", Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let store: Arc<dyn BlobStore> = Arc::new(LocalStore::new(dir.join("blobs")));
+            Fixture { dir, store }
+        }
+
+        /// Write `header` padded to `size` bytes in two chunks
+        async fn spool(&self, header: &[u8], size: usize) -> Spool {
+            let mut bytes = header.to_vec();
+            bytes.resize(size, 0);
+            let mut spool = Spool::new(&self.dir, self.store.clone(), 1024);
+            let rest = bytes.split_off(size / 2);
+            spool.write(Bytes::from(bytes)).await.unwrap();
+            spool.write(Bytes::from(rest)).await.unwrap();
+            spool
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_files_that_are_not_stripped_into_the_store() {
+        let fixture = Fixture::new();
+        let spooled = fixture.spool(b"GIF89a", 200).await.finish().await.unwrap();
+        let key = match &spooled.location {
+            Location::Staged(staged) => staged.key.clone(),
+            Location::Disk(_) => panic!("gif should be staged in the store"),
+        };
+        assert_eq!(spooled.size, 200);
+        assert_eq!(spooled.media_type, Some(crate::sniff::GIF));
+        assert!(fixture.store.exists(&key).await.unwrap());
+        let (digest, size) = blob::hash_file(&spooled.local_path().unwrap()).unwrap();
+        assert_eq!((digest, size), (spooled.digest.clone(), 200));
+    }
+
+    #[tokio::test]
+    async fn spools_strippable_and_short_files_to_disk() {
+        let fixture = Fixture::new();
+        let png = fixture.spool(b"\x89PNG\r\n\x1a\n", 200).await.finish().await.unwrap();
+        let short = fixture.spool(b"GIF89a", 20).await.finish().await.unwrap();
+        for spooled in [&png, &short] {
+            match &spooled.location {
+                Location::Disk(path) => assert!(path.starts_with(spool_dir(&fixture.dir))),
+                Location::Staged(_) => panic!("file should be spooled to disk"),
+            }
+        }
+        assert!(fixture.store.list().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dropped_spool_leaves_nothing_in_the_store() {
+        let fixture = Fixture::new();
+        let spool = fixture.spool(b"GIF89a", 200).await;
+        drop(spool);
+        //the background put sees the stream end early and removes its key
+        for _ in 0..100 {
+            if fixture.store.list().await.unwrap().is_empty() {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("staged bytes were kept");
+    }
+}
@@ -0,0 +1,814 @@
+//! Storing several posts as one unit.
+//!
+//! Post rows and blob references are written in a single Postgres transaction.
+//! Blob bytes and Mongo documents cannot take part in it, so each one written is
+//! recorded and removed again when a later step fails, while the transaction still
+//! holds its row locks. Whatever cannot be removed is returned as a [`Leftover`]
+//! for `repair_queue`.
+use super::{IngestError, Prepared};
+use crate::{
+    blob,
+    db_pool::mongo_database,
+    repair::RepairStep,
+    storage::BlobStore,
+    types::{Post, UploadJson},
+    utility::uuid_to_binary,
+};
+use async_trait::async_trait;
+use deadpool_postgres::Transaction;
+use mongodb::{Collection, bson::doc};
+use std::io;
+use uuid::Uuid;
+
+/// The Postgres side of a batch, everything is kept or dropped together
+#[async_trait]
+pub trait PostRecords: Send {
+    /// Add a reference to a blob, true when its bytes have to be stored
+    async fn acquire_blob(&mut self, hash: &str, size: u64, content_type: &str) -> io::Result<bool>;
+    async fn insert_post(&mut self, record: &PostRecord) -> io::Result<()>;
+    async fn commit(self: Box<Self>) -> io::Result<()>;
+    async fn rollback(self: Box<Self>) -> io::Result<()>;
+}
+
+/// The Mongo side of a batch, one document per post
+#[async_trait]
+pub trait PostDocuments: Send + Sync {
+    async fn insert(&self, post: Post) -> io::Result<()>;
+    /// Remove a post's document, a missing one is not an error
+    async fn delete(&self, post_id: &Uuid) -> io::Result<()>;
+}
+
+/// Row of the `post` table
+pub struct PostRecord {
+    pub post_id: Uuid,
+    pub user_id: Uuid,
+    pub filename: String,
+    pub content_type: String,
+    pub blob_hash: String,
+}
+
+pub struct PgRecords<'a>(pub Transaction<'a>);
+
+fn db_error(e: tokio_postgres::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+#[async_trait]
+impl PostRecords for PgRecords<'_> {
+    async fn acquire_blob(&mut self, hash: &str, size: u64, content_type: &str) -> io::Result<bool> {
+        blob::acquire(&self.0, hash, size, content_type)
+            .await
+            .map_err(db_error)
+    }
+
+    async fn insert_post(&mut self, record: &PostRecord) -> io::Result<()> {
+        self.0
+            .execute(
+                "INSERT INTO post (post_id, user_id, filename, content_type, blob_hash) VALUES ($1, $2, $3, $4, $5)",
+                &[
+                    &record.post_id,
+                    &record.user_id,
+                    &record.filename,
+                    &record.content_type,
+                    &record.blob_hash,
+                ],
+            )
+            .await
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    async fn commit(self: Box<Self>) -> io::Result<()> {
+        self.0.commit().await.map_err(db_error)
+    }
+
+    async fn rollback(self: Box<Self>) -> io::Result<()> {
+        self.0.rollback().await.map_err(db_error)
+    }
+}
+
+pub struct MongoPosts(pub Collection<Post>);
+
+impl MongoPosts {
+    pub fn new(mongo_pool: &mongodb::Client) -> MongoPosts {
+        MongoPosts(mongo_database(mongo_pool).collection::<Post>("post"))
+    }
+}
+
+#[async_trait]
+impl PostDocuments for MongoPosts {
+    async fn insert(&self, post: Post) -> io::Result<()> {
+        self.0
+            .insert_one(post)
+            .await
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn delete(&self, post_id: &Uuid) -> io::Result<()> {
+        self.0
+            .delete_one(doc! {"post_id": uuid_to_binary(post_id)})
+            .await
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// A post whose rows, document and bytes were all written
+pub struct StoredPost {
+    pub post_id: Uuid,
+    pub filename: String,
+    pub content_type: String,
+    pub blob_hash: String,
+    pub phash: Option<u64>,
+}
+
+/// State a failed batch could not remove, to be queued for repair
+#[derive(Debug)]
+pub struct Leftover {
+    pub post_id: Uuid,
+    pub filename: String,
+    pub blob_hash: Option<String>,
+    pub step: RepairStep,
+    pub detail: String,
+}
+
+#[derive(Debug)]
+pub struct BatchError {
+    pub error: IngestError,
+    pub leftovers: Vec<Leftover>,
+}
+
+/// Something written outside the transaction
+enum Applied {
+    Blob {
+        hash: String,
+        post_id: Uuid,
+        filename: String,
+    },
+    Document {
+        post_id: Uuid,
+        filename: String,
+    },
+}
+
+/// Remove what was written, newest first
+async fn undo(
+    applied: Vec<Applied>,
+    documents: &dyn PostDocuments,
+    store: &dyn BlobStore,
+) -> Vec<Leftover> {
+    let mut leftovers = Vec::new();
+    for step in applied.into_iter().rev() {
+        match step {
+            Applied::Document { post_id, filename } => {
+                if let Err(e) = documents.delete(&post_id).await {
+                    eprintln!("Failed to remove MongoDB document of {}: {}", post_id, e);
+                    leftovers.push(Leftover {
+                        post_id,
+                        filename,
+                        blob_hash: None,
+                        step: RepairStep::Mongodb,
+                        detail: format!("document left by a failed upload: {}", e),
+                    });
+                }
+            }
+            Applied::Blob {
+                hash,
+                post_id,
+                filename,
+            } => {
+                if let Err(e) = store.delete(&hash).await {
+                    eprintln!("Failed to remove blob {}: {}", hash, e);
+                    leftovers.push(Leftover {
+                        post_id,
+                        filename,
+                        blob_hash: Some(hash.clone()),
+                        step: RepairStep::File,
+                        detail: format!("blob {} left by a failed upload: {}", hash, e),
+                    });
+                }
+            }
+        }
+    }
+    leftovers
+}
+
+/// Write one post's row, bytes and document, recording what has to be undone
+async fn store_one(
+    records: &mut dyn PostRecords,
+    documents: &dyn PostDocuments,
+    store: &dyn BlobStore,
+    user_id: &Uuid,
+    prepared: Prepared,
+    metadata: UploadJson,
+    applied: &mut Vec<Applied>,
+) -> Result<StoredPost, IngestError> {
+    let Prepared {
+        mut file,
+        media_type,
+        stripped_metadata,
+        phash,
+    } = prepared;
+    let content_type = media_type.mime.to_string();
+    let post_id = Uuid::new_v4();
+    let filename = format!("{}.{}", post_id, media_type.extension);
+    let db_failed = |e: io::Error| {
+        eprintln!("PostgreSQL Error: {}", e);
+        IngestError::Internal("Failed to store post metadata in database.")
+    };
+    let store_failed = |e: io::Error| {
+        eprintln!("{} failed to save: {}", filename, e);
+        IngestError::Internal("Failed to save uploaded file.")
+    };
+
+    let is_new = records
+        .acquire_blob(&file.digest, file.size, &content_type)
+        .await
+        .map_err(db_failed)?;
+    //a known blob is only written again when its bytes went missing
+    let needs_file = match is_new {
+        true => true,
+        false => !store.exists(&file.digest).await.map_err(store_failed)?,
+    };
+    match needs_file {
+        true => {
+            //only bytes nobody else references are removed on failure
+            if is_new {
+                applied.push(Applied::Blob {
+                    hash: file.digest.clone(),
+                    post_id,
+                    filename: filename.clone(),
+                });
+            }
+            file.store_into(store).await.map_err(store_failed)?;
+            println!("{} saved successfully", filename);
+        }
+        //identical bytes are already stored, the spooled copy is dropped
+        false => {
+            println!("{} matches stored blob {}", filename, file.digest);
+            if let Err(e) = file.discard().await {
+                eprintln!("{} failed to remove staged copy: {}", filename, e);
+            }
+        }
+    }
+    records
+        .insert_post(&PostRecord {
+            post_id,
+            user_id: *user_id,
+            filename: filename.clone(),
+            content_type: content_type.clone(),
+            blob_hash: file.digest.clone(),
+        })
+        .await
+        .map_err(db_failed)?;
+
+    let article = Post {
+        post_id,
+        title: metadata.title,
+        creator: metadata.creator,
+        source: metadata.source,
+        description: metadata.description,
+        uploader: *user_id,
+        stripped_metadata,
+    };
+    //pushed first, an insert that failed halfway may still have written
+    applied.push(Applied::Document {
+        post_id,
+        filename: filename.clone(),
+    });
+    if let Err(e) = documents.insert(article).await {
+        eprintln!("MongoDB Insert Error: {}", e);
+        return Err(IngestError::Internal("Failed to store post in MongoDB."));
+    }
+    println!("Inserted post {} to MongoDB", post_id);
+    Ok(StoredPost {
+        post_id,
+        filename,
+        content_type,
+        blob_hash: file.digest.clone(),
+        phash,
+    })
+}
+
+/// Store every item or none of them
+pub async fn store_all(
+    mut records: Box<dyn PostRecords + '_>,
+    documents: &dyn PostDocuments,
+    store: &dyn BlobStore,
+    user_id: &Uuid,
+    items: Vec<(Prepared, UploadJson)>,
+) -> Result<Vec<StoredPost>, BatchError> {
+    let mut applied = Vec::new();
+    let mut stored = Vec::new();
+    for (prepared, metadata) in items {
+        match store_one(
+            records.as_mut(),
+            documents,
+            store,
+            user_id,
+            prepared,
+            metadata,
+            &mut applied,
+        )
+        .await
+        {
+            Ok(post) => stored.push(post),
+            Err(error) => {
+                //undone before the rollback releases the blob rows, so a concurrent
+                //upload of the same bytes cannot store them in between
+                let leftovers = undo(applied, documents, store).await;
+                if let Err(e) = records.rollback().await {
+                    eprintln!("PostgreSQL Rollback Error: {}", e);
+                }
+                return Err(BatchError { error, leftovers });
+            }
+        }
+    }
+    if let Err(e) = records.commit().await {
+        eprintln!("PostgreSQL Commit Error: {}", e);
+        //the blob rows are unlocked now, removing new bytes could race with another
+        //upload of them, so they are left for repair instead
+        let (blobs, documents_written): (Vec<Applied>, Vec<Applied>) = applied
+            .into_iter()
+            .partition(|step| matches!(step, Applied::Blob { .. }));
+        let mut leftovers = undo(documents_written, documents, store).await;
+        for step in blobs {
+            if let Applied::Blob {
+                hash,
+                post_id,
+                filename,
+            } = step
+            {
+                leftovers.push(Leftover {
+                    post_id,
+                    filename,
+                    blob_hash: Some(hash.clone()),
+                    step: RepairStep::File,
+                    detail: format!("blob {} may be unreferenced after a failed commit", hash),
+                });
+            }
+        }
+        return Err(BatchError {
+            error: IngestError::Internal("Failed to store post metadata in database."),
+            leftovers,
+        });
+    }
+    Ok(stored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::{Location, SpooledFile, StagedBlob};
+    use crate::sniff::{GIF, PNG};
+    use crate::storage::{BlobReader, ByteStream};
+    use futures_util::StreamExt;
+    use std::collections::{HashMap, HashSet};
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+
+    /// Which call fails, counted from zero
+    #[derive(Clone, Copy, Default)]
+    struct Faults {
+        acquire: Option<usize>,
+        insert_post: Option<usize>,
+        commit: bool,
+        put: Option<usize>,
+        insert_document: Option<usize>,
+        delete_document: bool,
+        delete_blob: bool,
+    }
+
+    fn injected() -> io::Error {
+        io::Error::other("injected failure")
+    }
+
+    #[derive(Default)]
+    struct Database {
+        blobs: HashMap<String, i32>,
+        posts: Vec<Uuid>,
+    }
+
+    /// Committed tables and the pending copy a transaction works on
+    struct FakeRecords {
+        committed: Arc<Mutex<Database>>,
+        pending: Database,
+        faults: Faults,
+        acquired: usize,
+        inserted: usize,
+    }
+
+    impl FakeRecords {
+        fn begin(committed: &Arc<Mutex<Database>>, faults: Faults) -> Box<FakeRecords> {
+            let current = committed.lock().unwrap();
+            Box::new(FakeRecords {
+                committed: committed.clone(),
+                pending: Database {
+                    blobs: current.blobs.clone(),
+                    posts: current.posts.clone(),
+                },
+                faults,
+                acquired: 0,
+                inserted: 0,
+            })
+        }
+    }
+
+    #[async_trait]
+    impl PostRecords for FakeRecords {
+        async fn acquire_blob(&mut self, hash: &str, _size: u64, _content_type: &str) -> io::Result<bool> {
+            self.acquired += 1;
+            if self.faults.acquire == Some(self.acquired - 1) {
+                return Err(injected());
+            }
+            let count = self.pending.blobs.entry(hash.to_string()).or_insert(0);
+            *count += 1;
+            Ok(*count == 1)
+        }
+
+        async fn insert_post(&mut self, record: &PostRecord) -> io::Result<()> {
+            self.inserted += 1;
+            if self.faults.insert_post == Some(self.inserted - 1) {
+                return Err(injected());
+            }
+            self.pending.posts.push(record.post_id);
+            Ok(())
+        }
+
+        async fn commit(self: Box<Self>) -> io::Result<()> {
+            if self.faults.commit {
+                return Err(injected());
+            }
+            *self.committed.lock().unwrap() = self.pending;
+            Ok(())
+        }
+
+        async fn rollback(self: Box<Self>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeDocuments {
+        posts: Mutex<HashSet<Uuid>>,
+        inserted: Mutex<usize>,
+        faults: Mutex<Faults>,
+    }
+
+    #[async_trait]
+    impl PostDocuments for FakeDocuments {
+        async fn insert(&self, post: Post) -> io::Result<()> {
+            let mut inserted = self.inserted.lock().unwrap();
+            *inserted += 1;
+            if self.faults.lock().unwrap().insert_document == Some(*inserted - 1) {
+                return Err(injected());
+            }
+            self.posts.lock().unwrap().insert(post.post_id);
+            Ok(())
+        }
+
+        async fn delete(&self, post_id: &Uuid) -> io::Result<()> {
+            if self.faults.lock().unwrap().delete_document {
+                return Err(injected());
+            }
+            self.posts.lock().unwrap().remove(post_id);
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeStore {
+        blobs: Mutex<HashMap<String, Vec<u8>>>,
+        puts: Mutex<usize>,
+        faults: Mutex<Faults>,
+    }
+
+    #[async_trait]
+    impl BlobStore for FakeStore {
+        async fn put(&self, key: &str, source: &Path) -> io::Result<()> {
+            let mut puts = self.puts.lock().unwrap();
+            *puts += 1;
+            if self.faults.lock().unwrap().put == Some(*puts - 1) {
+                return Err(injected());
+            }
+            let bytes = std::fs::read(source)?;
+            self.blobs.lock().unwrap().insert(key.to_string(), bytes);
+            Ok(())
+        }
+
+        async fn put_stream(&self, key: &str, mut stream: ByteStream) -> io::Result<()> {
+            let mut bytes = Vec::new();
+            while let Some(chunk) = stream.next().await {
+                bytes.extend_from_slice(&chunk?);
+            }
+            self.blobs.lock().unwrap().insert(key.to_string(), bytes);
+            Ok(())
+        }
+
+        async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+            let mut puts = self.puts.lock().unwrap();
+            *puts += 1;
+            if self.faults.lock().unwrap().put == Some(*puts - 1) {
+                return Err(injected());
+            }
+            let mut blobs = self.blobs.lock().unwrap();
+            let bytes = blobs.remove(from).ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            blobs.insert(to.to_string(), bytes);
+            Ok(())
+        }
+
+        async fn get(&self, _key: &str) -> io::Result<Option<BlobReader>> {
+            Ok(None)
+        }
+
+        async fn delete(&self, key: &str) -> io::Result<()> {
+            if self.faults.lock().unwrap().delete_blob {
+                return Err(injected());
+            }
+            self.blobs.lock().unwrap().remove(key);
+            Ok(())
+        }
+
+        async fn exists(&self, key: &str) -> io::Result<bool> {
+            Ok(self.blobs.lock().unwrap().contains_key(key))
+        }
+
+        async fn list(&self) -> io::Result<Vec<String>> {
+            Ok(self.blobs.lock().unwrap().keys().cloned().collect())
+        }
+    }
+
+    struct Fixture {
+        dir: PathBuf,
+        committed: Arc<Mutex<Database>>,
+        documents: FakeDocuments,
+        store: Arc<FakeStore>,
+    }
+
+    impl Fixture {
+        fn new() -> Fixture {
+            let dir = std::env::temp_dir().join(format!("mediapub-batch-{}", Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Fixture {
+                dir,
+                committed: Arc::new(Mutex::new(Database::default())),
+                documents: FakeDocuments::default(),
+                store: Arc::new(FakeStore::default()),
+            }
+        }
+
+        /// One spooled file per entry of `contents`
+        fn items(&self, contents: &[&str]) -> Vec<(Prepared, UploadJson)> {
+            contents
+                .iter()
+                .map(|content| {
+                    let path = self.dir.join(Uuid::new_v4().to_string());
+                    std::fs::write(&path, content).unwrap();
+                    let prepared = Prepared {
+                        file: SpooledFile {
+                            location: Location::Disk(path),
+                            size: content.len() as u64,
+                            digest: format!("digest-{}", content),
+                            media_type: Some(PNG),
+                        },
+                        media_type: PNG,
+                        stripped_metadata: Vec::new(),
+                        phash: None,
+                    };
+                    let metadata = UploadJson {
+                        title: content.to_string(),
+                        creator: String::new(),
+                        source: String::new(),
+                        description: String::new(),
+                    };
+                    (prepared, metadata)
+                })
+                .collect()
+        }
+
+        /// One file per entry of `contents`, already streamed into the store
+        fn staged_items(&self, contents: &[&str]) -> Vec<(Prepared, UploadJson)> {
+            contents
+                .iter()
+                .map(|content| {
+                    let key = format!("staged-{}", Uuid::new_v4().simple());
+                    self.store
+                        .blobs
+                        .lock()
+                        .unwrap()
+                        .insert(key.clone(), content.as_bytes().to_vec());
+                    let prepared = Prepared {
+                        file: SpooledFile {
+                            location: Location::Staged(StagedBlob {
+                                key,
+                                store: self.store.clone(),
+                                moved: false,
+                            }),
+                            size: content.len() as u64,
+                            digest: format!("digest-{}", content),
+                            media_type: Some(GIF),
+                        },
+                        media_type: GIF,
+                        stripped_metadata: Vec::new(),
+                        phash: None,
+                    };
+                    (prepared, UploadJson {
+                        title: content.to_string(),
+                        creator: String::new(),
+                        source: String::new(),
+                        description: String::new(),
+                    })
+                })
+                .collect()
+        }
+
+        async fn run(&self, faults: Faults, contents: &[&str]) -> Result<Vec<StoredPost>, BatchError> {
+            self.run_items(faults, self.items(contents)).await
+        }
+
+        async fn run_items(
+            &self,
+            faults: Faults,
+            items: Vec<(Prepared, UploadJson)>,
+        ) -> Result<Vec<StoredPost>, BatchError> {
+            *self.documents.faults.lock().unwrap() = faults;
+            *self.documents.inserted.lock().unwrap() = 0;
+            *self.store.faults.lock().unwrap() = faults;
+            *self.store.puts.lock().unwrap() = 0;
+            let records = FakeRecords::begin(&self.committed, faults);
+            store_all(
+                records,
+                &self.documents,
+                self.store.as_ref(),
+                &Uuid::new_v4(),
+                items,
+            )
+            .await
+        }
+
+        fn post_count(&self) -> usize {
+            self.committed.lock().unwrap().posts.len()
+        }
+
+        fn document_count(&self) -> usize {
+            self.documents.posts.lock().unwrap().len()
+        }
+
+        fn stored_blobs(&self) -> usize {
+            self.store.blobs.lock().unwrap().len()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Run a three file batch with `faults` and check that nothing was kept
+    async fn assert_rolled_back(faults: Faults) {
+        let fixture = Fixture::new();
+        let result = fixture.run(faults, &["a", "b", "c"]).await;
+        let failure = result.err().expect("batch should fail");
+        assert!(matches!(failure.error, IngestError::Internal(_)));
+        assert!(failure.leftovers.is_empty());
+        assert_eq!(fixture.post_count(), 0);
+        assert_eq!(fixture.document_count(), 0);
+        assert_eq!(fixture.stored_blobs(), 0);
+    }
+
+    #[tokio::test]
+    async fn stores_every_file() {
+        let fixture = Fixture::new();
+        let stored = fixture.run(Faults::default(), &["a", "b", "c"]).await.unwrap();
+        assert_eq!(stored.len(), 3);
+        assert_eq!(fixture.post_count(), 3);
+        assert_eq!(fixture.document_count(), 3);
+        assert_eq!(fixture.stored_blobs(), 3);
+        assert_eq!(fixture.committed.lock().unwrap().blobs["digest-a"], 1);
+    }
+
+    #[tokio::test]
+    async fn rolls_back_when_blob_reference_fails() {
+        for index in 0..3 {
+            assert_rolled_back(Faults {
+                acquire: Some(index),
+                ..Default::default()
+            })
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn rolls_back_when_storing_bytes_fails() {
+        for index in 0..3 {
+            assert_rolled_back(Faults {
+                put: Some(index),
+                ..Default::default()
+            })
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn rolls_back_when_post_row_fails() {
+        for index in 0..3 {
+            assert_rolled_back(Faults {
+                insert_post: Some(index),
+                ..Default::default()
+            })
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn rolls_back_when_mongo_insert_fails() {
+        for index in 0..3 {
+            assert_rolled_back(Faults {
+                insert_document: Some(index),
+                ..Default::default()
+            })
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn removes_documents_but_keeps_bytes_when_commit_fails() {
+        let faults = Faults {
+            commit: true,
+            ..Default::default()
+        };
+        let fixture = Fixture::new();
+        let failure = fixture.run(faults, &["a", "b"]).await.err().unwrap();
+        assert_eq!(fixture.post_count(), 0);
+        assert_eq!(fixture.document_count(), 0);
+        assert_eq!(fixture.stored_blobs(), 2);
+        assert_eq!(failure.leftovers.len(), 2);
+        assert!(failure.leftovers.iter().all(|l| matches!(l.step, RepairStep::File)));
+    }
+
+    #[tokio::test]
+    async fn reports_what_could_not_be_undone() {
+        let faults = Faults {
+            insert_document: Some(2),
+            delete_document: true,
+            delete_blob: true,
+            ..Default::default()
+        };
+        let fixture = Fixture::new();
+        let failure = fixture.run(faults, &["a", "b", "c"]).await.err().unwrap();
+        assert_eq!(fixture.post_count(), 0);
+        let documents = failure
+            .leftovers
+            .iter()
+            .filter(|l| matches!(l.step, RepairStep::Mongodb))
+            .count();
+        let files = failure
+            .leftovers
+            .iter()
+            .filter(|l| matches!(l.step, RepairStep::File))
+            .count();
+        assert_eq!(documents, 3);
+        assert_eq!(files, 3);
+    }
+
+    #[tokio::test]
+    async fn keeps_bytes_of_existing_blobs() {
+        let fixture = Fixture::new();
+        fixture.run(Faults::default(), &["a"]).await.unwrap();
+        let faults = Faults {
+            insert_document: Some(1),
+            ..Default::default()
+        };
+        let failure = fixture.run(faults, &["a", "b"]).await.err().unwrap();
+        assert!(failure.leftovers.is_empty());
+        assert_eq!(fixture.post_count(), 1);
+        assert_eq!(fixture.document_count(), 1);
+        assert!(fixture.store.blobs.lock().unwrap().contains_key("digest-a"));
+        assert!(!fixture.store.blobs.lock().unwrap().contains_key("digest-b"));
+        assert_eq!(fixture.committed.lock().unwrap().blobs["digest-a"], 1);
+    }
+
+    #[tokio::test]
+    async fn stores_identical_files_once() {
+        let fixture = Fixture::new();
+        let stored = fixture.run(Faults::default(), &["a", "a"]).await.unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(fixture.stored_blobs(), 1);
+        assert_eq!(fixture.committed.lock().unwrap().blobs["digest-a"], 2);
+    }
+
+    #[tokio::test]
+    async fn moves_staged_bytes_to_their_digest() {
+        let fixture = Fixture::new();
+        let items = fixture.staged_items(&["a", "a", "b"]);
+        let stored = fixture.run_items(Faults::default(), items).await.unwrap();
+        assert_eq!(stored.len(), 3);
+        let blobs = fixture.store.blobs.lock().unwrap();
+        let mut keys: Vec<&String> = blobs.keys().collect();
+        keys.sort();
+        assert_eq!(keys, ["digest-a", "digest-b"]);
+        assert_eq!(blobs["digest-a"], b"a");
+    }
+}
@@ -1,6 +1,10 @@
+use crate::db_pool::mongo_database;
 use deadpool_postgres::Pool;
-use mongodb::Client;
-use std::io::{Error, ErrorKind, Result};
+use mongodb::{
+    Client, IndexModel,
+    bson::{Document, doc},
+};
+use std::io::{Error, Result};
 
 const POSTGRES_SQL: &str = "
 CREATE TABLE IF NOT EXISTS \"user\" (
@@ -30,6 +34,21 @@ CREATE TABLE IF NOT EXISTS \"session\" (
     CONSTRAINT valid_refresh_expiry CHECK (refresh_expires_at > created_at)
 );
 
+-- a refreshed session keeps the family of the login it came from
+ALTER TABLE \"session\" ADD COLUMN IF NOT EXISTS family_id UUID;
+UPDATE \"session\" SET family_id = token_id WHERE family_id IS NULL;
+ALTER TABLE \"session\" ALTER COLUMN family_id SET NOT NULL;
+ALTER TABLE \"session\" ADD COLUMN IF NOT EXISTS parent_token_id UUID;
+ALTER TABLE \"session\" ADD COLUMN IF NOT EXISTS last_seen_at TIMESTAMPTZ;
+
+CREATE TABLE IF NOT EXISTS \"audit_event\" (
+    event_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID REFERENCES \"user\"(user_id) ON DELETE CASCADE,
+    kind TEXT NOT NULL,
+    detail TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
 CREATE TABLE IF NOT EXISTS \"dev_token\" (
     token_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
     user_id UUID NOT NULL REFERENCES \"user\"(user_id) ON DELETE CASCADE,
@@ -55,27 +74,151 @@ CREATE TABLE IF NOT EXISTS \"post\" (
     updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
 );
 
+CREATE TABLE IF NOT EXISTS \"blob\" (
+    hash TEXT PRIMARY KEY NOT NULL,
+    size BIGINT NOT NULL,
+    content_type TEXT NOT NULL,
+    ref_count INTEGER NOT NULL DEFAULT 1,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CONSTRAINT blob_hash_format CHECK (hash ~ '^[0-9a-f]{64}$')
+);
+
+ALTER TABLE \"post\" ADD COLUMN IF NOT EXISTS blob_hash TEXT REFERENCES \"blob\"(hash);
+
+CREATE TABLE IF NOT EXISTS \"tag\" (
+    tag_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CONSTRAINT tag_name_length CHECK (LENGTH(name) >= 1 AND LENGTH(name) <= 64)
+);
+
+CREATE TABLE IF NOT EXISTS \"post_tag\" (
+    post_id UUID NOT NULL REFERENCES \"post\"(post_id) ON DELETE CASCADE,
+    tag_id UUID NOT NULL REFERENCES \"tag\"(tag_id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (post_id, tag_id)
+);
+
+CREATE TABLE IF NOT EXISTS \"post_analysis\" (
+    post_id UUID PRIMARY KEY NOT NULL REFERENCES \"post\"(post_id) ON DELETE CASCADE,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    orientation SMALLINT NOT NULL,
+    bit_depth SMALLINT NOT NULL,
+    frame_count INTEGER NOT NULL,
+    camera_make TEXT,
+    camera_model TEXT,
+    lens_model TEXT,
+    exposure_time TEXT,
+    f_number TEXT,
+    iso TEXT,
+    focal_length TEXT,
+    taken_at TEXT,
+    palette TEXT[] NOT NULL,
+    analyzed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS \"post_phash\" (
+    post_id UUID PRIMARY KEY NOT NULL REFERENCES \"post\"(post_id) ON DELETE CASCADE,
+    hash BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS \"tus_upload\" (
+    upload_id UUID PRIMARY KEY NOT NULL,
+    user_id UUID NOT NULL REFERENCES \"user\"(user_id) ON DELETE CASCADE,
+    upload_length BIGINT NOT NULL CHECK (upload_length >= 0),
+    upload_offset BIGINT NOT NULL DEFAULT 0 CHECK (upload_offset >= 0),
+    filename TEXT NOT NULL,
+    filetype TEXT,
+    title TEXT NOT NULL,
+    creator TEXT NOT NULL,
+    source TEXT NOT NULL,
+    description TEXT NOT NULL,
+    strip_metadata BOOLEAN NOT NULL DEFAULT true,
+    post_id UUID,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+ALTER TABLE \"tus_upload\" ADD COLUMN IF NOT EXISTS lock_id UUID;
+ALTER TABLE \"tus_upload\" ADD COLUMN IF NOT EXISTS locked_until TIMESTAMPTZ;
+ALTER TABLE \"tus_upload\" ADD COLUMN IF NOT EXISTS failed_reason TEXT;
+
+CREATE TABLE IF NOT EXISTS \"repair_queue\" (
+    repair_id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    post_id UUID NOT NULL,
+    filename TEXT NOT NULL,
+    failed_step TEXT NOT NULL,
+    detail TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    resolved_at TIMESTAMPTZ
+);
+ALTER TABLE \"repair_queue\" ADD COLUMN IF NOT EXISTS blob_hash TEXT;
+
 CREATE INDEX IF NOT EXISTS idx_user_username ON \"user\"(username);
 CREATE INDEX IF NOT EXISTS idx_user_is_active ON \"user\"(is_active) WHERE is_active = true;
 CREATE INDEX IF NOT EXISTS idx_session_user_id ON \"session\"(user_id);
 CREATE INDEX IF NOT EXISTS idx_session_is_revoked ON \"session\"(is_revoked) WHERE is_revoked = false;
+CREATE INDEX IF NOT EXISTS idx_session_refresh_expires_at ON \"session\"(refresh_expires_at);
+CREATE INDEX IF NOT EXISTS idx_session_refresh_token_hash ON \"session\"(refresh_token_hash);
+CREATE INDEX IF NOT EXISTS idx_session_family_id ON \"session\"(family_id);
+CREATE INDEX IF NOT EXISTS idx_session_parent_token_id ON \"session\"(parent_token_id);
+CREATE INDEX IF NOT EXISTS idx_audit_event_user_id ON \"audit_event\"(user_id, created_at DESC);
 CREATE INDEX IF NOT EXISTS idx_dev_token_user_id ON \"dev_token\"(user_id);
 CREATE INDEX IF NOT EXISTS idx_dev_token_is_active ON \"dev_token\"(is_revoked) WHERE is_revoked = false;
 CREATE INDEX IF NOT EXISTS idx_post_user_id ON \"post\"(user_id);
 CREATE INDEX IF NOT EXISTS idx_post_created_at ON \"post\"(created_at DESC);
+CREATE INDEX IF NOT EXISTS idx_post_blob_hash ON \"post\"(blob_hash);
+CREATE INDEX IF NOT EXISTS idx_tus_upload_expires_at ON \"tus_upload\"(expires_at);
+CREATE INDEX IF NOT EXISTS idx_post_tag_tag_id ON \"post_tag\"(tag_id);
+CREATE INDEX IF NOT EXISTS idx_repair_queue_pending ON \"repair_queue\"(created_at) WHERE resolved_at IS NULL;
+
+-- keep post.is_tagged in sync with post_tag
+CREATE OR REPLACE FUNCTION sync_post_is_tagged() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        UPDATE \"post\" SET is_tagged = true WHERE post_id = NEW.post_id AND is_tagged = false;
+        RETURN NEW;
+    END IF;
+    UPDATE \"post\" SET is_tagged = EXISTS(SELECT 1 FROM \"post_tag\" WHERE post_id = OLD.post_id)
+    WHERE post_id = OLD.post_id;
+    RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS trg_post_tag_is_tagged ON \"post_tag\";
+CREATE TRIGGER trg_post_tag_is_tagged
+    AFTER INSERT OR DELETE ON \"post_tag\"
+    FOR EACH ROW EXECUTE FUNCTION sync_post_is_tagged();
+
+UPDATE \"post\" p SET is_tagged = NOT p.is_tagged
+WHERE p.is_tagged <> EXISTS(SELECT 1 FROM \"post_tag\" pt WHERE pt.post_id = p.post_id);
 ";
 
 /// Initialize database tables and collections
 pub async fn database(psql_pool: &Pool,mongo_pool: &Client) -> Result<()> {
     //mongo initialization
     println!("===mongo initialization===");
+    let post_index = IndexModel::builder().keys(doc! {"post_id": 1}).build();
+    match mongo_database(mongo_pool)
+        .collection::<Document>("post")
+        .create_index(post_index)
+        .await
+    {
+        Ok(_) => println!("Mongodb indexes initialized successfully"),
+        Err(e) => {
+            eprintln!("Error while initializing Mongodb indexes");
+            eprintln!("{}", e);
+            return Err(Error::other(e.to_string()));
+        }
+    }
     //postgres initialization
     println!("===postgres initialization===");
     let psql_client = match psql_pool.get().await {
         Ok(conn) => conn,
         Err(e) => {
             eprintln!("Failed to get connection from pool: {}", e);
-            return Err(Error::new(ErrorKind::Other, e.to_string()));
+            return Err(Error::other(e.to_string()));
         }
     };
     //create tables
@@ -83,8 +226,8 @@ pub async fn database(psql_pool: &Pool,mongo_pool: &Client) -> Result<()> {
         Ok(_) => println!("Postgres tables initialized successfully"),
         Err(e) => {
             eprintln!("Error while initializing Postgres tables");
-            eprintln!("{}", e.to_string());
-            return Err(Error::new(ErrorKind::Other, e.to_string()));
+            eprintln!("{}", e);
+            return Err(Error::other(e.to_string()));
         }
     }
     println!("===Finish Initialization===");
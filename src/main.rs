@@ -1,56 +1,73 @@
 use actix_cors::Cors;
-use actix_multipart::form::MultipartFormConfig;
 use actix_web::{
     App, HttpResponse, HttpServer, Responder,
-    http::{StatusCode, header::ContentType},
+    http::{Method, StatusCode, header::ContentType},
     web,
 };
+use clap::Parser;
 use mediapub::{
-    ACTIX_PORT, ACTIX_SERVER, MAX_PAYLOAD_SIZE,
     db_pool::{create_mongo_pool, create_psql_pool},
+    imaging::{
+        cache::DerivedCache,
+        phash::{self, SimilarityIndex},
+    },
+    storage::{self, BlobStore, StorageConfig},
     init,
     route::{
-        items::{get_all, get_one, open_file},
+        drop::drop,
+        items::{get_all, get_one, open_file, open_thumbnail},
         ping::ping,
+        search::search,
+        similar::similar,
+        tag::{add_tags, get_tags, remove_tag},
+        tus::{self, append, create, options, status, terminate},
+        update::update,
         upload::upload,
         user::{
             login::{raw, refresh_token, session_token_login},
+            logout::{self, logout, logout_all},
+            sessions::{list_sessions, revoke_session},
             signup::signup,
+            tokens::{create_token, list_tokens, revoke_token},
         },
     },
+    settings::{self, Settings, SettingsArgs},
 };
 use std::io::{self, Error};
 
-async fn get_env() -> String {
-    match cfg!(debug_assertions) {
-        true => ".env".to_string(),
-        false => ".env_prod".to_string(),
-    }
+/// MediaPub server
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(flatten)]
+    settings: SettingsArgs,
 }
 
 #[actix_web::main]
 async fn main() -> Result<(), Error> {
-    //TODO set env value as constants
+    let cli = Cli::parse();
+    settings::load_env_file();
+    let settings = match Settings::load(&cli.settings).and_then(|s| s.validate().map(|_| s)) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Err(Error::other("Failed to load settings"));
+        }
+    };
 
     //create pool
-    let psql_pool = match create_psql_pool().await {
+    let psql_pool = match create_psql_pool(&settings.postgres).await {
         Ok(p) => p,
         Err(e) => {
             eprintln!("Failed to create database pool: {}", e);
-            return Err(Error::new(
-                io::ErrorKind::Other,
-                "Failed to create database pool",
-            ));
+            return Err(Error::other("Failed to create database pool"));
         }
     };
-    let mongo_pool = match create_mongo_pool().await {
+    let mongo_pool = match create_mongo_pool(&settings.mongodb).await {
         Ok(p) => p,
         Err(e) => {
             eprintln!("Failed to create mongodb pool: {}", e);
-            return Err(Error::new(
-                io::ErrorKind::Other,
-                "Failed to create mongodb pool",
-            ));
+            return Err(Error::other("Failed to create mongodb pool"));
         }
     };
     //initialize database
@@ -58,13 +75,46 @@ async fn main() -> Result<(), Error> {
         Ok(_) => println!("Database initialized successfully"),
         Err(e) => {
             eprintln!("Database initialization failed: {}", e);
-            return Err(Error::new(
-                io::ErrorKind::Other,
-                "Database initialization failed",
-            ));
+            return Err(Error::other("Database initialization failed"));
         }
     }
-    let launch_msg = format!("Starting Server on {}:{}...", ACTIX_SERVER, ACTIX_PORT);
+    let derived_cache = match DerivedCache::open(
+        &settings.files.derived_cache_dir,
+        settings.files.derived_cache_max_bytes,
+    ) {
+        Ok(cache) => web::Data::new(cache),
+        Err(e) => {
+            eprintln!("Failed to open derived image cache: {}", e);
+            return Err(Error::other("Failed to open derived image cache"));
+        }
+    };
+    let similarity = match SimilarityIndex::load(&psql_pool).await {
+        Ok(index) => web::Data::new(index),
+        Err(e) => {
+            eprintln!("Failed to load perceptual hash index: {}", e);
+            return Err(Error::other("Failed to load perceptual hash index"));
+        }
+    };
+    let store: web::Data<dyn BlobStore> = match storage::open(
+        &StorageConfig::from_settings(&settings),
+        &mongo_pool,
+    ) {
+        Ok(store) => web::Data::from(store),
+        Err(e) => {
+            eprintln!("Failed to open blob storage: {}", e);
+            return Err(Error::other("Failed to open blob storage"));
+        }
+    };
+    tus::spawn_expiry_sweeper(psql_pool.clone(), settings.files.destination.clone());
+    logout::spawn_session_sweeper(psql_pool.clone());
+    phash::spawn_reloader(psql_pool.clone(), similarity.clone().into_inner());
+    let launch_msg = format!(
+        "Starting Server on {}:{}...",
+        settings.server.host, settings.server.port
+    );
+    let bind = (settings.server.host.clone(), settings.server.port);
+    let workers = settings.server.workers;
+    let settings = web::Data::new(settings);
 
     println!("{}", &launch_msg);
 
@@ -72,17 +122,17 @@ async fn main() -> Result<(), Error> {
         App::new()
             .app_data(web::Data::new(psql_pool.clone()))
             .app_data(web::Data::new(mongo_pool.clone()))
-            .app_data(web::PayloadConfig::new(MAX_PAYLOAD_SIZE))
-            .app_data(
-                MultipartFormConfig::default()
-                    .total_limit(MAX_PAYLOAD_SIZE)
-                    .memory_limit(MAX_PAYLOAD_SIZE),
-            )
+            .app_data(derived_cache.clone())
+            .app_data(similarity.clone())
+            .app_data(store.clone())
+            .app_data(web::PayloadConfig::new(settings.files.max_payload_size))
+            .app_data(settings.clone())
             .wrap(
                 Cors::default()
                     .allow_any_origin()
                     .allow_any_method()
-                    .allow_any_header(),
+                    .allow_any_header()
+                    .expose_any_header(),
             )
             .service(web::resource("/ping").route(web::get().to(ping)))
             .service(
@@ -90,16 +140,62 @@ async fn main() -> Result<(), Error> {
                     .route(web::get().to(index))
                     .route(web::post().to(upload)),
             )
+            .service(
+                web::resource("/files")
+                    .route(web::method(Method::OPTIONS).to(options))
+                    .route(web::post().to(create)),
+            )
+            .service(
+                web::resource("/files/{upload_id}")
+                    .route(web::method(Method::OPTIONS).to(options))
+                    .route(web::head().to(status))
+                    .route(web::patch().to(append))
+                    .route(web::delete().to(terminate)),
+            )
             .service(web::resource("/item").route(web::get().to(get_all)))
-            .service(web::resource("/item/{item_id:[a-f0-9\\-]+}").route(web::get().to(get_one)))
+            .service(
+                web::resource("/item/{item_id:[a-f0-9\\-]+}")
+                    .route(web::get().to(get_one))
+                    .route(web::patch().to(update))
+                    .route(web::delete().to(drop)),
+            )
+            .service(
+                web::resource("/item/{item_id:[a-f0-9\\-]+}/thumb/{size}")
+                    .route(web::get().to(open_thumbnail)),
+            )
+            .service(
+                web::resource("/item/{item_id:[a-f0-9\\-]+}/similar")
+                    .route(web::get().to(similar)),
+            )
+            .service(
+                web::resource("/item/{item_id:[a-f0-9\\-]+}/tag").route(web::post().to(add_tags)),
+            )
+            .service(
+                web::resource("/item/{item_id:[a-f0-9\\-]+}/tag/{tag}")
+                    .route(web::delete().to(remove_tag)),
+            )
             .service(web::resource("/item/{file:.*\\..*}").route(web::get().to(open_file)))
+            .service(web::resource("/tag").route(web::get().to(get_tags)))
+            .service(web::resource("/search").route(web::get().to(search)))
             .service(web::resource("/signup").route(web::post().to(signup)))
             .service(web::resource("/login").route(web::post().to(raw)))
             .service(web::resource("/login/session").route(web::post().to(session_token_login)))
             .service(web::resource("/login/refresh").route(web::post().to(refresh_token)))
+            .service(web::resource("/logout").route(web::post().to(logout)))
+            .service(web::resource("/logout/all").route(web::post().to(logout_all)))
+            .service(
+                web::resource("/me/tokens")
+                    .route(web::get().to(list_tokens))
+                    .route(web::post().to(create_token)),
+            )
+            .service(web::resource("/me/tokens/{token_id}").route(web::delete().to(revoke_token)))
+            .service(web::resource("/me/sessions").route(web::get().to(list_sessions)))
+            .service(
+                web::resource("/me/sessions/{token_id}").route(web::delete().to(revoke_session)),
+            )
     })
-    .bind((ACTIX_SERVER, ACTIX_PORT))?
-    .workers(2)
+    .bind(bind)?
+    .workers(workers)
     .run()
     .await
 }
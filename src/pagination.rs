@@ -0,0 +1,125 @@
+use chrono::{DateTime, Utc};
+use tokio_postgres::types::ToSql;
+use uuid::Uuid;
+
+pub const DEFAULT_PAGE_LIMIT: i64 = 50;
+pub const MAX_PAGE_LIMIT: i64 = 200;
+
+/// Keyset position in the `created_at DESC, post_id DESC` ordering.
+/// Handed to clients as an opaque hex string.
+#[derive(Debug, Clone, Copy)]
+pub struct Cursor {
+    pub created_at: DateTime<Utc>,
+    pub post_id: Uuid,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        hex::encode(format!(
+            "{}:{}",
+            self.created_at.timestamp_micros(),
+            self.post_id
+        ))
+    }
+
+    pub fn decode(raw: &str) -> Option<Cursor> {
+        let bytes = hex::decode(raw).ok()?;
+        let text = String::from_utf8(bytes).ok()?;
+        let (micros, post_id) = text.split_once(':')?;
+        Some(Cursor {
+            created_at: DateTime::from_timestamp_micros(micros.parse().ok()?)?,
+            post_id: Uuid::parse_str(post_id).ok()?,
+        })
+    }
+}
+
+pub fn clamp_limit(limit: Option<i64>) -> i64 {
+    limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT)
+}
+
+/// Collects `WHERE` conditions over `post p JOIN "user" u` together with their parameters
+#[derive(Default)]
+pub struct PostQuery {
+    conditions: Vec<String>,
+    params: Vec<Box<dyn ToSql + Sync + Send>>,
+}
+
+impl PostQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a parameter and return its placeholder (`$n`)
+    pub fn bind<T: ToSql + Sync + Send + 'static>(&mut self, value: T) -> String {
+        self.params.push(Box::new(value));
+        format!("${}", self.params.len())
+    }
+
+    pub fn condition(&mut self, sql: String) {
+        self.conditions.push(sql);
+    }
+
+    /// Condition matching an exact content type, or a whole family for `image/*`
+    pub fn content_type_condition(&mut self, content_type: String) -> String {
+        match content_type.strip_suffix("/*") {
+            Some(major) => {
+                let p = self.bind(format!("{}/%", major.replace(['%', '_', '\\'], "")));
+                format!("p.content_type LIKE {}", p)
+            }
+            None => {
+                let p = self.bind(content_type);
+                format!("p.content_type = {}", p)
+            }
+        }
+    }
+
+    /// Run the query and return one page of post ids plus the cursor of the next page
+    pub async fn fetch_page(
+        mut self,
+        psql_client: &deadpool_postgres::Client,
+        cursor: Option<Cursor>,
+        limit: i64,
+    ) -> Result<(Vec<Uuid>, Option<String>), tokio_postgres::Error> {
+        if let Some(cursor) = cursor {
+            let created_at = self.bind(cursor.created_at);
+            let post_id = self.bind(cursor.post_id);
+            self.condition(format!(
+                "(p.created_at, p.post_id) < ({}, {})",
+                created_at, post_id
+            ));
+        }
+        let where_clause = match self.conditions.is_empty() {
+            true => String::new(),
+            false => format!("WHERE {}", self.conditions.join(" AND ")),
+        };
+        let query = format!(
+            "SELECT p.post_id, p.created_at FROM post p JOIN \"user\" u ON u.user_id = p.user_id {} ORDER BY p.created_at DESC, p.post_id DESC LIMIT {}",
+            where_clause,
+            limit + 1
+        );
+        let params: Vec<&(dyn ToSql + Sync)> = self
+            .params
+            .iter()
+            .map(|p| p.as_ref() as &(dyn ToSql + Sync))
+            .collect();
+        let rows = psql_client.query(&query, &params).await?;
+
+        let mut next_cursor = None;
+        if rows.len() as i64 > limit {
+            let last = &rows[limit as usize - 1];
+            next_cursor = Some(
+                Cursor {
+                    created_at: last.get(1),
+                    post_id: last.get(0),
+                }
+                .encode(),
+            );
+        }
+        let ids = rows
+            .iter()
+            .take(limit as usize)
+            .map(|row| row.get::<_, Uuid>(0))
+            .collect();
+        Ok((ids, next_cursor))
+    }
+}
@@ -0,0 +1,465 @@
+//! Search query language used by `GET /search`.
+//!
+//! ```text
+//! query   := or
+//! or      := and (('|' | "OR") and)*
+//! and     := unary+
+//! unary   := '-' unary | primary
+//! primary := '(' or ')' | term
+//! term    := tag | field ':' value
+//! ```
+//!
+//! Fields are `creator`, `uploader`, `type`, `before` and `after`.
+//! Values may be quoted (`creator:"some name"`), dates use `YYYY-MM-DD`.
+//! Positions in errors are character offsets into the query string.
+use crate::tag::normalize_tag;
+use chrono::NaiveDate;
+
+pub const MAX_QUERY_LENGTH: usize = 512;
+pub const MAX_TERMS: usize = 32;
+pub const MAX_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+    Tag(String),
+    Creator(String),
+    Uploader(String),
+    Type(String),
+    Before(NaiveDate),
+    After(NaiveDate),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Term(Term),
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl QueryError {
+    pub fn new(position: usize, message: impl Into<String>) -> Self {
+        QueryError {
+            position,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+impl std::error::Error for QueryError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Minus,
+    LParen,
+    RParen,
+    Pipe,
+}
+
+fn tokenize(input: &str) -> Result<Vec<(Token, Span)>, QueryError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let single = match c {
+            _ if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '(' => Some(Token::LParen),
+            ')' => Some(Token::RParen),
+            '|' => Some(Token::Pipe),
+            '-' => Some(Token::Minus),
+            _ => None,
+        };
+        if let Some(token) = single {
+            tokens.push((token, Span { start: i, end: i + 1 }));
+            i += 1;
+            continue;
+        }
+        let start = i;
+        let mut word = String::new();
+        let mut quoted = false;
+        while i < chars.len() && !chars[i].is_whitespace() && !"()|".contains(chars[i]) {
+            if chars[i] == '"' {
+                quoted = true;
+                let quote = i;
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    word.push(chars[i]);
+                    i += 1;
+                }
+                if i == chars.len() {
+                    return Err(QueryError::new(quote, "unterminated quote"));
+                }
+                i += 1;
+                continue;
+            }
+            word.push(chars[i]);
+            i += 1;
+        }
+        //a quoted "OR" is searched for, not an operator
+        let token = match (word.as_str(), quoted) {
+            ("OR", false) => Token::Pipe,
+            _ => Token::Word(word),
+        };
+        tokens.push((token, Span { start, end: i }));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, Span)>,
+    pos: usize,
+    end: usize,
+    terms: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(t, _)| t)
+    }
+
+    fn position(&self) -> usize {
+        match self.tokens.get(self.pos) {
+            Some((_, span)) => span.start,
+            None => self.end,
+        }
+    }
+
+    fn parse_or(&mut self, depth: usize) -> Result<Expr, QueryError> {
+        let first = self.parse_and(depth)?;
+        let mut alternatives = vec![first];
+        while self.peek() == Some(&Token::Pipe) {
+            self.pos += 1;
+            alternatives.push(self.parse_and(depth)?);
+        }
+        if alternatives.len() == 1 {
+            return Ok(alternatives.remove(0));
+        }
+        let span = Span {
+            start: alternatives[0].span.start,
+            end: alternatives[alternatives.len() - 1].span.end,
+        };
+        Ok(Expr {
+            kind: ExprKind::Or(alternatives),
+            span,
+        })
+    }
+
+    fn parse_and(&mut self, depth: usize) -> Result<Expr, QueryError> {
+        let mut clauses = Vec::new();
+        while let Some(token) = self.peek() {
+            if matches!(token, Token::Pipe | Token::RParen) {
+                break;
+            }
+            clauses.push(self.parse_unary(depth)?);
+        }
+        match clauses.len() {
+            0 => Err(QueryError::new(self.position(), "expected a search term")),
+            1 => Ok(clauses.remove(0)),
+            _ => {
+                let span = Span {
+                    start: clauses[0].span.start,
+                    end: clauses[clauses.len() - 1].span.end,
+                };
+                Ok(Expr {
+                    kind: ExprKind::And(clauses),
+                    span,
+                })
+            }
+        }
+    }
+
+    fn parse_unary(&mut self, depth: usize) -> Result<Expr, QueryError> {
+        if self.peek() == Some(&Token::Minus) {
+            let start = self.tokens[self.pos].1.start;
+            self.pos += 1;
+            if !matches!(
+                self.peek(),
+                Some(Token::Word(_)) | Some(Token::LParen) | Some(Token::Minus)
+            ) {
+                return Err(QueryError::new(start, "'-' must be followed by a term or group"));
+            }
+            let inner = self.parse_unary(depth)?;
+            let span = Span {
+                start,
+                end: inner.span.end,
+            };
+            return Ok(Expr {
+                kind: ExprKind::Not(Box::new(inner)),
+                span,
+            });
+        }
+        self.parse_primary(depth)
+    }
+
+    fn parse_primary(&mut self, depth: usize) -> Result<Expr, QueryError> {
+        let (token, span) = match self.tokens.get(self.pos) {
+            Some(entry) => entry.clone(),
+            None => return Err(QueryError::new(self.end, "expected a search term")),
+        };
+        match token {
+            Token::LParen => {
+                if depth >= MAX_DEPTH {
+                    return Err(QueryError::new(span.start, "groups are nested too deeply"));
+                }
+                self.pos += 1;
+                let mut inner = self.parse_or(depth + 1)?;
+                match self.tokens.get(self.pos) {
+                    Some((Token::RParen, close)) => {
+                        inner.span = Span {
+                            start: span.start,
+                            end: close.end,
+                        };
+                        self.pos += 1;
+                        Ok(inner)
+                    }
+                    _ => Err(QueryError::new(span.start, "unclosed group")),
+                }
+            }
+            Token::Word(word) => {
+                self.pos += 1;
+                self.terms += 1;
+                if self.terms > MAX_TERMS {
+                    return Err(QueryError::new(
+                        span.start,
+                        format!("too many terms (max {})", MAX_TERMS),
+                    ));
+                }
+                Ok(Expr {
+                    kind: ExprKind::Term(parse_term(&word, span)?),
+                    span,
+                })
+            }
+            Token::RParen => Err(QueryError::new(span.start, "unexpected ')'")),
+            Token::Pipe => Err(QueryError::new(span.start, "unexpected '|'")),
+            Token::Minus => Err(QueryError::new(span.start, "unexpected '-'")),
+        }
+    }
+}
+
+fn parse_term(word: &str, span: Span) -> Result<Term, QueryError> {
+    let (field, value) = match word.split_once(':') {
+        Some(pair) => pair,
+        None => {
+            return match normalize_tag(word) {
+                Some(tag) => Ok(Term::Tag(tag)),
+                None => Err(QueryError::new(span.start, format!("invalid tag '{}'", word))),
+            };
+        }
+    };
+    let value_pos = span.start + field.chars().count() + 1;
+    if value.is_empty() {
+        return Err(QueryError::new(
+            value_pos,
+            format!("missing value for '{}'", field),
+        ));
+    }
+    let parse_date = |value: &str| {
+        NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| {
+            QueryError::new(value_pos, format!("invalid date '{}', expected YYYY-MM-DD", value))
+        })
+    };
+    match field.to_lowercase().as_str() {
+        "creator" => Ok(Term::Creator(value.to_string())),
+        "uploader" => Ok(Term::Uploader(value.to_string())),
+        "type" => {
+            let value = value.to_lowercase();
+            match value.contains('/') {
+                true => Ok(Term::Type(value)),
+                false => Ok(Term::Type(format!("{}/*", value))),
+            }
+        }
+        "before" => Ok(Term::Before(parse_date(value)?)),
+        "after" => Ok(Term::After(parse_date(value)?)),
+        _ => Err(QueryError::new(span.start, format!("unknown field '{}'", field))),
+    }
+}
+
+pub fn parse(input: &str) -> Result<Expr, QueryError> {
+    let length = input.chars().count();
+    if length > MAX_QUERY_LENGTH {
+        return Err(QueryError::new(
+            MAX_QUERY_LENGTH,
+            format!("query is too long (max {} characters)", MAX_QUERY_LENGTH),
+        ));
+    }
+    let mut parser = Parser {
+        tokens: tokenize(input)?,
+        pos: 0,
+        end: length,
+        terms: 0,
+    };
+    let expr = parser.parse_or(0)?;
+    if let Some((_, span)) = parser.tokens.get(parser.pos) {
+        return Err(QueryError::new(span.start, "unexpected ')'"));
+    }
+    Ok(expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(name: &str) -> ExprKind {
+        ExprKind::Term(Term::Tag(name.to_string()))
+    }
+
+    fn node(kind: ExprKind) -> Expr {
+        Expr {
+            kind,
+            span: Span { start: 0, end: 0 },
+        }
+    }
+
+    fn not(kind: ExprKind) -> ExprKind {
+        ExprKind::Not(Box::new(node(kind)))
+    }
+
+    fn and(kinds: Vec<ExprKind>) -> ExprKind {
+        ExprKind::And(kinds.into_iter().map(node).collect())
+    }
+
+    fn or(kinds: Vec<ExprKind>) -> ExprKind {
+        ExprKind::Or(kinds.into_iter().map(node).collect())
+    }
+
+    /// The expression tree with every span zeroed
+    fn shape(expr: &Expr) -> ExprKind {
+        match &expr.kind {
+            ExprKind::Term(term) => ExprKind::Term(term.clone()),
+            ExprKind::Not(inner) => not(shape(inner)),
+            ExprKind::And(children) => and(children.iter().map(shape).collect()),
+            ExprKind::Or(children) => or(children.iter().map(shape).collect()),
+        }
+    }
+
+    fn parsed(input: &str) -> ExprKind {
+        shape(&parse(input).unwrap())
+    }
+
+    fn error_at(input: &str) -> usize {
+        parse(input).unwrap_err().position
+    }
+
+    #[test]
+    fn single_tag_is_lowercased() {
+        assert_eq!(parsed("Cat"), tag("cat"));
+    }
+
+    #[test]
+    fn adjacent_terms_are_joined_with_and() {
+        assert_eq!(parsed("cat dog"), and(vec![tag("cat"), tag("dog")]));
+    }
+
+    #[test]
+    fn negation_applies_to_terms_and_groups() {
+        assert_eq!(parsed("-cat"), not(tag("cat")));
+        assert_eq!(parsed("--cat"), not(not(tag("cat"))));
+        assert_eq!(parsed("-(cat | dog)"), not(or(vec![tag("cat"), tag("dog")])));
+    }
+
+    #[test]
+    fn or_binds_looser_than_and() {
+        assert_eq!(
+            parsed("cat dog | bird"),
+            or(vec![and(vec![tag("cat"), tag("dog")]), tag("bird")])
+        );
+        assert_eq!(
+            parsed("cat (dog OR bird)"),
+            and(vec![tag("cat"), or(vec![tag("dog"), tag("bird")])])
+        );
+    }
+
+    #[test]
+    fn quoted_values_keep_spaces() {
+        assert_eq!(
+            parsed("creator:\"some name\""),
+            ExprKind::Term(Term::Creator("some name".to_string()))
+        );
+    }
+
+    #[test]
+    fn quoted_or_is_a_literal() {
+        assert_eq!(parsed("\"OR\""), tag("or"));
+        assert_eq!(parsed("cat \"OR\" dog"), and(vec![tag("cat"), tag("or"), tag("dog")]));
+        assert_eq!(parsed("cat or dog"), and(vec![tag("cat"), tag("or"), tag("dog")]));
+    }
+
+    #[test]
+    fn fields_are_parsed() {
+        assert_eq!(
+            parsed("type:image"),
+            ExprKind::Term(Term::Type("image/*".to_string()))
+        );
+        assert_eq!(
+            parsed("after:2024-01-31"),
+            ExprKind::Term(Term::After(NaiveDate::from_ymd_opt(2024, 1, 31).unwrap()))
+        );
+    }
+
+    #[test]
+    fn spans_cover_the_source() {
+        let expr = parse("cat -(dog | bird)").unwrap();
+        assert_eq!(expr.span, Span { start: 0, end: 17 });
+        match expr.kind {
+            ExprKind::And(children) => assert_eq!(children[1].span, Span { start: 4, end: 17 }),
+            other => panic!("expected and, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_character() {
+        assert_eq!(error_at(""), 0);
+        assert_eq!(error_at("cat |"), 5);
+        assert_eq!(error_at("cat | | dog"), 6);
+        assert_eq!(error_at("cat )"), 4);
+        assert_eq!(error_at("cat (dog"), 4);
+        assert_eq!(error_at("cat -"), 4);
+        assert_eq!(error_at("cat \"dog"), 4);
+        assert_eq!(error_at("cat color:red"), 4);
+        assert_eq!(error_at("before:2024-13-01"), 7);
+        assert_eq!(error_at("creator:"), 8);
+        assert_eq!(error_at("ca$t"), 0);
+    }
+
+    #[test]
+    fn positions_count_characters_not_bytes() {
+        assert_eq!(error_at("ねこ |"), 4);
+    }
+
+    #[test]
+    fn limits_are_enforced() {
+        let deep = format!("{}cat{}", "(".repeat(MAX_DEPTH + 1), ")".repeat(MAX_DEPTH + 1));
+        assert_eq!(error_at(&deep), MAX_DEPTH);
+        let many = vec!["cat"; MAX_TERMS + 1].join(" ");
+        assert_eq!(error_at(&many), MAX_TERMS * 4);
+        assert_eq!(error_at(&"a".repeat(MAX_QUERY_LENGTH + 1)), MAX_QUERY_LENGTH);
+    }
+}
@@ -0,0 +1,45 @@
+use deadpool_postgres::Client;
+use serde::Serialize;
+use uuid::Uuid;
+
+/// Store a post lives in, used to report which cleanup step failed
+#[derive(Debug, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum RepairStep {
+    Postgres,
+    Mongodb,
+    File,
+}
+
+impl std::fmt::Display for RepairStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RepairStep::Postgres => write!(f, "postgres"),
+            RepairStep::Mongodb => write!(f, "mongodb"),
+            RepairStep::File => write!(f, "file"),
+        }
+    }
+}
+
+/// Record leftover state of a half-finished operation so it can be repaired later.
+/// `blob_hash` names the bytes a `file` step left behind, `None` for legacy files.
+/// `mediapub-admin fsck --repair` resolves the entry once nothing is left.
+pub async fn enqueue(
+    psql_client: &Client,
+    post_id: &Uuid,
+    filename: &str,
+    blob_hash: Option<&str>,
+    step: RepairStep,
+    detail: &str,
+) {
+    let query = r#"
+        INSERT INTO "repair_queue" (post_id, filename, blob_hash, failed_step, detail)
+        VALUES ($1, $2, $3, $4, $5)
+    "#;
+    if let Err(e) = psql_client
+        .execute(query, &[post_id, &filename, &blob_hash, &step.to_string(), &detail])
+        .await
+    {
+        eprintln!("Failed to queue repair for post {}: {}", post_id, e);
+    }
+}
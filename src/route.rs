@@ -3,4 +3,8 @@ pub mod update;
 pub mod ping;
 pub mod items;
 pub mod user;
-pub mod drop;
\ No newline at end of file
+pub mod drop;
+pub mod tag;
+pub mod search;
+pub mod similar;
+pub mod tus;
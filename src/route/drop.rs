@@ -0,0 +1,204 @@
+use crate::{
+    auth::AuthUser,
+    blob,
+    db_pool::mongo_database,
+    imaging::{phash::SimilarityIndex, thumbnail},
+    repair::{self, RepairStep},
+    scope::Scope,
+    settings::Settings,
+    storage::BlobStore,
+    types::{DropResponse, ErrorResponse, StepErrorResponse},
+    utility::{check_post_ownership, generate_response, get_psql_pool, uuid_to_binary},
+};
+use actix_web::{HttpResponse, Responder, web};
+use deadpool_postgres::Pool;
+use mongodb::{
+    Client,
+    bson::{Document, doc},
+};
+use std::io;
+use std::path::Path;
+use uuid::Uuid;
+
+/// Delete a post from Postgres, Mongo and the file store.
+/// The Postgres row is only committed once the Mongo document is gone, and the
+/// blob file is only removed when no other post references it;
+/// anything left behind after a partial failure is queued in `repair_queue`.
+pub async fn drop(
+    user: AuthUser,
+    psql_pool: web::Data<Pool>,
+    mongo_pool: web::Data<Client>,
+    similarity: web::Data<SimilarityIndex>,
+    store: web::Data<dyn BlobStore>,
+    settings: web::Data<Settings>,
+    item_id: web::Path<String>,
+) -> io::Result<impl Responder> {
+    let post_id = match Uuid::parse_str(&item_id.into_inner()) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid post ID format".to_string(),
+            }));
+        }
+    };
+    if let Err(e) = user.require(Scope::PostsDelete) {
+        return Ok(generate_response(&e));
+    }
+    let user_id = user.user_id;
+    let mut postgres = match get_psql_pool(&psql_pool).await {
+        Ok(conn) => conn,
+        Err(_) => {
+            return Ok(HttpResponse::ExpectationFailed().json(ErrorResponse {
+                error: "Failed to get database connection".to_string(),
+            }));
+        }
+    };
+    let filename = match check_post_ownership(&postgres, &post_id, &user_id).await {
+        Ok(name) => name,
+        Err(e) => return Ok(generate_response(&e)),
+    };
+
+    if let Err(step) = delete_post(
+        &mut postgres,
+        &mongo_pool,
+        store.get_ref(),
+        &settings.files.destination,
+        Some(&similarity),
+        &post_id,
+        &filename,
+    )
+    .await
+    {
+        return Ok(step_failed(step));
+    }
+
+    Ok(HttpResponse::Ok().json(DropResponse {
+        post_id: post_id.to_string(),
+        message: "post deleted successfully.".to_string(),
+    }))
+}
+
+/// Remove a post from Postgres, Mongo and the file store, returning the step that failed.
+/// `similarity` is the server's in-memory index, when there is one to update.
+pub async fn delete_post(
+    postgres: &mut deadpool_postgres::Client,
+    mongo_pool: &Client,
+    store: &dyn BlobStore,
+    destination: &Path,
+    similarity: Option<&SimilarityIndex>,
+    post_id: &Uuid,
+    filename: &str,
+) -> Result<(), RepairStep> {
+    //postgres
+    let transaction = match postgres.transaction().await {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("Failed to begin transaction: {}", e);
+            return Err(RepairStep::Postgres);
+        }
+    };
+    let blob_hash: Option<String> = match transaction
+        .query_one(
+            "DELETE FROM post WHERE post_id = $1 RETURNING blob_hash",
+            &[post_id],
+        )
+        .await
+    {
+        Ok(row) => row.get(0),
+        Err(e) => {
+            eprintln!("PostgreSQL Delete Error: {}", e);
+            return Err(RepairStep::Postgres);
+        }
+    };
+    //other posts may still share the blob
+    let remove_file = match &blob_hash {
+        Some(hash) => match blob::release(&transaction, hash).await {
+            Ok(last) => last,
+            Err(e) => {
+                eprintln!("PostgreSQL Blob Error: {}", e);
+                return Err(RepairStep::Postgres);
+            }
+        },
+        None => true,
+    };
+
+    //mongo
+    let coll = mongo_database(mongo_pool)
+        .collection::<Document>("post");
+    if let Err(e) = coll
+        .delete_one(doc! {"post_id": uuid_to_binary(post_id)})
+        .await
+    {
+        eprintln!("MongoDB Delete Error: {}", e);
+        //nothing has been removed yet, the transaction rolls back on drop
+        return Err(RepairStep::Mongodb);
+    }
+
+    //file, removed while the blob row is still locked so a concurrent
+    //upload of the same bytes cannot reuse it in between
+    let mut file_failed = false;
+    if remove_file {
+        let removed = match &blob_hash {
+            Some(hash) => store.delete(hash).await,
+            None => match tokio::fs::remove_file(destination.join(filename)).await {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    eprintln!("{} was already missing from storage", filename);
+                    Ok(())
+                }
+                other => other,
+            },
+        };
+        match removed {
+            Ok(_) => println!("{} removed successfully", filename),
+            Err(e) => {
+                eprintln!("{} failed to remove: {}", filename, e);
+                file_failed = true;
+            }
+        }
+    }
+    if let Err(e) = transaction.commit().await {
+        eprintln!("Failed to commit post deletion: {}", e);
+        repair::enqueue(
+            postgres,
+            post_id,
+            filename,
+            blob_hash.as_deref(),
+            RepairStep::Postgres,
+            "mongo document and file deleted but post row remains",
+        )
+        .await;
+        return Err(RepairStep::Postgres);
+    }
+    if let Some(similarity) = similarity {
+        similarity.remove(post_id);
+    }
+    if file_failed {
+        repair::enqueue(
+            postgres,
+            post_id,
+            filename,
+            blob_hash.as_deref(),
+            RepairStep::File,
+            "post row and mongo document deleted but file remains",
+        )
+        .await;
+        return Err(RepairStep::File);
+    }
+
+    if let Err(e) = thumbnail::remove_all(destination, post_id).await {
+        eprintln!("Failed to remove thumbnails of {}: {}", post_id, e);
+    }
+    Ok(())
+}
+
+fn step_failed(step: RepairStep) -> HttpResponse {
+    let error = match step {
+        RepairStep::Postgres => "Failed to delete post from PostgreSQL.",
+        RepairStep::Mongodb => "Failed to delete post from MongoDB.",
+        RepairStep::File => "Failed to remove stored file.",
+    };
+    HttpResponse::InternalServerError().json(StepErrorResponse {
+        error: error.to_string(),
+        failed_step: step,
+    })
+}
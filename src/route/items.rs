@@ -1,13 +1,26 @@
-use crate::types::{ErrorResponse, ItemResponse, ResponseFile, UploadJson};
+use crate::imaging::{
+    analysis,
+    cache::DerivedCache,
+    is_decodable,
+    thumbnail::{self, ThumbSize, thumbnail_path},
+    transform::{self, TransformError, TransformParams},
+};
+use crate::pagination::{Cursor, PostQuery, clamp_limit};
+use crate::route::tag::fetch_post_tags;
+use crate::settings::Settings;
+use crate::types::{
+    ErrorResponse, ItemListResponse, ItemQuery, ItemResponse, TransformQuery, UploadJson,
+};
 use crate::utility::get_psql_pool;
-use crate::{DESTINATION, MONGODB_DBANAME};
+use crate::storage::{self, BlobStore};
+use crate::db_pool::mongo_database;
 use actix_files::NamedFile;
-use actix_web::{HttpResponse, Responder, web};
+use actix_web::{HttpRequest, HttpResponse, Responder, mime, web};
 use deadpool_postgres::Pool;
 use mongodb::Client;
 use mongodb::bson::{Binary, doc, spec::BinarySubtype};
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
 pub async fn get_one(
@@ -51,8 +64,7 @@ pub async fn get_one(
             }));
         }
     };
-    let coll = mongo_pool
-        .database(MONGODB_DBANAME)
+    let coll = mongo_database(&mongo_pool)
         //we do not use Post type here so that rust fails convert type(mongo express uuid as bin)
         .collection::<mongodb::bson::Document>("post");
     let uuid_binary = Binary {
@@ -84,6 +96,24 @@ pub async fn get_one(
             }));
         }
     };
+    let tags = match fetch_post_tags(&clinet, &post_id).await {
+        Ok(tags) => tags,
+        Err(e) => {
+            eprintln!("Tag query failed: {}", e);
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database query failed".to_string(),
+            }));
+        }
+    };
+    let (analysis_status, analysis) = match analysis::fetch(&clinet, &post_id).await {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            eprintln!("Analysis query failed: {}", e);
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database query failed".to_string(),
+            }));
+        }
+    };
     Ok(HttpResponse::Ok().json(ItemResponse {
         image: filename,
         metadata: UploadJson {
@@ -92,18 +122,154 @@ pub async fn get_one(
             source: meta_source,
             description: meta_description,
         },
+        tags,
+        analysis,
+        analysis_status,
     }))
 }
-pub async fn open_file(item: web::Path<String>) -> io::Result<impl Responder> {
-    let filename = item.into_inner();
+/// Serve a thumbnail, regenerating it when it is missing
+pub async fn open_thumbnail(
+    request: HttpRequest,
+    psql_pool: web::Data<Pool>,
+    store: web::Data<dyn BlobStore>,
+    settings: web::Data<Settings>,
+    path: web::Path<(String, String)>,
+) -> io::Result<HttpResponse> {
+    let destination = settings.files.destination.clone();
+    let (item_id, raw_size) = path.into_inner();
+    let post_id = match Uuid::parse_str(&item_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid post ID format".to_string(),
+            }));
+        }
+    };
+    let size = match ThumbSize::parse(&raw_size) {
+        Some(size) => size,
+        None => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "thumbnail size must be small, medium or large.".to_string(),
+            }));
+        }
+    };
+    let thumb_path = thumbnail_path(&destination, &post_id, size);
+    if let Ok(file) = NamedFile::open_async(&thumb_path).await {
+        return Ok(file.into_response(&request));
+    }
+
+    let client = match get_psql_pool(&psql_pool).await {
+        Ok(conn) => conn,
+        Err(_) => {
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Failed to get database connection".to_string(),
+            }));
+        }
+    };
+    let (filename, content_type, blob_hash) = match client
+        .query_opt(
+            "SELECT filename, content_type, blob_hash FROM post WHERE post_id = $1",
+            &[&post_id],
+        )
+        .await
+    {
+        Ok(Some(row)) => (
+            row.get::<_, String>(0),
+            row.get::<_, String>(1),
+            row.get::<_, Option<String>>(2),
+        ),
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(ErrorResponse {
+                error: "Item not found".to_string(),
+            }));
+        }
+        Err(e) => {
+            eprintln!("Query Error : {}", e);
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Query failed".to_string(),
+            }));
+        }
+    };
+    if !is_decodable(&content_type) {
+        return Ok(HttpResponse::UnsupportedMediaType().json(ErrorResponse {
+            error: "thumbnails are only available for images.".to_string(),
+        }));
+    }
+    let source = match storage::fetch_local(store.as_ref(), &destination, &filename, blob_hash.as_deref())
+        .await
+    {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Failed to fetch {}: {}", filename, e);
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Failed to read stored file.".to_string(),
+            }));
+        }
+    };
+    let generated = tokio::task::spawn_blocking(move || {
+        thumbnail::generate(&source.path, &destination, &post_id, size)
+    })
+    .await;
+    match generated {
+        Ok(Ok(path)) => Ok(NamedFile::open_async(path).await?.into_response(&request)),
+        Ok(Err(e)) => {
+            eprintln!("Thumbnail generation failed for {}: {}", post_id, e);
+            Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Failed to generate thumbnail.".to_string(),
+            }))
+        }
+        Err(e) => {
+            eprintln!("Thumbnail task failed: {}", e);
+            Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Failed to generate thumbnail.".to_string(),
+            }))
+        }
+    }
+}
+
+/// Serve a blob as is. Blobs have no extension, so the type comes from the post.
+async fn serve_blob(
+    request: &HttpRequest,
+    store: &dyn BlobStore,
+    hash: &str,
+    content_type: &str,
+) -> io::Result<HttpResponse> {
+    let content_type: mime::Mime = match content_type.parse() {
+        Ok(m) => m,
+        Err(_) => mime::APPLICATION_OCTET_STREAM,
+    };
+    if let Some(path) = store.local_path(hash) {
+        let file = NamedFile::open_async(path).await?.set_content_type(content_type);
+        return Ok(file.into_response(request));
+    }
+    match store.get(hash).await? {
+        Some(reader) => {
+            let mut response = HttpResponse::Ok();
+            response.content_type(content_type);
+            if let Some(size) = reader.size {
+                response.no_chunking(size);
+            }
+            Ok(response.streaming(reader.stream))
+        }
+        None => {
+            eprintln!("blob {} is missing from storage", hash);
+            Ok(HttpResponse::NotFound().json(ErrorResponse {
+                error: "Item not found".to_string(),
+            }))
+        }
+    }
+}
+
+/// Resolve a stored filename inside `destination`, rejecting anything that escapes it
+fn resolve_stored_file(destination: &Path, filename: &str) -> io::Result<PathBuf> {
     if filename.contains("..") || filename.starts_with("/") || filename.starts_with("\\") {
         return Err(io::Error::new(
             io::ErrorKind::PermissionDenied,
             "Invalid file path",
         ));
     }
-    let base_path = PathBuf::from(DESTINATION);
-    let full_path = base_path.join(&filename);
+    let base_path = destination;
+    let full_path = base_path.join(filename);
     match full_path.canonicalize() {
         Ok(canonical_path) => {
             let canonical_base = match base_path.canonicalize() {
@@ -121,7 +287,7 @@ pub async fn open_file(item: web::Path<String>) -> io::Result<impl Responder> {
                     "Access denied: path outside allowed directory",
                 ));
             }
-            NamedFile::open(&canonical_path)
+            Ok(canonical_path)
         }
         Err(e) => {
             eprintln!("File not found or access denied: {}", e);
@@ -129,7 +295,160 @@ pub async fn open_file(item: web::Path<String>) -> io::Result<impl Responder> {
         }
     }
 }
-pub async fn get_all(pool: web::Data<Pool>) -> io::Result<impl Responder> {
+
+/// Serve a stored file by its public `{post_id}.{ext}` name. With `w`, `h`, `fit`,
+/// `fmt` or `crop` the image is transformed first and the result kept in the derived cache.
+/// Transformed images are at most `MAX_DIMENSION` on each edge.
+pub async fn open_file(
+    request: HttpRequest,
+    psql_pool: web::Data<Pool>,
+    item: web::Path<String>,
+    query: web::Query<TransformQuery>,
+    cache: web::Data<DerivedCache>,
+    store: web::Data<dyn BlobStore>,
+    settings: web::Data<Settings>,
+) -> io::Result<HttpResponse> {
+    let filename = item.into_inner();
+    let destination = &settings.files.destination;
+    let client = match get_psql_pool(&psql_pool).await {
+        Ok(conn) => conn,
+        Err(_) => {
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Failed to get database connection".to_string(),
+            }));
+        }
+    };
+    let stored = match client
+        .query_opt(
+            "SELECT content_type, blob_hash FROM post WHERE filename = $1",
+            &[&filename],
+        )
+        .await
+    {
+        Ok(row) => row.map(|r| (r.get::<_, String>(0), r.get::<_, Option<String>>(1))),
+        Err(e) => {
+            eprintln!("Query Error : {}", e);
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Query failed".to_string(),
+            }));
+        }
+    };
+    let blob = match stored {
+        Some((content_type, Some(hash))) => Some((content_type, hash)),
+        _ => {
+            resolve_stored_file(destination, &filename)?;
+            None
+        }
+    };
+    let ext = PathBuf::from(&filename)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let params = match TransformParams::parse(
+        query.w,
+        query.h,
+        query.fit.as_deref(),
+        query.fmt.as_deref(),
+        query.crop.as_deref(),
+        &ext,
+    ) {
+        Ok(Some(params)) => params,
+        Ok(None) => {
+            return match &blob {
+                Some((content_type, hash)) => {
+                    serve_blob(&request, store.as_ref(), hash, content_type).await
+                }
+                None => Ok(NamedFile::open_async(resolve_stored_file(destination, &filename)?)
+                    .await?
+                    .into_response(&request)),
+            };
+        }
+        Err(message) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse { error: message }));
+        }
+    };
+    if !matches!(ext.as_str(), "png" | "jpg" | "jpeg" | "gif" | "webp") {
+        return Ok(HttpResponse::UnsupportedMediaType().json(ErrorResponse {
+            error: "only images can be transformed.".to_string(),
+        }));
+    }
+    let content_type: mime::Mime = match params.content_type().parse() {
+        Ok(m) => m,
+        Err(_) => mime::APPLICATION_OCTET_STREAM,
+    };
+    let name = params.cache_name(&filename);
+    if let Some(path) = cache.get(&name) {
+        let file = NamedFile::open_async(path).await?.set_content_type(content_type);
+        return Ok(file.into_response(&request));
+    }
+
+    let _permit = match cache.permits.acquire().await {
+        Ok(permit) => permit,
+        Err(_) => return Err(io::Error::other("transform queue closed")),
+    };
+    //another request may have produced it while we waited
+    if let Some(path) = cache.get(&name) {
+        let file = NamedFile::open_async(path).await?.set_content_type(content_type);
+        return Ok(file.into_response(&request));
+    }
+    let hash = blob.as_ref().map(|(_, hash)| hash.as_str());
+    let source = match storage::fetch_local(store.as_ref(), destination, &filename, hash).await {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Failed to fetch {}: {}", filename, e);
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Failed to read stored file.".to_string(),
+            }));
+        }
+    };
+    let partial = cache.partial_path(&name);
+    let target = partial.clone();
+    let result =
+        tokio::task::spawn_blocking(move || transform::apply(&source.path, &params, &target))
+            .await;
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(TransformError::CropOutOfBounds)) => {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "crop is outside of the image.".to_string(),
+            }));
+        }
+        Ok(Err(e)) => {
+            eprintln!("Transform of {} failed: {}", filename, e);
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Failed to transform image.".to_string(),
+            }));
+        }
+        Err(e) => {
+            eprintln!("Transform task failed: {}", e);
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Failed to transform image.".to_string(),
+            }));
+        }
+    }
+    let path = cache.insert(&name, &partial)?;
+    let file = NamedFile::open_async(path).await?.set_content_type(content_type);
+    Ok(file.into_response(&request))
+}
+/// List post ids newest first, one page at a time.
+/// `before`/`after` bound `created_at`, `content_type` accepts a `type/*` wildcard.
+pub async fn get_all(
+    pool: web::Data<Pool>,
+    query: web::Query<ItemQuery>,
+) -> io::Result<impl Responder> {
+    let query = query.into_inner();
+    let cursor = match query.cursor.as_deref().map(Cursor::decode) {
+        None => None,
+        Some(Some(cursor)) => Some(cursor),
+        Some(None) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid cursor".to_string(),
+            }));
+        }
+    };
     let client = match pool.get().await {
         Ok(conn) => conn,
         Err(e) => {
@@ -139,12 +458,28 @@ pub async fn get_all(pool: web::Data<Pool>) -> io::Result<impl Responder> {
             }));
         }
     };
-    let result = client.query("SELECT post_id FROM post", &[]).await;
-    let ids: Vec<Uuid> = match result {
-        Ok(row_vec) => row_vec
-            .iter()
-            .map(|f| -> Uuid { f.get::<_, Uuid>("post_id") })
-            .collect(),
+    let mut post_query = PostQuery::new();
+    if let Some(before) = query.before {
+        let p = post_query.bind(before);
+        post_query.condition(format!("p.created_at < {}", p));
+    }
+    if let Some(after) = query.after {
+        let p = post_query.bind(after);
+        post_query.condition(format!("p.created_at > {}", p));
+    }
+    if let Some(uploader) = query.uploader {
+        let p = post_query.bind(uploader);
+        post_query.condition(format!("u.username = {}", p));
+    }
+    if let Some(content_type) = query.content_type {
+        let condition = post_query.content_type_condition(content_type);
+        post_query.condition(condition);
+    }
+    let (ids, next_cursor) = match post_query
+        .fetch_page(&client, cursor, clamp_limit(query.limit))
+        .await
+    {
+        Ok(page) => page,
         Err(e) => {
             eprintln!("Query failed: {}", e);
             return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
@@ -152,8 +487,9 @@ pub async fn get_all(pool: web::Data<Pool>) -> io::Result<impl Responder> {
             }));
         }
     };
-    let response = ResponseFile {
+    let response = ItemListResponse {
         file: ids.iter().map(|id| -> String { id.to_string() }).collect(),
+        next_cursor,
     };
     Ok(HttpResponse::Ok().json(response))
 }
@@ -0,0 +1,273 @@
+use crate::{
+    db_pool::mongo_database,
+    pagination::{Cursor, PostQuery, clamp_limit},
+    query::{self, Expr, ExprKind, QueryError, Term},
+    types::{ErrorResponse, ItemListResponse, QueryErrorResponse, SearchQuery},
+    utility::get_psql_pool,
+};
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{Days, NaiveDate, NaiveTime};
+use deadpool_postgres::Pool;
+use mongodb::{
+    Client, Collection,
+    bson::{Document, RawBsonRef, doc},
+};
+use std::io;
+use uuid::Uuid;
+
+/// Upper bound on posts matched by the Mongo half of a query
+pub const MAX_MONGO_MATCHES: usize = 10_000;
+
+#[derive(Debug, PartialEq)]
+enum Store {
+    Postgres,
+    Mongodb,
+    Mixed,
+}
+
+fn store_of(expr: &Expr) -> Store {
+    let merge = |children: &[Expr]| {
+        let mut stores = children.iter().map(store_of);
+        let first = stores.next().unwrap_or(Store::Postgres);
+        stores.fold(first, |acc, s| if acc == s { acc } else { Store::Mixed })
+    };
+    match &expr.kind {
+        ExprKind::Term(Term::Creator(_)) => Store::Mongodb,
+        ExprKind::Term(_) => Store::Postgres,
+        ExprKind::Not(inner) => store_of(inner),
+        ExprKind::And(children) | ExprKind::Or(children) => merge(children),
+    }
+}
+
+fn start_of_day(date: &NaiveDate) -> chrono::DateTime<chrono::Utc> {
+    date.and_time(NaiveTime::MIN).and_utc()
+}
+
+fn to_sql(expr: &Expr, post_query: &mut PostQuery) -> String {
+    match &expr.kind {
+        ExprKind::Term(Term::Tag(name)) => {
+            let p = post_query.bind(name.clone());
+            format!(
+                "EXISTS (SELECT 1 FROM post_tag pt JOIN tag t ON t.tag_id = pt.tag_id WHERE pt.post_id = p.post_id AND t.name = {})",
+                p
+            )
+        }
+        ExprKind::Term(Term::Uploader(name)) => {
+            let p = post_query.bind(name.clone());
+            format!("u.username = {}", p)
+        }
+        ExprKind::Term(Term::Type(content_type)) => {
+            post_query.content_type_condition(content_type.clone())
+        }
+        ExprKind::Term(Term::Before(date)) => {
+            let p = post_query.bind(start_of_day(date));
+            format!("p.created_at < {}", p)
+        }
+        //after:DATE means after that whole day
+        ExprKind::Term(Term::After(date)) => {
+            let next_day = date.checked_add_days(Days::new(1)).unwrap_or(*date);
+            let p = post_query.bind(start_of_day(&next_day));
+            format!("p.created_at >= {}", p)
+        }
+        ExprKind::Term(Term::Creator(_)) => unreachable!("creator terms are matched in Mongo"),
+        ExprKind::Not(inner) => format!("NOT ({})", to_sql(inner, post_query)),
+        ExprKind::And(children) => {
+            let parts: Vec<String> = children.iter().map(|c| to_sql(c, post_query)).collect();
+            format!("({})", parts.join(" AND "))
+        }
+        ExprKind::Or(children) => {
+            let parts: Vec<String> = children.iter().map(|c| to_sql(c, post_query)).collect();
+            format!("({})", parts.join(" OR "))
+        }
+    }
+}
+
+/// Escape regex metacharacters so creator names match literally
+fn escape_regex(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn to_mongo(expr: &Expr) -> Document {
+    match &expr.kind {
+        ExprKind::Term(Term::Creator(name)) => doc! {
+            "creator": {"$regex": format!("^{}$", escape_regex(name)), "$options": "i"}
+        },
+        ExprKind::Term(_) => unreachable!("only creator terms are matched in Mongo"),
+        ExprKind::Not(inner) => doc! {"$nor": [to_mongo(inner)]},
+        ExprKind::And(children) => {
+            doc! {"$and": children.iter().map(to_mongo).collect::<Vec<Document>>()}
+        }
+        ExprKind::Or(children) => {
+            doc! {"$or": children.iter().map(to_mongo).collect::<Vec<Document>>()}
+        }
+    }
+}
+
+/// Remove leading negations, returning whether the clause excludes what `inner` matches.
+/// A negated creator term matches nearly every post, so Mongo is asked for the
+/// positive term and Postgres excludes the result instead.
+fn strip_negation(expr: &Expr) -> (bool, &Expr) {
+    let mut negated = false;
+    let mut inner = expr;
+    while let ExprKind::Not(child) = &inner.kind {
+        negated = !negated;
+        inner = child;
+    }
+    (negated, inner)
+}
+
+/// Split the top-level conjunction into the Postgres part and the Mongo part
+fn split(expr: Expr) -> Result<(Vec<Expr>, Vec<Expr>), QueryError> {
+    let clauses = match expr.kind {
+        ExprKind::And(children) => children,
+        _ => vec![expr],
+    };
+    let mut postgres = Vec::new();
+    let mut mongo = Vec::new();
+    for clause in clauses {
+        match store_of(&clause) {
+            Store::Postgres => postgres.push(clause),
+            Store::Mongodb => mongo.push(clause),
+            Store::Mixed => {
+                return Err(QueryError::new(
+                    clause.span.start,
+                    "creator: cannot be combined with other fields inside a group",
+                ));
+            }
+        }
+    }
+    Ok((postgres, mongo))
+}
+
+async fn matching_post_ids(
+    coll: &Collection<Document>,
+    filter: Document,
+) -> Result<Vec<Uuid>, HttpResponse> {
+    let ids = match find_post_ids(coll, filter).await {
+        Ok(ids) => ids,
+        Err(e) => {
+            eprintln!("MongoDB query error: {}", e);
+            return Err(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database query failed".to_string(),
+            }));
+        }
+    };
+    if ids.len() > MAX_MONGO_MATCHES {
+        return Err(HttpResponse::BadRequest().json(ErrorResponse {
+            error: "creator filter matches too many posts, narrow the query.".to_string(),
+        }));
+    }
+    Ok(ids)
+}
+
+async fn find_post_ids(
+    coll: &Collection<Document>,
+    filter: Document,
+) -> Result<Vec<Uuid>, mongodb::error::Error> {
+    let mut found = coll
+        .find(filter)
+        .projection(doc! {"post_id": 1, "_id": 0})
+        .limit(MAX_MONGO_MATCHES as i64 + 1)
+        .await?;
+    let mut ids = Vec::new();
+    while found.advance().await? {
+        if let Ok(Some(RawBsonRef::Binary(bin))) = found.current().get("post_id")
+            && let Ok(id) = Uuid::from_slice(bin.bytes)
+        {
+            ids.push(id);
+        }
+    }
+    Ok(ids)
+}
+
+pub async fn search(
+    psql_pool: web::Data<Pool>,
+    mongo_pool: web::Data<Client>,
+    query: web::Query<SearchQuery>,
+) -> io::Result<impl Responder> {
+    let query = query.into_inner();
+    let (postgres_clauses, mongo_clauses) = match query::parse(&query.q).and_then(split) {
+        Ok(parts) => parts,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(QueryErrorResponse {
+                error: e.to_string(),
+                position: e.position,
+            }));
+        }
+    };
+    let cursor = match query.cursor.as_deref().map(Cursor::decode) {
+        None => None,
+        Some(Some(cursor)) => Some(cursor),
+        Some(None) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid cursor".to_string(),
+            }));
+        }
+    };
+
+    let mut post_query = PostQuery::new();
+    for clause in &postgres_clauses {
+        let condition = to_sql(clause, &mut post_query);
+        post_query.condition(condition);
+    }
+    if !mongo_clauses.is_empty() {
+        let coll = mongo_database(&mongo_pool)
+            .collection::<Document>("post");
+        let mut included = Vec::new();
+        let mut excluded = Vec::new();
+        for clause in &mongo_clauses {
+            match strip_negation(clause) {
+                (false, inner) => included.push(to_mongo(inner)),
+                (true, inner) => excluded.push(to_mongo(inner)),
+            }
+        }
+        if !included.is_empty() {
+            let ids = match matching_post_ids(&coll, doc! {"$and": included}).await {
+                Ok(ids) => ids,
+                Err(response) => return Ok(response),
+            };
+            let p = post_query.bind(ids);
+            post_query.condition(format!("p.post_id = ANY({})", p));
+        }
+        for filter in excluded {
+            let ids = match matching_post_ids(&coll, filter).await {
+                Ok(ids) => ids,
+                Err(response) => return Ok(response),
+            };
+            let p = post_query.bind(ids);
+            post_query.condition(format!("NOT (p.post_id = ANY({}))", p));
+        }
+    }
+
+    let client = match get_psql_pool(&psql_pool).await {
+        Ok(conn) => conn,
+        Err(_) => {
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Failed to get database connection".to_string(),
+            }));
+        }
+    };
+    let (ids, next_cursor) = match post_query
+        .fetch_page(&client, cursor, clamp_limit(query.limit))
+        .await
+    {
+        Ok(page) => page,
+        Err(e) => {
+            eprintln!("Query failed: {}", e);
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Query failed".to_string(),
+            }));
+        }
+    };
+    Ok(HttpResponse::Ok().json(ItemListResponse {
+        file: ids.iter().map(|id| id.to_string()).collect(),
+        next_cursor,
+    }))
+}
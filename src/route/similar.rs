@@ -0,0 +1,72 @@
+use crate::{
+    imaging::phash::{MAX_SIMILAR_DISTANCE, SIMILAR_DISTANCE, SimilarityIndex},
+    pagination::clamp_limit,
+    types::{ErrorResponse, SimilarPost, SimilarQuery, SimilarResponse},
+    utility::get_psql_pool,
+};
+use actix_web::{HttpResponse, Responder, web};
+use deadpool_postgres::Pool;
+use std::io;
+use uuid::Uuid;
+
+/// Posts whose perceptual hash is within `distance` bits of this one, closest first
+pub async fn similar(
+    psql_pool: web::Data<Pool>,
+    similarity: web::Data<SimilarityIndex>,
+    item_id: web::Path<String>,
+    query: web::Query<SimilarQuery>,
+) -> io::Result<impl Responder> {
+    let post_id = match Uuid::parse_str(&item_id.into_inner()) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid post ID format".to_string(),
+            }));
+        }
+    };
+    let max_distance = query.distance.unwrap_or(SIMILAR_DISTANCE);
+    if max_distance > MAX_SIMILAR_DISTANCE {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+            error: format!("distance must be at most {}.", MAX_SIMILAR_DISTANCE),
+        }));
+    }
+    let hash = match similarity.hash_of(&post_id) {
+        Some(hash) => hash,
+        None => {
+            //tell apart a missing post from one that is not an image
+            let client = match get_psql_pool(&psql_pool).await {
+                Ok(conn) => conn,
+                Err(_) => {
+                    return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                        error: "Failed to get database connection".to_string(),
+                    }));
+                }
+            };
+            return match client
+                .query_opt("SELECT 1 FROM post WHERE post_id = $1", &[&post_id])
+                .await
+            {
+                Ok(Some(_)) => Ok(HttpResponse::UnprocessableEntity().json(ErrorResponse {
+                    error: "this post has no perceptual hash.".to_string(),
+                })),
+                Ok(None) => Ok(HttpResponse::NotFound().json(ErrorResponse {
+                    error: "Item not found".to_string(),
+                })),
+                Err(e) => {
+                    eprintln!("Query Error : {}", e);
+                    Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                        error: "Query failed".to_string(),
+                    }))
+                }
+            };
+        }
+    };
+    let similar = similarity
+        .find(hash, max_distance)
+        .into_iter()
+        .filter(|(id, _)| *id != post_id)
+        .take(clamp_limit(query.limit) as usize)
+        .map(|(post_id, distance)| SimilarPost { post_id, distance })
+        .collect();
+    Ok(HttpResponse::Ok().json(SimilarResponse { post_id, similar }))
+}
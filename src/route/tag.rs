@@ -0,0 +1,215 @@
+use crate::{
+    auth::AuthUser,
+    pagination::clamp_limit,
+    scope::Scope,
+    tag::normalize_tag,
+    types::{ErrorResponse, PostTagsResponse, TagCount, TagListResponse, TagQuery, TagRequest},
+    utility::{check_post_ownership, generate_response, get_psql_pool},
+};
+use actix_web::{HttpResponse, Responder, web};
+use deadpool_postgres::Pool;
+use std::io;
+use uuid::Uuid;
+
+pub const MAX_TAGS_PER_REQUEST: usize = 64;
+
+pub async fn fetch_post_tags(
+    psql_client: &deadpool_postgres::Client,
+    post_id: &Uuid,
+) -> Result<Vec<String>, tokio_postgres::Error> {
+    let rows = psql_client
+        .query(
+            "SELECT t.name FROM post_tag pt JOIN tag t ON t.tag_id = pt.tag_id WHERE pt.post_id = $1 ORDER BY t.name",
+            &[post_id],
+        )
+        .await?;
+    Ok(rows.iter().map(|row| row.get::<_, String>(0)).collect())
+}
+
+/// Attach tags to a post. Only the owner of the post may change its tags.
+pub async fn add_tags(
+    user: AuthUser,
+    psql_pool: web::Data<Pool>,
+    item_id: web::Path<String>,
+    data: web::Json<TagRequest>,
+) -> io::Result<impl Responder> {
+    let post_id = match Uuid::parse_str(&item_id.into_inner()) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid post ID format".to_string(),
+            }));
+        }
+    };
+    if data.tags.is_empty() || data.tags.len() > MAX_TAGS_PER_REQUEST {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+            error: format!("between 1 and {} tags are required.", MAX_TAGS_PER_REQUEST),
+        }));
+    }
+    let mut tags: Vec<String> = Vec::new();
+    for raw in &data.tags {
+        match normalize_tag(raw) {
+            Some(tag) => {
+                if !tags.contains(&tag) {
+                    tags.push(tag);
+                }
+            }
+            None => {
+                return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                    error: format!("invalid tag: {}", raw),
+                }));
+            }
+        }
+    }
+    if let Err(e) = user.require(Scope::TagsWrite) {
+        return Ok(generate_response(&e));
+    }
+    let postgres = match get_psql_pool(&psql_pool).await {
+        Ok(conn) => conn,
+        Err(_) => {
+            return Ok(HttpResponse::ExpectationFailed().json(ErrorResponse {
+                error: "Failed to get database connection".to_string(),
+            }));
+        }
+    };
+    if let Err(e) = check_post_ownership(&postgres, &post_id, &user.user_id).await {
+        return Ok(generate_response(&e));
+    }
+    let query = r#"
+        WITH upserted AS (
+            INSERT INTO tag (name) SELECT unnest($1::text[])
+            ON CONFLICT (name) DO UPDATE SET name = EXCLUDED.name
+            RETURNING tag_id
+        )
+        INSERT INTO post_tag (post_id, tag_id) SELECT $2, tag_id FROM upserted
+        ON CONFLICT DO NOTHING
+    "#;
+    if let Err(e) = postgres.execute(query, &[&tags, &post_id]).await {
+        eprintln!("Tag insert failed: {}", e);
+        return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+            error: "Failed to store tags.".to_string(),
+        }));
+    }
+    Ok(post_tags_response(&postgres, &post_id).await)
+}
+
+pub async fn remove_tag(
+    user: AuthUser,
+    psql_pool: web::Data<Pool>,
+    path: web::Path<(String, String)>,
+) -> io::Result<impl Responder> {
+    let (item_id, raw_tag) = path.into_inner();
+    let post_id = match Uuid::parse_str(&item_id) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid post ID format".to_string(),
+            }));
+        }
+    };
+    let tag = match normalize_tag(&raw_tag) {
+        Some(tag) => tag,
+        None => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: format!("invalid tag: {}", raw_tag),
+            }));
+        }
+    };
+    if let Err(e) = user.require(Scope::TagsWrite) {
+        return Ok(generate_response(&e));
+    }
+    let postgres = match get_psql_pool(&psql_pool).await {
+        Ok(conn) => conn,
+        Err(_) => {
+            return Ok(HttpResponse::ExpectationFailed().json(ErrorResponse {
+                error: "Failed to get database connection".to_string(),
+            }));
+        }
+    };
+    if let Err(e) = check_post_ownership(&postgres, &post_id, &user.user_id).await {
+        return Ok(generate_response(&e));
+    }
+    let query = r#"
+        DELETE FROM post_tag pt USING tag t
+        WHERE pt.tag_id = t.tag_id AND pt.post_id = $1 AND t.name = $2
+    "#;
+    match postgres.execute(query, &[&post_id, &tag]).await {
+        Ok(0) => Ok(HttpResponse::NotFound().json(ErrorResponse {
+            error: "tag is not attached to this item.".to_string(),
+        })),
+        Ok(_) => Ok(post_tags_response(&postgres, &post_id).await),
+        Err(e) => {
+            eprintln!("Tag delete failed: {}", e);
+            Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Failed to remove tag.".to_string(),
+            }))
+        }
+    }
+}
+
+/// List tags with the number of posts using them, most used first
+pub async fn get_tags(
+    psql_pool: web::Data<Pool>,
+    query: web::Query<TagQuery>,
+) -> io::Result<impl Responder> {
+    let postgres = match get_psql_pool(&psql_pool).await {
+        Ok(conn) => conn,
+        Err(_) => {
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Failed to get database connection".to_string(),
+            }));
+        }
+    };
+    let prefix = format!(
+        "{}%",
+        query
+            .prefix
+            .as_deref()
+            .unwrap_or("")
+            .to_lowercase()
+            .replace(['%', '_', '\\'], "")
+    );
+    let sql = r#"
+        SELECT t.name, COUNT(pt.post_id) AS usage FROM tag t
+        LEFT JOIN post_tag pt ON pt.tag_id = t.tag_id
+        WHERE t.name LIKE $1
+        GROUP BY t.name
+        ORDER BY usage DESC, t.name
+        LIMIT $2
+    "#;
+    match postgres
+        .query(sql, &[&prefix, &clamp_limit(query.limit)])
+        .await
+    {
+        Ok(rows) => Ok(HttpResponse::Ok().json(TagListResponse {
+            tags: rows
+                .iter()
+                .map(|row| TagCount {
+                    name: row.get(0),
+                    count: row.get(1),
+                })
+                .collect(),
+        })),
+        Err(e) => {
+            eprintln!("Tag query failed: {}", e);
+            Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Query failed".to_string(),
+            }))
+        }
+    }
+}
+
+async fn post_tags_response(psql_client: &deadpool_postgres::Client, post_id: &Uuid) -> HttpResponse {
+    match fetch_post_tags(psql_client, post_id).await {
+        Ok(tags) => HttpResponse::Ok().json(PostTagsResponse {
+            post_id: post_id.to_string(),
+            tags,
+        }),
+        Err(e) => {
+            eprintln!("Tag query failed: {}", e);
+            HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Query failed".to_string(),
+            })
+        }
+    }
+}
@@ -0,0 +1,802 @@
+//! Resumable uploads, tus 1.0.0 with the creation, termination and expiration extensions.
+//!
+//! `POST /files` creates an upload, `PATCH /files/{id}` appends at `Upload-Offset`,
+//! `HEAD /files/{id}` reports the offset and `DELETE /files/{id}` terminates it.
+//! Bytes are kept under `files.destination/tus` until the upload completes, then the file
+//! goes through the same checks and post creation as `POST /upload`.
+//! When those checks reject the file, or its content is not an allowed media type, the
+//! bytes are removed but the upload is kept as failed until it expires: `HEAD` and
+//! `PATCH` answer `410 Gone` with the reason instead of `404`.
+//! `Upload-Metadata` keys: `filename` (required), `filetype`, `title`, `creator`,
+//! `source`, `description` and `strip_metadata`.
+use crate::{
+    auth::AuthUser,
+    imaging::phash::SimilarityIndex,
+    ingest::{Ingest, IngestError, SpooledFile},
+    route::upload::ingest_failed,
+    scope::Scope,
+    settings::{FileSettings, Settings},
+    sniff::{SNIFF_LENGTH, sniff_file},
+    storage::BlobStore,
+    types::{ErrorResponse, UploadJson},
+    utility::{generate_response, get_psql_pool},
+};
+use actix_web::{
+    HttpRequest, HttpResponse, HttpResponseBuilder,
+    http::{StatusCode, header},
+    web,
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chrono::{DateTime, Duration, Utc};
+use deadpool_postgres::Pool;
+use futures_util::StreamExt;
+use mongodb::Client;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio_postgres::types::ToSql;
+use uuid::Uuid;
+
+pub const TUS_VERSION: &str = "1.0.0";
+pub const TUS_EXTENSIONS: &str = "creation,termination,expiration";
+/// How long an upload may stay unfinished
+pub const UPLOAD_EXPIRATION_HOURS: i64 = 24;
+pub const SWEEP_INTERVAL_SECS: u64 = 600;
+const MAX_METADATA_LENGTH: usize = 4096;
+/// How long a `PATCH` keeps its claim on an upload without renewing it
+pub const UPLOAD_LEASE_SECS: f64 = 60.0;
+/// Streaming renews the claim once it is this old
+const LEASE_RENEW_SECS: u64 = 20;
+/// Claim held while a completed upload becomes a post
+pub const INGEST_LEASE_SECS: f64 = 900.0;
+
+fn upload_path(destination: &Path, upload_id: &Uuid) -> PathBuf {
+    destination.join("tus").join(upload_id.to_string())
+}
+
+fn http_date(time: &DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn tus(status: StatusCode) -> HttpResponseBuilder {
+    let mut builder = HttpResponse::build(status);
+    builder.insert_header(("Tus-Resumable", TUS_VERSION));
+    builder
+}
+
+fn tus_error(status: StatusCode, error: &str) -> HttpResponse {
+    tus(status).json(ErrorResponse {
+        error: error.to_string(),
+    })
+}
+
+fn header_str<'a>(request: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    request.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Every request but `OPTIONS` has to speak our version
+fn check_version(request: &HttpRequest) -> Result<(), HttpResponse> {
+    match header_str(request, "Tus-Resumable") {
+        Some(TUS_VERSION) => Ok(()),
+        _ => Err(tus(StatusCode::PRECONDITION_FAILED)
+            .insert_header(("Tus-Version", TUS_VERSION))
+            .json(ErrorResponse {
+                error: format!("Tus-Resumable must be {}.", TUS_VERSION),
+            })),
+    }
+}
+
+fn authenticate(request: &HttpRequest, user: &AuthUser, required: Scope) -> Result<Uuid, HttpResponse> {
+    check_version(request)?;
+    user.require(required).map_err(|e| generate_response(&e))?;
+    Ok(user.user_id)
+}
+
+/// `key base64,key base64`, a key may have no value
+fn parse_metadata(raw: &str) -> Option<HashMap<String, String>> {
+    let mut pairs = HashMap::new();
+    for pair in raw.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (key, value) = match pair.split_once(' ') {
+            Some((key, value)) => (key, STANDARD.decode(value.trim()).ok()?),
+            None => (pair, Vec::new()),
+        };
+        pairs.insert(key.to_string(), String::from_utf8(value).ok()?);
+    }
+    Some(pairs)
+}
+
+fn parse_upload_id(raw: &str) -> Result<Uuid, HttpResponse> {
+    Uuid::parse_str(raw).map_err(|_| tus_error(StatusCode::NOT_FOUND, "Upload not found"))
+}
+
+fn check_upload_length(files: &FileSettings, length: u64) -> Result<(), HttpResponse> {
+    match length > files.max_upload_file_size {
+        true => Err(tus_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &format!("uploads are limited to {} bytes.", files.max_upload_file_size),
+        )),
+        false => Ok(()),
+    }
+}
+
+pub async fn options(settings: web::Data<Settings>) -> HttpResponse {
+    tus(StatusCode::NO_CONTENT)
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Max-Size", settings.files.max_upload_file_size.to_string()))
+        .finish()
+}
+
+pub async fn create(
+    request: HttpRequest,
+    user: AuthUser,
+    psql_pool: web::Data<Pool>,
+    settings: web::Data<Settings>,
+) -> io::Result<HttpResponse> {
+    let user_id = match authenticate(&request, &user, Scope::PostsWrite) {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    if request.headers().contains_key("Upload-Defer-Length") {
+        return Ok(tus_error(
+            StatusCode::BAD_REQUEST,
+            "Upload-Defer-Length is not supported.",
+        ));
+    }
+    let length = match header_str(&request, "Upload-Length").map(str::parse::<u64>) {
+        Some(Ok(length)) => length,
+        _ => return Ok(tus_error(StatusCode::BAD_REQUEST, "Upload-Length is required.")),
+    };
+    if let Err(response) = check_upload_length(&settings.files, length) {
+        return Ok(response);
+    }
+    let raw_metadata = header_str(&request, "Upload-Metadata").unwrap_or_default();
+    let metadata = match raw_metadata.len() <= MAX_METADATA_LENGTH {
+        true => parse_metadata(raw_metadata),
+        false => None,
+    };
+    let metadata = match metadata {
+        Some(metadata) => metadata,
+        None => return Ok(tus_error(StatusCode::BAD_REQUEST, "Invalid Upload-Metadata.")),
+    };
+    let filename = match metadata.get("filename").filter(|f| !f.is_empty()) {
+        Some(filename) => filename.clone(),
+        None => return Ok(tus_error(StatusCode::BAD_REQUEST, "filename was not found.")),
+    };
+    let strip_metadata = match metadata.get("strip_metadata").map(String::as_str) {
+        None | Some("true") => true,
+        Some("false") => false,
+        Some(_) => {
+            return Ok(tus_error(
+                StatusCode::BAD_REQUEST,
+                "strip_metadata must be true or false.",
+            ));
+        }
+    };
+    let field = |key: &str| metadata.get(key).cloned().unwrap_or_default();
+
+    let upload_id = Uuid::new_v4();
+    let expires_at = Utc::now() + Duration::hours(UPLOAD_EXPIRATION_HOURS);
+    let path = upload_path(&settings.files.destination, &upload_id);
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::File::create(&path).await?;
+    let client = match get_psql_pool(&psql_pool).await {
+        Ok(conn) => conn,
+        Err(_) => {
+            let _ = tokio::fs::remove_file(&path).await;
+            return Ok(tus_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to get database connection",
+            ));
+        }
+    };
+    if let Err(e) = client
+        .execute(
+            "INSERT INTO tus_upload (upload_id, user_id, upload_length, filename, filetype, title, creator, source, description, strip_metadata, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+            &[
+                &upload_id,
+                &user_id,
+                &(length as i64),
+                &filename,
+                &metadata.get("filetype"),
+                &field("title"),
+                &field("creator"),
+                &field("source"),
+                &field("description"),
+                &strip_metadata,
+                &expires_at,
+            ],
+        )
+        .await
+    {
+        eprintln!("PostgreSQL Insert Error: {}", e);
+        let _ = tokio::fs::remove_file(&path).await;
+        return Ok(tus_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to create upload.",
+        ));
+    }
+    Ok(tus(StatusCode::CREATED)
+        .insert_header((header::LOCATION, format!("/files/{}", upload_id)))
+        .insert_header(("Upload-Expires", http_date(&expires_at)))
+        .finish())
+}
+
+pub async fn status(
+    request: HttpRequest,
+    user: AuthUser,
+    psql_pool: web::Data<Pool>,
+    upload_id: web::Path<String>,
+) -> io::Result<HttpResponse> {
+    let user_id = match authenticate(&request, &user, Scope::PostsRead) {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    let upload_id = match parse_upload_id(&upload_id) {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    let client = match get_psql_pool(&psql_pool).await {
+        Ok(conn) => conn,
+        Err(_) => {
+            return Ok(tus_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to get database connection",
+            ));
+        }
+    };
+    let row = match client
+        .query_opt(
+            "SELECT upload_offset, upload_length, expires_at, post_id, failed_reason FROM tus_upload WHERE upload_id = $1 AND user_id = $2",
+            &[&upload_id, &user_id],
+        )
+        .await
+    {
+        Ok(Some(row)) => row,
+        Ok(None) => return Ok(tus_error(StatusCode::NOT_FOUND, "Upload not found")),
+        Err(e) => {
+            eprintln!("Query Error : {}", e);
+            return Ok(tus_error(StatusCode::INTERNAL_SERVER_ERROR, "Query failed"));
+        }
+    };
+    let expires_at: DateTime<Utc> = row.get(2);
+    if expires_at < Utc::now() {
+        return Ok(tus_error(StatusCode::GONE, "Upload expired"));
+    }
+    if let Some(reason) = row.get::<_, Option<String>>(4) {
+        return Ok(upload_failed(&reason));
+    }
+    let mut response = tus(StatusCode::OK);
+    response
+        .insert_header(("Upload-Offset", row.get::<_, i64>(0).to_string()))
+        .insert_header(("Upload-Length", row.get::<_, i64>(1).to_string()))
+        .insert_header(("Upload-Expires", http_date(&expires_at)))
+        .insert_header((header::CACHE_CONTROL, "no-store"));
+    if let Some(post_id) = row.get::<_, Option<Uuid>>(3) {
+        response.insert_header(("Upload-Post-Id", post_id.to_string()));
+    }
+    Ok(response.finish())
+}
+
+/// Claim an upload for one `PATCH`. The claim is committed right away, so no row lock
+/// or pooled connection is held while the body streams. It lapses after
+/// [`UPLOAD_LEASE_SECS`] unless renewed, e.g. when the server dies mid-request.
+async fn claim(
+    psql_pool: &Pool,
+    upload_id: &Uuid,
+    user_id: &Uuid,
+    lock_id: &Uuid,
+) -> Result<tokio_postgres::Row, HttpResponse> {
+    let client = match get_psql_pool(psql_pool).await {
+        Ok(conn) => conn,
+        Err(_) => {
+            return Err(tus_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to get database connection",
+            ));
+        }
+    };
+    let claimed = client
+        .query_opt(
+            "UPDATE tus_upload SET lock_id = $3, locked_until = NOW() + make_interval(secs => $4)
+            WHERE upload_id = $1 AND user_id = $2 AND (locked_until IS NULL OR locked_until < NOW())
+            RETURNING upload_offset, upload_length, expires_at, post_id, filename, filetype, title, creator, source, description, strip_metadata, failed_reason",
+            &[upload_id, user_id, lock_id, &UPLOAD_LEASE_SECS],
+        )
+        .await;
+    let exists = match claimed {
+        Ok(Some(row)) => return Ok(row),
+        Ok(None) => {
+            client
+                .query_opt(
+                    "SELECT 1 FROM tus_upload WHERE upload_id = $1 AND user_id = $2",
+                    &[upload_id, user_id],
+                )
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    match exists {
+        Ok(Some(_)) => Err(tus_error(
+            StatusCode::LOCKED,
+            "Another request is writing to this upload.",
+        )),
+        Ok(None) => Err(tus_error(StatusCode::NOT_FOUND, "Upload not found")),
+        Err(e) => {
+            eprintln!("Query Error : {}", e);
+            Err(tus_error(StatusCode::INTERNAL_SERVER_ERROR, "Query failed"))
+        }
+    }
+}
+
+/// Run `statement` on an upload, its first two parameters are `upload_id` and `lock_id`.
+/// `Ok(false)` when the claim was lost to expiry, termination or another request.
+async fn while_claimed(
+    psql_pool: &Pool,
+    statement: &str,
+    params: &[&(dyn ToSql + Sync)],
+) -> Result<bool, HttpResponse> {
+    let client = match get_psql_pool(psql_pool).await {
+        Ok(conn) => conn,
+        Err(_) => {
+            return Err(tus_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to get database connection",
+            ));
+        }
+    };
+    match client.execute(statement, params).await {
+        Ok(updated) => Ok(updated > 0),
+        Err(e) => {
+            eprintln!("PostgreSQL Update Error: {}", e);
+            Err(tus_error(StatusCode::INTERNAL_SERVER_ERROR, "Query failed"))
+        }
+    }
+}
+
+async fn release(psql_pool: &Pool, upload_id: &Uuid, lock_id: &Uuid) {
+    let _ = while_claimed(
+        psql_pool,
+        "UPDATE tus_upload SET lock_id = NULL, locked_until = NULL
+        WHERE upload_id = $1 AND lock_id = $2",
+        &[upload_id, lock_id],
+    )
+    .await;
+}
+
+fn upload_failed(reason: &str) -> HttpResponse {
+    tus_error(StatusCode::GONE, &format!("Upload failed: {}", reason))
+}
+
+/// Keep a rejected upload as failed and drop its bytes, see the module docs
+async fn fail_upload(psql_pool: &Pool, path: &Path, upload_id: &Uuid, lock_id: &Uuid, reason: &str) {
+    let failed = while_claimed(
+        psql_pool,
+        "UPDATE tus_upload SET failed_reason = $3, lock_id = NULL, locked_until = NULL
+        WHERE upload_id = $1 AND lock_id = $2",
+        &[upload_id, lock_id, &reason],
+    )
+    .await;
+    if let Ok(true) = failed
+        && let Err(e) = tokio::fs::remove_file(path).await
+        && e.kind() != io::ErrorKind::NotFound
+    {
+        eprintln!("Failed to remove upload {}: {}", upload_id, e);
+    }
+}
+
+fn claim_lost() -> HttpResponse {
+    tus_error(
+        StatusCode::CONFLICT,
+        "The upload was changed by another request, check the offset with HEAD.",
+    )
+}
+
+enum Streamed {
+    /// Bytes up to `offset` are on disk, `interrupted` when the client went away
+    Written { offset: u64, interrupted: bool },
+    TooLarge,
+    ClaimLost,
+}
+
+/// Write the body from `offset`, renewing the claim every [`LEASE_RENEW_SECS`]
+async fn stream_body(
+    body: &mut web::Payload,
+    path: &Path,
+    offset: u64,
+    length: u64,
+    psql_pool: &Pool,
+    upload_id: &Uuid,
+    lock_id: &Uuid,
+) -> io::Result<Streamed> {
+    //bytes past the recorded offset are from an interrupted request, drop them
+    let mut file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+    file.set_len(offset).await?;
+    file.seek(io::SeekFrom::Start(offset)).await?;
+    let mut new_offset = offset;
+    let mut interrupted = false;
+    let mut renewed_at = std::time::Instant::now();
+    while let Some(chunk) = body.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                eprintln!("Upload {} interrupted: {}", upload_id, e);
+                interrupted = true;
+                break;
+            }
+        };
+        if new_offset + chunk.len() as u64 > length {
+            return Ok(Streamed::TooLarge);
+        }
+        if renewed_at.elapsed().as_secs() >= LEASE_RENEW_SECS {
+            let renewed = while_claimed(
+                psql_pool,
+                "UPDATE tus_upload SET locked_until = NOW() + make_interval(secs => $3)
+                WHERE upload_id = $1 AND lock_id = $2",
+                &[upload_id, lock_id, &UPLOAD_LEASE_SECS],
+            )
+            .await;
+            if !matches!(renewed, Ok(true)) {
+                return Ok(Streamed::ClaimLost);
+            }
+            renewed_at = std::time::Instant::now();
+        }
+        file.write_all(&chunk).await?;
+        new_offset += chunk.len() as u64;
+    }
+    file.flush().await?;
+    file.sync_data().await?;
+    Ok(Streamed::Written {
+        offset: new_offset,
+        interrupted,
+    })
+}
+
+/// Append the body at `Upload-Offset`. The upload is claimed for the request, a
+/// concurrent `PATCH` to the same upload gets `423 Locked`. Successful responses
+/// carry the new `Upload-Offset` and `Upload-Expires`.
+#[allow(clippy::too_many_arguments)]
+pub async fn append(
+    request: HttpRequest,
+    user: AuthUser,
+    psql_pool: web::Data<Pool>,
+    mongo_pool: web::Data<Client>,
+    similarity: web::Data<SimilarityIndex>,
+    store: web::Data<dyn BlobStore>,
+    settings: web::Data<Settings>,
+    upload_id: web::Path<String>,
+    mut body: web::Payload,
+) -> io::Result<HttpResponse> {
+    let user_id = match authenticate(&request, &user, Scope::PostsWrite) {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    let upload_id = match parse_upload_id(&upload_id) {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    if header_str(&request, "Content-Type") != Some("application/offset+octet-stream") {
+        return Ok(tus_error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Content-Type must be application/offset+octet-stream.",
+        ));
+    }
+    let offset = match header_str(&request, "Upload-Offset").map(str::parse::<u64>) {
+        Some(Ok(offset)) => offset,
+        _ => return Ok(tus_error(StatusCode::BAD_REQUEST, "Upload-Offset is required.")),
+    };
+    let lock_id = Uuid::new_v4();
+    let row = match claim(&psql_pool, &upload_id, &user_id, &lock_id).await {
+        Ok(row) => row,
+        Err(response) => return Ok(response),
+    };
+    let stored_offset = row.get::<_, i64>(0) as u64;
+    let length = row.get::<_, i64>(1) as u64;
+    let expires_at: DateTime<Utc> = row.get(2);
+    if expires_at < Utc::now() {
+        release(&psql_pool, &upload_id, &lock_id).await;
+        return Ok(tus_error(StatusCode::GONE, "Upload expired"));
+    }
+    if let Some(reason) = row.get::<_, Option<String>>(11) {
+        release(&psql_pool, &upload_id, &lock_id).await;
+        return Ok(upload_failed(&reason));
+    }
+    if let Some(post_id) = row.get::<_, Option<Uuid>>(3) {
+        release(&psql_pool, &upload_id, &lock_id).await;
+        return Ok(tus(StatusCode::NO_CONTENT)
+            .insert_header(("Upload-Offset", length.to_string()))
+            .insert_header(("Upload-Expires", http_date(&expires_at)))
+            .insert_header(("Upload-Post-Id", post_id.to_string()))
+            .finish());
+    }
+    if offset != stored_offset {
+        release(&psql_pool, &upload_id, &lock_id).await;
+        return Ok(tus(StatusCode::CONFLICT)
+            .insert_header(("Upload-Offset", stored_offset.to_string()))
+            .json(ErrorResponse {
+                error: "Upload-Offset does not match the current offset.".to_string(),
+            }));
+    }
+
+    let path = upload_path(&settings.files.destination, &upload_id);
+    let streamed = stream_body(
+        &mut body,
+        &path,
+        stored_offset,
+        length,
+        &psql_pool,
+        &upload_id,
+        &lock_id,
+    )
+    .await;
+    let (new_offset, interrupted) = match streamed {
+        Ok(Streamed::Written {
+            offset,
+            interrupted,
+        }) => (offset, interrupted),
+        Ok(Streamed::TooLarge) => {
+            release(&psql_pool, &upload_id, &lock_id).await;
+            return Ok(tus_error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "body goes past Upload-Length.",
+            ));
+        }
+        Ok(Streamed::ClaimLost) => return Ok(claim_lost()),
+        Err(e) => {
+            release(&psql_pool, &upload_id, &lock_id).await;
+            return Err(e);
+        }
+    };
+
+    //reject content we would never store as soon as it can be recognised
+    if stored_offset < SNIFF_LENGTH as u64 && (new_offset >= SNIFF_LENGTH as u64 || new_offset == length) {
+        let sniff_path = path.clone();
+        let sniffed = tokio::task::spawn_blocking(move || sniff_file(&sniff_path)).await;
+        if !matches!(sniffed, Ok(Ok(Some(_)))) {
+            let filename: String = row.get(4);
+            let error = IngestError::Unsupported(filename);
+            fail_upload(&psql_pool, &path, &upload_id, &lock_id, &error.to_string()).await;
+            return Ok(tus_error(StatusCode::UNSUPPORTED_MEDIA_TYPE, &error.to_string()));
+        }
+    }
+    if new_offset < length || interrupted {
+        let recorded = while_claimed(
+            &psql_pool,
+            "UPDATE tus_upload SET upload_offset = $3, lock_id = NULL, locked_until = NULL
+            WHERE upload_id = $1 AND lock_id = $2",
+            &[&upload_id, &lock_id, &(new_offset as i64)],
+        )
+        .await;
+        return Ok(match recorded {
+            Ok(true) => tus(StatusCode::NO_CONTENT)
+                .insert_header(("Upload-Offset", new_offset.to_string()))
+                .insert_header(("Upload-Expires", http_date(&expires_at)))
+                .finish(),
+            Ok(false) => claim_lost(),
+            Err(response) => response,
+        });
+    }
+
+    //complete, keep the upload claimed for as long as creating the post may take
+    match while_claimed(
+        &psql_pool,
+        "UPDATE tus_upload SET upload_offset = $3, locked_until = NOW() + make_interval(secs => $4)
+        WHERE upload_id = $1 AND lock_id = $2",
+        &[&upload_id, &lock_id, &(new_offset as i64), &INGEST_LEASE_SECS],
+    )
+    .await
+    {
+        Ok(true) => {}
+        Ok(false) => return Ok(claim_lost()),
+        Err(response) => return Ok(response),
+    }
+    let ingest = Ingest {
+        psql_pool: psql_pool.get_ref().clone(),
+        mongo_pool: mongo_pool.get_ref().clone(),
+        store: store.into_inner(),
+        similarity: similarity.into_inner(),
+        destination: settings.files.destination.clone(),
+    };
+    let filename: String = row.get(4);
+    let filetype: Option<String> = row.get(5);
+    let metadata = UploadJson {
+        title: row.get(6),
+        creator: row.get(7),
+        source: row.get(8),
+        description: row.get(9),
+    };
+    let strip_metadata: bool = row.get(10);
+    let spooled = match SpooledFile::adopt(path.clone()).await {
+        Ok(spooled) => spooled,
+        Err(e) => {
+            release(&psql_pool, &upload_id, &lock_id).await;
+            return Ok(ingest_failed(&e));
+        }
+    };
+    let mut postgres = match get_psql_pool(&psql_pool).await {
+        Ok(conn) => conn,
+        Err(_) => {
+            release(&psql_pool, &upload_id, &lock_id).await;
+            return Ok(tus_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to get database connection",
+            ));
+        }
+    };
+    let created = match ingest
+        .prepare(spooled, &filename, filetype.as_deref(), strip_metadata)
+        .await
+    {
+        Ok(prepared) => ingest
+            .create_posts(&mut postgres, &user_id, vec![(prepared, metadata)])
+            .await
+            .map(|mut created| created.remove(0)),
+        Err(e) => Err(e),
+    };
+    drop(postgres);
+    let created = match created {
+        Ok(created) => created,
+        Err(e) => {
+            //the bytes are gone with the failed attempt
+            fail_upload(&psql_pool, &path, &upload_id, &lock_id, &e.to_string()).await;
+            let mut response = ingest_failed(&e);
+            response.headers_mut().insert(
+                header::HeaderName::from_static("tus-resumable"),
+                header::HeaderValue::from_static(TUS_VERSION),
+            );
+            return Ok(response);
+        }
+    };
+    let _ = while_claimed(
+        &psql_pool,
+        "UPDATE tus_upload SET post_id = $3, lock_id = NULL, locked_until = NULL
+        WHERE upload_id = $1 AND lock_id = $2",
+        &[&upload_id, &lock_id, &created.post_id],
+    )
+    .await;
+    println!("Upload {} completed as {}", upload_id, created.filename);
+    Ok(tus(StatusCode::NO_CONTENT)
+        .insert_header(("Upload-Offset", new_offset.to_string()))
+        .insert_header(("Upload-Expires", http_date(&expires_at)))
+        .insert_header(("Upload-Post-Id", created.post_id.to_string()))
+        .finish())
+}
+
+/// Delete an upload and its bytes, `423 Locked` while a `PATCH` holds its claim
+pub async fn terminate(
+    request: HttpRequest,
+    user: AuthUser,
+    psql_pool: web::Data<Pool>,
+    settings: web::Data<Settings>,
+    upload_id: web::Path<String>,
+) -> io::Result<HttpResponse> {
+    let user_id = match authenticate(&request, &user, Scope::PostsWrite) {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    let upload_id = match parse_upload_id(&upload_id) {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    let client = match get_psql_pool(&psql_pool).await {
+        Ok(conn) => conn,
+        Err(_) => {
+            return Ok(tus_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to get database connection",
+            ));
+        }
+    };
+    //an upload claimed by a PATCH is left to it, its bytes are still being written or ingested
+    let deleted = client
+        .execute(
+            "DELETE FROM tus_upload WHERE upload_id = $1 AND user_id = $2
+            AND (locked_until IS NULL OR locked_until < NOW())",
+            &[&upload_id, &user_id],
+        )
+        .await;
+    let query_failed = |e: tokio_postgres::Error| {
+        eprintln!("PostgreSQL Delete Error: {}", e);
+        tus_error(StatusCode::INTERNAL_SERVER_ERROR, "Query failed")
+    };
+    match deleted {
+        Ok(0) => {
+            let exists = client
+                .query_opt(
+                    "SELECT 1 FROM tus_upload WHERE upload_id = $1 AND user_id = $2",
+                    &[&upload_id, &user_id],
+                )
+                .await;
+            return Ok(match exists {
+                Ok(Some(_)) => tus_error(StatusCode::LOCKED, "Another request is writing to this upload."),
+                Ok(None) => tus_error(StatusCode::NOT_FOUND, "Upload not found"),
+                Err(e) => query_failed(e),
+            });
+        }
+        Ok(_) => {}
+        Err(e) => return Ok(query_failed(e)),
+    }
+    match tokio::fs::remove_file(upload_path(&settings.files.destination, &upload_id)).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            eprintln!("Failed to remove upload {}: {}", upload_id, e);
+        }
+        _ => {}
+    }
+    Ok(tus(StatusCode::NO_CONTENT).finish())
+}
+
+/// Remove expired uploads and their bytes every `SWEEP_INTERVAL_SECS`. Uploads still
+/// claimed by a `PATCH` are left for a later sweep.
+pub fn spawn_expiry_sweeper(psql_pool: Pool, destination: PathBuf) {
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(std::time::Duration::from_secs(SWEEP_INTERVAL_SECS));
+        loop {
+            interval.tick().await;
+            let client = match psql_pool.get().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    eprintln!("Failed to get connection from pool: {}", e);
+                    continue;
+                }
+            };
+            let rows = match client
+                .query(
+                    "DELETE FROM tus_upload WHERE expires_at < NOW()
+                    AND (locked_until IS NULL OR locked_until < NOW()) RETURNING upload_id",
+                    &[],
+                )
+                .await
+            {
+                Ok(rows) => rows,
+                Err(e) => {
+                    eprintln!("Failed to sweep expired uploads: {}", e);
+                    continue;
+                }
+            };
+            for row in &rows {
+                let upload_id: Uuid = row.get(0);
+                match tokio::fs::remove_file(upload_path(&destination, &upload_id)).await {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        eprintln!("Failed to remove upload {}: {}", upload_id, e);
+                    }
+                    _ => {}
+                }
+            }
+            if !rows.is_empty() {
+                println!("Removed {} expired uploads", rows.len());
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_settings_accept_large_videos() {
+        let files = Settings::default().files;
+        assert!(check_upload_length(&files, 200 * 1024 * 1024).is_ok());
+        assert!(check_upload_length(&files, files.max_upload_file_size).is_ok());
+    }
+
+    #[test]
+    fn uploads_over_the_file_limit_are_rejected() {
+        let files = Settings::default().files;
+        let rejected = check_upload_length(&files, files.max_upload_file_size + 1).unwrap_err();
+        assert_eq!(rejected.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn failed_uploads_answer_gone() {
+        let reason = IngestError::Unsupported("notes.txt".to_string()).to_string();
+        let response = upload_failed(&reason);
+        assert_eq!(response.status(), StatusCode::GONE);
+        assert_eq!(response.headers().get("Tus-Resumable").unwrap(), TUS_VERSION);
+    }
+}
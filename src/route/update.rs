@@ -1,10 +1,135 @@
-use std::io;
-
+use crate::{
+    auth::AuthUser,
+    db_pool::mongo_database,
+    imaging::analysis,
+    route::tag::fetch_post_tags,
+    scope::Scope,
+    types::{ErrorResponse, ItemResponse, UpdateJson, UploadJson},
+    utility::{check_post_ownership, generate_response, get_psql_pool, uuid_to_binary},
+};
 use actix_web::{HttpResponse, Responder, web};
 use deadpool_postgres::Pool;
-use mongodb::Client;
+use mongodb::{
+    Client,
+    bson::{Document, doc},
+    options::ReturnDocument,
+};
+use std::io;
+use uuid::Uuid;
+
+pub async fn update(
+    user: AuthUser,
+    psql_pool: web::Data<Pool>,
+    mongo_pool: web::Data<Client>,
+    item_id: web::Path<String>,
+    data: web::Json<UpdateJson>,
+) -> io::Result<impl Responder> {
+    let post_id = match Uuid::parse_str(&item_id.into_inner()) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid post ID format".to_string(),
+            }));
+        }
+    };
+    let mut changes = Document::new();
+    for (key, value) in [
+        ("title", &data.title),
+        ("creator", &data.creator),
+        ("source", &data.source),
+        ("description", &data.description),
+    ] {
+        if let Some(v) = value {
+            changes.insert(key, v.clone());
+        }
+    }
+    if changes.is_empty() {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+            error: "no metadata fields to update.".to_string(),
+        }));
+    }
+    if let Err(e) = user.require(Scope::PostsWrite) {
+        return Ok(generate_response(&e));
+    }
+    let user_id = user.user_id;
+    let postgres = match get_psql_pool(&psql_pool).await {
+        Ok(conn) => conn,
+        Err(_) => {
+            return Ok(HttpResponse::ExpectationFailed().json(ErrorResponse {
+                error: "Failed to get database connection".to_string(),
+            }));
+        }
+    };
+    let filename = match check_post_ownership(&postgres, &post_id, &user_id).await {
+        Ok(name) => name,
+        Err(e) => return Ok(generate_response(&e)),
+    };
+
+    let coll = mongo_database(&mongo_pool)
+        .collection::<Document>("post");
+    let updated = match coll
+        .find_one_and_update(
+            doc! {"post_id": uuid_to_binary(&post_id)},
+            doc! {"$set": changes},
+        )
+        .return_document(ReturnDocument::After)
+        .await
+    {
+        Ok(Some(doc)) => doc,
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(ErrorResponse {
+                error: "the content you are looking for is not found.".to_string(),
+            }));
+        }
+        Err(e) => {
+            eprintln!("MongoDB Update Error: {}", e);
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Failed to update post in MongoDB.".to_string(),
+            }));
+        }
+    };
+    if let Err(e) = postgres
+        .execute(
+            "UPDATE post SET updated_at = NOW() WHERE post_id = $1",
+            &[&post_id],
+        )
+        .await
+    {
+        eprintln!("PostgreSQL Update Error: {}", e);
+        return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+            error: "Failed to update post timestamp in database.".to_string(),
+        }));
+    }
+
+    let tags = match fetch_post_tags(&postgres, &post_id).await {
+        Ok(tags) => tags,
+        Err(e) => {
+            eprintln!("Tag query failed: {}", e);
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database query failed".to_string(),
+            }));
+        }
+    };
 
-pub async fn update(psql_pool:web::Data<Pool>,monogo_pool:web::Data<Client>)->io::Result<impl Responder>{
-    
-    Ok(HttpResponse::Ok().finish())
-}
\ No newline at end of file
+    let (analysis_status, analysis) = match analysis::fetch(&postgres, &post_id).await {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            eprintln!("Analysis query failed: {}", e);
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database query failed".to_string(),
+            }));
+        }
+    };
+    Ok(HttpResponse::Ok().json(ItemResponse {
+        image: filename,
+        metadata: UploadJson {
+            title: updated.get_str("title").unwrap_or("").to_string(),
+            creator: updated.get_str("creator").unwrap_or("").to_string(),
+            source: updated.get_str("source").unwrap_or("").to_string(),
+            description: updated.get_str("description").unwrap_or("").to_string(),
+        },
+        tags,
+        analysis,
+        analysis_status,
+    }))
+}
@@ -1,52 +1,215 @@
 use crate::{
-    DESTINATION, MONGODB_DBANAME, types::{ErrorResponse, Post, ResponseFile, UploadFrom}, utility::{
-        CredentialType, check_user_validity_with_pool, generate_response, get_psql_pool,
-    }
-};
-use actix_multipart::form::MultipartForm;
-use actix_web::{
-    HttpRequest, HttpResponse, Responder,
-    http::header::{AUTHORIZATION, ContentType},
-    web,
+    auth::AuthUser,
+    imaging::phash::SimilarityIndex,
+    ingest::{Ingest, IngestError, Spool, SpooledFile},
+    scope::Scope,
+    settings::Settings,
+    storage::BlobStore,
+    types::{ErrorResponse, UploadJson, UploadResponse},
+    utility::{generate_response, get_psql_pool},
 };
+use actix_multipart::{Field, Multipart};
+use actix_web::{HttpResponse, Responder, http::header::ContentType, web};
 use deadpool_postgres::Pool;
-use mongodb::{Client, bson};
+use futures_util::StreamExt;
+use mongodb::Client;
 use std::io;
-use uuid::Uuid;
 
+/// A `file` part, spooled to disk or staged in the blob store while it streamed in
+struct ReceivedFile {
+    spooled: SpooledFile,
+    filename: String,
+    claimed: Option<String>,
+}
+
+pub fn ingest_failed(e: &IngestError) -> HttpResponse {
+    let body = ErrorResponse {
+        error: e.to_string(),
+    };
+    match e {
+        IngestError::TooLarge(_) => HttpResponse::PayloadTooLarge().json(body),
+        IngestError::Unsupported(_) | IngestError::Mismatch { .. } => {
+            HttpResponse::UnsupportedMediaType().json(body)
+        }
+        IngestError::Unreadable { .. } => HttpResponse::BadRequest().json(body),
+        IngestError::Internal(_) => HttpResponse::InternalServerError().json(body),
+    }
+}
+
+fn malformed() -> HttpResponse {
+    HttpResponse::BadRequest().json(ErrorResponse {
+        error: "Malformed multipart body.".to_string(),
+    })
+}
+
+/// Read a small text part, `None` when it is malformed or over `limit` bytes
+async fn read_text(field: &mut Field, limit: usize) -> Option<String> {
+    let mut buffer = Vec::new();
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk.ok()?;
+        if buffer.len() + chunk.len() > limit {
+            return None;
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+    String::from_utf8(buffer).ok()
+}
+
+/// Accepts `file` parts with one `metadata` JSON array entry each, and an optional
+/// `strip_metadata` flag. Files stream to disk or the blob store as they arrive, nothing
+/// is kept in memory, see [`crate::ingest`].
 pub async fn upload(
-    MultipartForm(form): MultipartForm<UploadFrom>,
-    request: HttpRequest,
+    mut payload: Multipart,
+    user: AuthUser,
     psql_pool: web::Data<Pool>,
-    mongo_pool:web::Data<Client>
+    mongo_pool:web::Data<Client>,
+    similarity: web::Data<SimilarityIndex>,
+    store: web::Data<dyn BlobStore>,
+    settings: web::Data<Settings>,
 ) -> io::Result<impl Responder> {
-    if form.file.len() != form.metadata.len() {
-        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
-            error: "metadata count does not match file count.".to_string(),
-        }));
+    let limits = &settings.files;
+    if let Err(e) = user.require(Scope::PostsWrite) {
+        return Ok(generate_response(&e));
     }
-    let auth = request.headers().get(AUTHORIZATION);
-    if auth.is_none() {
-        return Ok(HttpResponse::Unauthorized().json(ErrorResponse {
-            error: "authorization header not found.".to_string(),
-        }));
+    let user_id = user.user_id;
+    let store = store.into_inner();
+
+    //receive every part before anything is stored
+    let mut files: Vec<ReceivedFile> = Vec::new();
+    let mut metadata: Option<Vec<UploadJson>> = None;
+    let mut strip_enabled = true;
+    let mut total_size = 0u64;
+    while let Some(field) = payload.next().await {
+        let mut field = match field {
+            Ok(field) => field,
+            Err(e) => {
+                eprintln!("Multipart Error: {}", e);
+                return Ok(malformed());
+            }
+        };
+        match field.name().unwrap_or_default() {
+            "file" => {
+                if files.len() >= limits.max_upload_files {
+                    return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                        error: format!(
+                            "at most {} files can be uploaded at once.",
+                            limits.max_upload_files
+                        ),
+                    }));
+                }
+                let filename = match field.content_disposition().and_then(|cd| cd.get_filename()) {
+                    Some(name) => name.to_string(),
+                    None => {
+                        eprintln!("filename was not found");
+                        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                            error: "filename was not found.".to_string(),
+                        }));
+                    }
+                };
+                let claimed = field.content_type().map(|m| m.essence_str().to_string());
+                let mut spool = Spool::new(&limits.destination, store.clone(), limits.max_upload_file_size);
+                let mut sniffed = false;
+                while let Some(chunk) = field.next().await {
+                    let chunk = match chunk {
+                        Ok(chunk) => chunk,
+                        Err(e) => {
+                            eprintln!("Multipart Error: {}", e);
+                            return Ok(malformed());
+                        }
+                    };
+                    total_size += chunk.len() as u64;
+                    if total_size > limits.max_payload_size as u64 {
+                        return Ok(ingest_failed(&IngestError::TooLarge(
+                            limits.max_payload_size as u64,
+                        )));
+                    }
+                    if let Err(e) = spool.write(chunk).await {
+                        return Ok(ingest_failed(&e));
+                    }
+                    //reject unknown content without reading the rest of it
+                    if !sniffed && let Some(media_type) = spool.sniffed() {
+                        sniffed = true;
+                        if media_type.is_none() {
+                            return Ok(ingest_failed(&IngestError::Unsupported(filename)));
+                        }
+                    }
+                }
+                let spooled = match spool.finish().await {
+                    Ok(spooled) => spooled,
+                    Err(e) => return Ok(ingest_failed(&e)),
+                };
+                files.push(ReceivedFile {
+                    spooled,
+                    filename,
+                    claimed,
+                });
+            }
+            "metadata" => {
+                let parsed = read_text(&mut field, limits.max_form_field_size)
+                    .await
+                    .and_then(|text| serde_json::from_str::<Vec<UploadJson>>(&text).ok());
+                match parsed {
+                    Some(parsed) => metadata = Some(parsed),
+                    None => {
+                        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                            error: "metadata must be a JSON array of post metadata.".to_string(),
+                        }));
+                    }
+                }
+            }
+            "strip_metadata" => match read_text(&mut field, limits.max_form_field_size).await.as_deref().map(str::trim) {
+                Some("true") => strip_enabled = true,
+                Some("false") => strip_enabled = false,
+                _ => {
+                    return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                        error: "strip_metadata must be true or false.".to_string(),
+                    }));
+                }
+            },
+            //unknown parts are skipped
+            _ => {
+                while let Some(chunk) = field.next().await {
+                    if chunk.is_err() {
+                        return Ok(malformed());
+                    }
+                }
+            }
+        }
     }
-    let auth_header = match auth.unwrap().to_str() {
-        Ok(h) => h,
-        Err(_) => {
+    let metadata = match metadata {
+        Some(metadata) => metadata,
+        None => {
             return Ok(HttpResponse::BadRequest().json(ErrorResponse {
-                error: "Invalid authorization header format.".to_string(),
+                error: "metadata was not found.".to_string(),
             }));
         }
     };
-    let user_id =
-        match check_user_validity_with_pool(&psql_pool, auth_header, CredentialType::SessionToken).await
+    if files.len() != metadata.len() {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+            error: "metadata count does not match file count.".to_string(),
+        }));
+    }
+
+    let ingest = Ingest {
+        psql_pool: psql_pool.get_ref().clone(),
+        mongo_pool: mongo_pool.get_ref().clone(),
+        store,
+        similarity: similarity.into_inner(),
+        destination: limits.destination.clone(),
+    };
+    //detect and clean every file before anything is stored
+    let mut prepared_files = Vec::new();
+    for file in files {
+        match ingest
+            .prepare(file.spooled, &file.filename, file.claimed.as_deref(), strip_enabled)
+            .await
         {
-            Ok(id) => id,
-            Err(e) => return Ok(generate_response(&e)),
-        };
-    let coll = mongo_pool.database(MONGODB_DBANAME).collection::<Post>("post");
-    let postgres = match get_psql_pool(&psql_pool).await {
+            Ok(prepared) => prepared_files.push(prepared),
+            Err(e) => return Ok(ingest_failed(&e)),
+        }
+    }
+
+    let mut postgres = match get_psql_pool(&psql_pool).await {
         Ok(conn) => conn,
         Err(_) => {
             return Ok(HttpResponse::ExpectationFailed().json(ErrorResponse {
@@ -55,106 +218,25 @@ pub async fn upload(
         }
     };
     println!("database connection established");
-
-    let statement = match postgres
-        .prepare(
-            "INSERT INTO post (post_id, user_id, filename, content_type) VALUES ($1, $2, $3, $4)"
-        )
-        .await
-    {
-        Ok(stmt) => stmt,
-        Err(e) => {
-            eprintln!("Failed to prepare PostgreSQL statement: {}", e);
-            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
-                error: "Database preparation failed.".to_string(),
-            }));
-        }
+    //the whole request is stored as one unit
+    let items: Vec<_> = prepared_files.into_iter().zip(metadata).collect();
+    for (prepared, _) in &items {
+        println!("Content-Type: {}", prepared.media_type.mime);
+    }
+    let created = match ingest.create_posts(&mut postgres, &user_id, items).await {
+        Ok(created) => created,
+        Err(e) => return Ok(ingest_failed(&e)),
     };
-
-    //file process
     let mut received_files: Vec<String> = Vec::new();
-    for (file, metadata) in form.file.into_iter().zip(form.metadata.0.into_iter()) {
-        let content_type = match &file.content_type {
-            Some(ct_type) => {
-                println!("Content-Type: {}", ct_type.essence_str());
-                ct_type.essence_str().to_string()
-            }
-            None => {
-                return Ok(HttpResponse::BadRequest().json(ErrorResponse {
-                    error: "Content-Type header missing.".to_string(),
-                }));
-            }
-        };
-
-        let filename = match &file.file_name {
-            Some(name) => name.clone(),
-            None => {
-                eprintln!("filename was not found");
-                return Ok(HttpResponse::BadRequest().json(ErrorResponse {
-                    error: "filename was not found.".to_string(),
-                }));
-            }
-        };
-
-        let ext = match filename.rsplit('.').next() {
-            Some(e) => e,
-            None => {
-                return Ok(HttpResponse::BadRequest().json(ErrorResponse {
-                    error: "extension was not found.".to_string(),
-                }));
-            }
-        };
-
-        let post_id = Uuid::new_v4();
-        let new_filename = format!("{}.{}", &post_id, ext);
-        let path = format!("{}/{}", DESTINATION, new_filename);
-
-        match file.file.persist(&path) {
-            Ok(_) => println!("{} saved successfully", filename),
-            Err(e) => {
-                eprintln!("{} failed to save: {}", filename, e);
-                return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
-                    error: "Failed to save uploaded file.".to_string(),
-                }));
-            }
-        }
-        if let Err(e) = postgres.execute(
-            &statement,
-            &[&post_id, &user_id, &new_filename, &content_type]
-        )
-        .await
-        {
-            eprintln!("PostgreSQL Insert Error: {}", e);
-            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
-                error: "Failed to store post metadata in database.".to_string(),
-            }));
-        }
-        //for mongo
-        let article = Post {
-            post_id,
-            title: metadata.title.clone(),
-            creator: metadata.creator.clone(),
-            source: metadata.source.clone(),
-            description: metadata.description.clone(),
-            uploader: user_id,
-        };
-
-        match coll.insert_one(article).await {
-            Ok(_) => {
-                println!("Inserted post {} to MongoDB", post_id);
-                received_files.push(new_filename);
-            }
-            Err(e) => {
-                eprintln!("MongoDB Insert Error: {}", e);
-                return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
-                    error: "Failed to store post in MongoDB.".to_string(),
-                }));
-            }
-        }
+    let mut possible_duplicates = Vec::new();
+    for post in created {
+        received_files.push(post.filename);
+        possible_duplicates.extend(post.possible_duplicates);
     }
 
-    let response = ResponseFile {
+    let response = UploadResponse {
         file: received_files,
+        possible_duplicates,
     };
 
     Ok(HttpResponse::Ok()
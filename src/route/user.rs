@@ -1,2 +1,5 @@
 pub mod login;
-pub mod signup;
\ No newline at end of file
+pub mod logout;
+pub mod sessions;
+pub mod signup;
+pub mod tokens;
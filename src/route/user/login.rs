@@ -1,17 +1,68 @@
-use actix_web::{HttpResponse, Responder, web};
+use actix_web::{
+    HttpRequest, HttpResponse, Responder,
+    cookie::{Cookie, SameSite, time::OffsetDateTime},
+    http::header::USER_AGENT,
+    web,
+};
 use bcrypt::verify;
 use chrono::{DateTime, Duration, Utc};
-use deadpool_postgres::Pool;
+use deadpool_postgres::{GenericClient, Pool};
 use rand::Rng;
 use sha2::{Digest, Sha256};
+use std::net::IpAddr;
 use uuid::Uuid;
 
 use crate::{
+    audit::{self, AuditKind},
+    auth::SESSION_COOKIE,
     types::{ErrorResponse, LoginRequest, LoginResponse, RefreshToken, SessionTokenResponse},
     utility::get_psql_pool,
 };
 
+/// Longer `User-Agent` values are cut to this many characters
+pub const MAX_USER_AGENT_LENGTH: usize = 512;
+
+/// The device a session is created for, shown by `GET /me/sessions`
+pub struct ClientInfo {
+    pub ip_address: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+impl ClientInfo {
+    /// The address is the peer's, forwarding headers are not trusted
+    pub fn from_request(request: &HttpRequest) -> ClientInfo {
+        ClientInfo {
+            ip_address: request.peer_addr().map(|addr| addr.ip()),
+            user_agent: request
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|v| v.to_str().ok())
+                .map(|agent| agent.chars().take(MAX_USER_AGENT_LENGTH).collect()),
+        }
+    }
+}
+
+/// The new session as JSON, with the session cookie when `set_cookie` asked for it.
+/// Bearer clients leave it off and keep using the `session_token` field.
+fn session_created(response: SessionTokenResponse, set_cookie: bool) -> HttpResponse {
+    let mut builder = HttpResponse::Ok();
+    if set_cookie {
+        let expires = OffsetDateTime::from_unix_timestamp(response.session_expires_at.timestamp())
+            .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+        let cookie = Cookie::build(SESSION_COOKIE, response.session_token.clone())
+            .path("/")
+            .http_only(true)
+            .secure(true)
+            .same_site(SameSite::Lax)
+            .expires(expires)
+            .finish();
+        builder.cookie(cookie);
+    }
+    builder.json(response)
+}
+
 pub async fn raw(
+    request: HttpRequest,
     pool: web::Data<Pool>,
     data: web::Json<LoginRequest>,
 ) -> std::io::Result<impl Responder> {
@@ -64,8 +115,9 @@ pub async fn raw(
         }
     }
 
-    match generate_session_tokens(&psql_client, &user_id, &username).await {
-        Ok(response) => Ok(HttpResponse::Ok().json(response)),
+    let client = ClientInfo::from_request(&request);
+    match generate_session_tokens(&psql_client, &user_id, &username, &client, None).await {
+        Ok(response) => Ok(session_created(response, data.set_cookie)),
         Err(e) => Ok(HttpResponse::InternalServerError().json(ErrorResponse { error: e })),
     }
 }
@@ -89,7 +141,9 @@ pub async fn session_token_login(
         }
     };
 
-    let query = "SELECT user_id FROM session WHERE session_token = $1 AND is_revoked = false";
+    let query = "UPDATE session SET last_seen_at = NOW()
+        WHERE session_token = $1 AND is_revoked = false AND session_expires_at > NOW()
+        RETURNING user_id";
     let user_id: Uuid = match psql_client.query_one(query, &[&data.session_token]).await {
         Ok(row) => row.get(0),
         Err(_) => {
@@ -121,7 +175,12 @@ pub async fn session_token_login(
     }))
 }
 
+/// Exchange a refresh token for a new session. The old session is revoked and the new
+/// one joins its family. A refresh token that was already exchanged or whose session
+/// was revoked is treated as stolen: every session of the family is revoked and an
+/// audit event is recorded.
 pub async fn refresh_token(
+    request: HttpRequest,
     pool: web::Data<Pool>,
     data: web::Json<RefreshToken>,
 ) -> std::io::Result<impl Responder> {
@@ -131,7 +190,7 @@ pub async fn refresh_token(
         }));
     }
 
-    let psql_client = match get_psql_pool(&pool).await {
+    let mut psql_client = match get_psql_pool(&pool).await {
         Ok(client) => client,
         Err(_) => {
             return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
@@ -142,30 +201,96 @@ pub async fn refresh_token(
 
     let refresh_token_hash = hash_token(&data.refresh_token);
 
+    let transaction = match psql_client.transaction().await {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("Failed to begin transaction: {}", e);
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "database query error.".to_string(),
+            }));
+        }
+    };
+    //the row lock makes concurrent refreshes of the same token queue up
     let query = r#"
-        SELECT user_id, refresh_expires_at FROM session
-        WHERE refresh_token_hash = $1 AND is_revoked = false
+        SELECT token_id, user_id, family_id, refresh_expires_at, is_revoked,
+            EXISTS(SELECT 1 FROM session child WHERE child.parent_token_id = s.token_id)
+        FROM session s WHERE refresh_token_hash = $1
+        FOR UPDATE OF s
     "#;
-
-    let session_row = match psql_client.query_one(query, &[&refresh_token_hash]).await {
-        Ok(row) => row,
-        Err(_) => {
+    let session_row = match transaction.query_opt(query, &[&refresh_token_hash]).await {
+        Ok(Some(row)) => row,
+        Ok(None) => {
             return Ok(HttpResponse::Unauthorized().json(ErrorResponse {
                 error: "invalid refresh token.".to_string(),
             }));
         }
+        Err(e) => {
+            eprintln!("Refresh token query failed: {}", e);
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "database query error.".to_string(),
+            }));
+        }
     };
 
-    let user_id: Uuid = session_row.get(0);
-    let refresh_expires_at: DateTime<Utc> = session_row.get(1);
+    let token_id: Uuid = session_row.get(0);
+    let user_id: Uuid = session_row.get(1);
+    let family_id: Uuid = session_row.get(2);
+    let refresh_expires_at: DateTime<Utc> = session_row.get(3);
+    let is_revoked: bool = session_row.get(4);
+    let rotated: bool = session_row.get(5);
 
+    if rotated || is_revoked {
+        let (reason, error) = match rotated {
+            true => ("was reused", "refresh token was already used, please log in again."),
+            false => ("of a revoked session was presented", "refresh token was revoked, please log in again."),
+        };
+        let revoked = match transaction
+            .execute(
+                "UPDATE session SET is_revoked = true, updated_at = NOW()
+                WHERE family_id = $1 AND is_revoked = false",
+                &[&family_id],
+            )
+            .await
+        {
+            Ok(revoked) => revoked,
+            Err(e) => {
+                eprintln!("Failed to revoke session family {}: {}", family_id, e);
+                return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: "database query error.".to_string(),
+                }));
+            }
+        };
+        if let Err(e) = transaction.commit().await {
+            eprintln!("PostgreSQL Commit Error: {}", e);
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "database query error.".to_string(),
+            }));
+        }
+        eprintln!(
+            "Refresh token of session {} {}, revoked {} sessions of family {}",
+            token_id, reason, revoked, family_id
+        );
+        audit::record(
+            &psql_client,
+            Some(&user_id),
+            AuditKind::RefreshTokenReuse,
+            &format!(
+                "refresh token of session {} {}, revoked {} sessions of family {}",
+                token_id, reason, revoked, family_id
+            ),
+        )
+        .await;
+        return Ok(HttpResponse::Unauthorized().json(ErrorResponse {
+            error: error.to_string(),
+        }));
+    }
     if refresh_expires_at < Utc::now() {
         return Ok(HttpResponse::Unauthorized().json(ErrorResponse {
             error: "refresh token has expired.".to_string(),
         }));
     }
 
-    let username: String = match psql_client
+    let username: String = match transaction
         .query_one(
             "SELECT username FROM \"user\" WHERE user_id = $1",
             &[&user_id],
@@ -180,29 +305,76 @@ pub async fn refresh_token(
         }
     };
 
-    match generate_session_tokens(&psql_client, &user_id, &username).await {
-        Ok(response) => Ok(HttpResponse::Ok().json(response)),
-        Err(e) => Ok(HttpResponse::InternalServerError().json(ErrorResponse { error: e })),
+    if let Err(e) = transaction
+        .execute(
+            "UPDATE session SET is_revoked = true, updated_at = NOW() WHERE token_id = $1",
+            &[&token_id],
+        )
+        .await
+    {
+        eprintln!("Failed to revoke refreshed session: {}", e);
+        return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+            error: "database query error.".to_string(),
+        }));
     }
+    let lineage = Lineage {
+        family_id,
+        parent_token_id: token_id,
+    };
+    let client = ClientInfo::from_request(&request);
+    let response = match generate_session_tokens(
+        &transaction,
+        &user_id,
+        &username,
+        &client,
+        Some(lineage),
+    )
+    .await
+    {
+        Ok(response) => response,
+        Err(e) => return Ok(HttpResponse::InternalServerError().json(ErrorResponse { error: e })),
+    };
+    match transaction.commit().await {
+        Ok(_) => Ok(session_created(response, data.set_cookie)),
+        Err(e) => {
+            eprintln!("PostgreSQL Commit Error: {}", e);
+            Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "failed to create session.".to_string(),
+            }))
+        }
+    }
+}
+
+/// Where a refreshed session comes from
+struct Lineage {
+    family_id: Uuid,
+    parent_token_id: Uuid,
 }
 
+/// A new login starts its own family, named after its first session
 async fn generate_session_tokens(
-    psql_client: &deadpool_postgres::Client,
+    psql_client: &impl GenericClient,
     user_id: &Uuid,
     username: &str,
+    client: &ClientInfo,
+    lineage: Option<Lineage>,
 ) -> Result<SessionTokenResponse, String> {
     let token_id = Uuid::new_v4();
     let session_token = generate_random_token();
     let refresh_token = generate_random_token();
     let refresh_token_hash = hash_token(&refresh_token);
+    let (family_id, parent_token_id) = match lineage {
+        Some(lineage) => (lineage.family_id, Some(lineage.parent_token_id)),
+        None => (token_id, None),
+    };
 
     let now = Utc::now();
     let session_expires_at = now + Duration::hours(1);
     let refresh_expires_at = now + Duration::days(30);
 
     let insert_query = r#"
-        INSERT INTO "session" (token_id, user_id, session_token, refresh_token_hash, session_expires_at, refresh_expires_at)
-        VALUES ($1, $2, $3, $4, $5, $6)
+        INSERT INTO "session" (token_id, user_id, session_token, refresh_token_hash, session_expires_at, refresh_expires_at, family_id, parent_token_id, ip_address, user_agent, last_seen_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, NOW())
     "#;
 
     match psql_client
@@ -215,6 +387,10 @@ async fn generate_session_tokens(
                 &refresh_token_hash,
                 &session_expires_at,
                 &refresh_expires_at,
+                &family_id,
+                &parent_token_id,
+                &client.ip_address,
+                &client.user_agent,
             ],
         )
         .await
@@ -224,6 +400,7 @@ async fn generate_session_tokens(
             username: username.to_string(),
             session_token,
             refresh_token,
+            session_expires_at,
             message: "login successfully.".to_string(),
         }),
         Err(e) => {
@@ -233,14 +410,52 @@ async fn generate_session_tokens(
     }
 }
 
-fn generate_random_token() -> String {
+pub fn generate_random_token() -> String {
     let mut rng = rand::thread_rng();
     let random_bytes: Vec<u8> = (0..32).map(|_| rng.gen_range(0..256) as u8).collect();
     hex::encode(random_bytes)
 }
 
-fn hash_token(token: &str) -> String {
+/// Only this hash of refresh and dev tokens is stored
+pub fn hash_token(token: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(token.as_bytes());
     hex::encode(hasher.finalize())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response() -> SessionTokenResponse {
+        SessionTokenResponse {
+            user_id: Uuid::new_v4().to_string(),
+            username: "alice".to_string(),
+            session_token: "session".to_string(),
+            refresh_token: "refresh".to_string(),
+            session_expires_at: Utc::now() + Duration::hours(1),
+            message: "login successfully.".to_string(),
+        }
+    }
+
+    #[test]
+    fn sets_a_locked_down_cookie_when_asked() {
+        let expected = response();
+        let expires_at = expected.session_expires_at.timestamp();
+        let created = session_created(expected, true);
+        let cookie = created.cookies().next().expect("session cookie");
+        assert_eq!(cookie.name(), SESSION_COOKIE);
+        assert_eq!(cookie.value(), "session");
+        assert_eq!(cookie.path(), Some("/"));
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+        let expires = cookie.expires_datetime().expect("expiry");
+        assert_eq!(expires.unix_timestamp(), expires_at);
+    }
+
+    #[test]
+    fn bearer_clients_get_no_cookie() {
+        assert!(session_created(response(), false).cookies().next().is_none());
+    }
+}
@@ -0,0 +1,120 @@
+//! Ending sessions. Revoked and expired rows stay until
+//! [`spawn_session_sweeper`] deletes them, [`DEAD_SESSION_RETENTION_DAYS`] after
+//! their refresh token expired.
+use actix_web::{HttpResponse, Responder, cookie::Cookie, web};
+use deadpool_postgres::Pool;
+
+use crate::{
+    auth::{AuthUser, SESSION_COOKIE},
+    scope::Scope,
+    types::{ErrorResponse, LogoutResponse},
+    utility::{CredentialType, generate_response, get_psql_pool},
+};
+
+pub const SESSION_SWEEP_INTERVAL_SECS: u64 = 3600;
+pub const DEAD_SESSION_RETENTION_DAYS: i32 = 7;
+
+/// Tell the browser to drop the session cookie, if it has one
+fn logged_out(sessions_revoked: u64) -> HttpResponse {
+    let mut cookie = Cookie::build(SESSION_COOKIE, "").path("/").finish();
+    cookie.make_removal();
+    HttpResponse::Ok().cookie(cookie).json(LogoutResponse {
+        sessions_revoked,
+        message: "logged out.".to_string(),
+    })
+}
+
+/// Revoke the session the request was made with
+pub async fn logout(user: AuthUser, pool: web::Data<Pool>) -> std::io::Result<impl Responder> {
+    if user.credential_type != CredentialType::SessionToken {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+            error: "logout needs a session token, revoke dev tokens under /me/tokens.".to_string(),
+        }));
+    }
+    let psql_client = match get_psql_pool(&pool).await {
+        Ok(client) => client,
+        Err(_) => {
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "database connection error.".to_string(),
+            }));
+        }
+    };
+    match psql_client
+        .execute(
+            "UPDATE session SET is_revoked = true, updated_at = NOW()
+            WHERE token_id = $1 AND is_revoked = false",
+            &[&user.token_id],
+        )
+        .await
+    {
+        Ok(revoked) => Ok(logged_out(revoked)),
+        Err(e) => {
+            eprintln!("Failed to revoke session: {}", e);
+            Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "database query error.".to_string(),
+            }))
+        }
+    }
+}
+
+/// Revoke every session of the user, dev tokens are left alone.
+/// Needs the `admin` scope, like the rest of account management.
+pub async fn logout_all(user: AuthUser, pool: web::Data<Pool>) -> std::io::Result<impl Responder> {
+    if let Err(e) = user.require(Scope::Admin) {
+        return Ok(generate_response(&e));
+    }
+    let psql_client = match get_psql_pool(&pool).await {
+        Ok(client) => client,
+        Err(_) => {
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "database connection error.".to_string(),
+            }));
+        }
+    };
+    match psql_client
+        .execute(
+            "UPDATE session SET is_revoked = true, updated_at = NOW()
+            WHERE user_id = $1 AND is_revoked = false",
+            &[&user.user_id],
+        )
+        .await
+    {
+        Ok(revoked) => Ok(logged_out(revoked)),
+        Err(e) => {
+            eprintln!("Failed to revoke sessions: {}", e);
+            Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "database query error.".to_string(),
+            }))
+        }
+    }
+}
+
+/// Delete sessions that can no longer be used or refreshed every `SESSION_SWEEP_INTERVAL_SECS`
+pub fn spawn_session_sweeper(psql_pool: Pool) {
+    tokio::spawn(async move {
+        let mut interval =
+            tokio::time::interval(std::time::Duration::from_secs(SESSION_SWEEP_INTERVAL_SECS));
+        loop {
+            interval.tick().await;
+            let client = match psql_pool.get().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    eprintln!("Failed to get connection from pool: {}", e);
+                    continue;
+                }
+            };
+            match client
+                .execute(
+                    "DELETE FROM session
+                    WHERE refresh_expires_at < NOW() - make_interval(days => $1)",
+                    &[&DEAD_SESSION_RETENTION_DAYS],
+                )
+                .await
+            {
+                Ok(0) => {}
+                Ok(removed) => println!("Removed {} dead sessions", removed),
+                Err(e) => eprintln!("Failed to sweep dead sessions: {}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,116 @@
+//! Devices signed in to an account, under `/me/sessions`.
+//! A device is one session family; refreshing replaces its `token_id`.
+use actix_web::{HttpResponse, Responder, web};
+use deadpool_postgres::Pool;
+use uuid::Uuid;
+
+use crate::{
+    auth::AuthUser,
+    scope::Scope,
+    types::{ErrorResponse, RevokeResponse, SessionInfo, SessionListResponse},
+    utility::{generate_response, get_psql_pool},
+};
+
+/// Sessions that can still be used or refreshed, most recently seen first
+pub async fn list_sessions(
+    user: AuthUser,
+    pool: web::Data<Pool>,
+) -> std::io::Result<impl Responder> {
+    if let Err(e) = user.require(Scope::Admin) {
+        return Ok(generate_response(&e));
+    }
+    let psql_client = match get_psql_pool(&pool).await {
+        Ok(client) => client,
+        Err(_) => {
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "database connection error.".to_string(),
+            }));
+        }
+    };
+    match psql_client
+        .query(
+            "SELECT s.token_id, s.ip_address, s.user_agent,
+                (SELECT MIN(f.created_at) FROM session f WHERE f.family_id = s.family_id),
+                s.last_seen_at
+            FROM session s
+            WHERE s.user_id = $1 AND s.is_revoked = false AND s.refresh_expires_at > NOW()
+            ORDER BY s.last_seen_at DESC NULLS LAST",
+            &[&user.user_id],
+        )
+        .await
+    {
+        Ok(rows) => Ok(HttpResponse::Ok().json(SessionListResponse {
+            sessions: rows
+                .iter()
+                .map(|row| {
+                    let token_id: Uuid = row.get(0);
+                    SessionInfo {
+                        token_id,
+                        ip_address: row.get(1),
+                        user_agent: row.get(2),
+                        signed_in_at: row.get(3),
+                        last_seen_at: row.get(4),
+                        current: token_id == user.token_id,
+                    }
+                })
+                .collect(),
+        })),
+        Err(e) => {
+            eprintln!("Session query failed: {}", e);
+            Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "database query error.".to_string(),
+            }))
+        }
+    }
+}
+
+/// Sign a device out. Its refresh token stops working along with the session token.
+pub async fn revoke_session(
+    user: AuthUser,
+    pool: web::Data<Pool>,
+    token_id: web::Path<String>,
+) -> std::io::Result<impl Responder> {
+    if let Err(e) = user.require(Scope::Admin) {
+        return Ok(generate_response(&e));
+    }
+    let token_id = match Uuid::parse_str(&token_id.into_inner()) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid token ID format".to_string(),
+            }));
+        }
+    };
+    let psql_client = match get_psql_pool(&pool).await {
+        Ok(client) => client,
+        Err(_) => {
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "database connection error.".to_string(),
+            }));
+        }
+    };
+    match psql_client
+        .execute(
+            "UPDATE session SET is_revoked = true, updated_at = NOW()
+            WHERE user_id = $2 AND is_revoked = false AND family_id = (
+                SELECT family_id FROM session WHERE token_id = $1 AND user_id = $2
+            )",
+            &[&token_id, &user.user_id],
+        )
+        .await
+    {
+        Ok(0) => Ok(HttpResponse::NotFound().json(ErrorResponse {
+            error: "session not found.".to_string(),
+        })),
+        Ok(_) => Ok(HttpResponse::Ok().json(RevokeResponse {
+            token_id: token_id.to_string(),
+            message: "session revoked.".to_string(),
+        })),
+        Err(e) => {
+            eprintln!("Failed to revoke session: {}", e);
+            Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "database query error.".to_string(),
+            }))
+        }
+    }
+}
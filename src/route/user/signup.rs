@@ -5,47 +5,61 @@ use crate::{
 use actix_web::{HttpResponse, Responder, web};
 use bcrypt::{DEFAULT_COST, hash};
 use chrono::{Duration, Utc};
-use deadpool_postgres::Pool;
+use deadpool_postgres::{Client, Pool};
 use uuid::Uuid;
 
-pub async fn signup(
-    pool: web::Data<Pool>,
-    data: web::Json<SignUpRequest>,
-) -> std::io::Result<impl Responder> {
-    if data.username.trim().is_empty() || data.password.trim().is_empty() {
-        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
-            error: "Username and password cannot be empty".to_string(),
-        }));
+#[derive(Debug)]
+pub enum SignupError {
+    /// Username or password rejected, holds the message shown to the client
+    Invalid(&'static str),
+    UsernameTaken,
+    HashFailed,
+    Database,
+}
+
+impl std::fmt::Display for SignupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignupError::Invalid(message) => write!(f, "{}", message),
+            SignupError::UsernameTaken => write!(f, "Username already taken"),
+            SignupError::HashFailed => write!(f, "Failed to process password"),
+            SignupError::Database => write!(f, "Failed to create user"),
+        }
     }
-    if data.username.len() < 3 || data.username.len() > 255 {
-        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
-            error: "Username must be between 3 and 255 characters".to_string(),
-        }));
+}
+impl std::error::Error for SignupError {}
+
+pub fn validate_password(password: &str) -> Result<(), SignupError> {
+    if password.trim().is_empty() {
+        return Err(SignupError::Invalid("Username and password cannot be empty"));
     }
-    if data.password.len() < 8 {
-        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
-            error: "Password must be at least 8 characters long".to_string(),
-        }));
+    if password.len() < 8 {
+        return Err(SignupError::Invalid("Password must be at least 8 characters long"));
     }
+    Ok(())
+}
 
-    let psql_client = match get_psql_pool(&pool).await {
-        Ok(client) => client,
-        Err(_) => {
-            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
-                error: "Database connection error".to_string(),
-            }));
-        }
-    };
+pub fn hash_password(password: &str) -> Result<String, SignupError> {
+    hash(password, DEFAULT_COST).map_err(|e| {
+        eprintln!("Failed to hash password: {}", e);
+        SignupError::HashFailed
+    })
+}
 
-    let password_hash = match hash(&data.password, DEFAULT_COST) {
-        Ok(hashed_pass) => hashed_pass,
-        Err(e) => {
-            eprintln!("Failed to hash password: {}", e);
-            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
-                error: "Failed to process password".to_string(),
-            }));
-        }
-    };
+/// Validate and insert a new user, returns its id
+pub async fn create_user(
+    psql_client: &Client,
+    username: &str,
+    password: &str,
+) -> Result<Uuid, SignupError> {
+    if username.trim().is_empty() || password.trim().is_empty() {
+        return Err(SignupError::Invalid("Username and password cannot be empty"));
+    }
+    if username.len() < 3 || username.len() > 255 {
+        return Err(SignupError::Invalid("Username must be between 3 and 255 characters"));
+    }
+    validate_password(password)?;
+    let password_hash = hash_password(password)?;
 
     let user_id = Uuid::new_v4();
     
@@ -54,47 +68,65 @@ pub async fn signup(
     let query = r#"
         INSERT INTO "user" (user_id, username, password_hash, expired_at)
         VALUES ($1, $2, $3, $4)
-        RETURNING user_id, username
+        RETURNING user_id
     "#;
     match psql_client
-        .query_one(query, &[&user_id, &data.username, &password_hash,&expires_at])
+        .query_one(query, &[&user_id, &username, &password_hash,&expires_at])
         .await
     {
-        Ok(row) => {
-            let returned_user_id: Uuid = row.get(0);
-            let returned_username: String = row.get(1);
-            Ok(HttpResponse::Created().json(SignUpResponse {
-                user_id: returned_user_id.to_string(),
-                username: returned_username,
-                message: "User registered successfully".to_string(),
-            }))
-        }
+        Ok(row) => Ok(row.get(0)),
         Err(e) => {
             match psql_client
                 .query_one(
                     "SELECT EXISTS(SELECT 1 FROM \"user\" WHERE username = $1)",
-                    &[&data.username],
+                    &[&username],
                 )
                 .await
             {
                 Ok(is_exist) => {
                     let exists: bool = is_exist.get(0);
                     if exists {
-                        return Ok(HttpResponse::Conflict().json(ErrorResponse {
-                            error: "Username already taken".to_string(),
-                        }));
+                        return Err(SignupError::UsernameTaken);
                     }
                 }
-                Err(_) => {
-                    return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
-                        error: "Database query error".to_string(),
-                    }));
-                }
+                Err(_) => return Err(SignupError::Database),
             }
             eprintln!("Failed to insert user: {}", e);
-            Ok(HttpResponse::InternalServerError().json(ErrorResponse {
-                error: "Failed to create user".to_string(),
-            }))
+            Err(SignupError::Database)
+        }
+    }
+}
+
+pub async fn signup(
+    pool: web::Data<Pool>,
+    data: web::Json<SignUpRequest>,
+) -> std::io::Result<impl Responder> {
+    let psql_client = match get_psql_pool(&pool).await {
+        Ok(client) => client,
+        Err(_) => {
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database connection error".to_string(),
+            }));
+        }
+    };
+
+    match create_user(&psql_client, &data.username, &data.password).await {
+        Ok(user_id) => Ok(HttpResponse::Created().json(SignUpResponse {
+            user_id: user_id.to_string(),
+            username: data.username.clone(),
+            message: "User registered successfully".to_string(),
+        })),
+        Err(e) => {
+            let body = ErrorResponse {
+                error: e.to_string(),
+            };
+            match e {
+                SignupError::Invalid(_) => Ok(HttpResponse::BadRequest().json(body)),
+                SignupError::UsernameTaken => Ok(HttpResponse::Conflict().json(body)),
+                SignupError::HashFailed | SignupError::Database => {
+                    Ok(HttpResponse::InternalServerError().json(body))
+                }
+            }
         }
     }
 }
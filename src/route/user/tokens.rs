@@ -0,0 +1,209 @@
+//! Dev tokens for bots and scripts, managed under `/me/tokens` with a session token
+//! or a dev token holding the `admin` scope.
+//! The plaintext token is returned once by `POST /me/tokens`, only its SHA-256 is stored.
+use actix_web::{HttpResponse, Responder, web};
+use chrono::{Duration, Utc};
+use deadpool_postgres::Pool;
+use uuid::Uuid;
+
+use crate::{
+    auth::AuthUser,
+    route::user::login::{generate_random_token, hash_token},
+    scope::{Scope, format_scopes, parse_scopes},
+    types::{
+        CreateTokenRequest, CreatedTokenResponse, DevTokenInfo, DevTokenListResponse,
+        ErrorResponse, RevokeResponse,
+    },
+    utility::{generate_response, get_psql_pool},
+};
+
+pub const DEFAULT_TOKEN_DAYS: i64 = 90;
+pub const MAX_TOKEN_DAYS: i64 = 365;
+/// Active tokens one user may hold
+pub const MAX_TOKENS_PER_USER: i64 = 50;
+
+fn account_user(user: &AuthUser) -> Result<Uuid, HttpResponse> {
+    user.require(Scope::Admin).map_err(|e| generate_response(&e))?;
+    Ok(user.user_id)
+}
+
+fn token_info(row: &tokio_postgres::Row) -> DevTokenInfo {
+    DevTokenInfo {
+        token_id: row.get(0),
+        name: row.get(1),
+        scope: parse_scopes(row.get(2)),
+        created_at: row.get(3),
+        expires_at: row.get(4),
+        last_used_at: row.get(5),
+    }
+}
+
+pub async fn create_token(
+    user: AuthUser,
+    pool: web::Data<Pool>,
+    data: web::Json<CreateTokenRequest>,
+) -> std::io::Result<impl Responder> {
+    let user_id = match account_user(&user) {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    let name = data.name.trim();
+    if name.is_empty() || name.chars().count() > 255 {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+            error: "name must be between 1 and 255 characters.".to_string(),
+        }));
+    }
+    if data.scope.is_empty() {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+            error: "scope must name at least one scope.".to_string(),
+        }));
+    }
+    let scope = format_scopes(&data.scope);
+    let days = data.expires_in_days.unwrap_or(DEFAULT_TOKEN_DAYS);
+    if !(1..=MAX_TOKEN_DAYS).contains(&days) {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+            error: format!("expires_in_days must be between 1 and {}.", MAX_TOKEN_DAYS),
+        }));
+    }
+
+    let psql_client = match get_psql_pool(&pool).await {
+        Ok(client) => client,
+        Err(_) => {
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "database connection error.".to_string(),
+            }));
+        }
+    };
+    match psql_client
+        .query_one(
+            "SELECT COUNT(*) FROM dev_token
+            WHERE user_id = $1 AND is_revoked = false AND expires_at > NOW()",
+            &[&user_id],
+        )
+        .await
+    {
+        Ok(row) if row.get::<_, i64>(0) >= MAX_TOKENS_PER_USER => {
+            return Ok(HttpResponse::Conflict().json(ErrorResponse {
+                error: format!("at most {} active tokens are allowed.", MAX_TOKENS_PER_USER),
+            }));
+        }
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Dev token count failed: {}", e);
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "database query error.".to_string(),
+            }));
+        }
+    }
+
+    let token = generate_random_token();
+    let expires_at = Utc::now() + Duration::days(days);
+    let row = match psql_client
+        .query_one(
+            "INSERT INTO dev_token (user_id, token_hash, name, scope, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING token_id, name, scope, created_at, expires_at, last_used_at",
+            &[&user_id, &hash_token(&token), &name, &scope, &expires_at],
+        )
+        .await
+    {
+        Ok(row) => row,
+        Err(e) => {
+            eprintln!("Failed to insert dev token: {}", e);
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "failed to create token.".to_string(),
+            }));
+        }
+    };
+    Ok(HttpResponse::Created().json(CreatedTokenResponse {
+        token,
+        info: token_info(&row),
+    }))
+}
+
+/// Tokens that are neither revoked nor expired, newest first
+pub async fn list_tokens(
+    user: AuthUser,
+    pool: web::Data<Pool>,
+) -> std::io::Result<impl Responder> {
+    let user_id = match account_user(&user) {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    let psql_client = match get_psql_pool(&pool).await {
+        Ok(client) => client,
+        Err(_) => {
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "database connection error.".to_string(),
+            }));
+        }
+    };
+    match psql_client
+        .query(
+            "SELECT token_id, name, scope, created_at, expires_at, last_used_at FROM dev_token
+            WHERE user_id = $1 AND is_revoked = false AND expires_at > NOW()
+            ORDER BY created_at DESC",
+            &[&user_id],
+        )
+        .await
+    {
+        Ok(rows) => Ok(HttpResponse::Ok().json(DevTokenListResponse {
+            tokens: rows.iter().map(token_info).collect(),
+        })),
+        Err(e) => {
+            eprintln!("Dev token query failed: {}", e);
+            Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "database query error.".to_string(),
+            }))
+        }
+    }
+}
+
+pub async fn revoke_token(
+    user: AuthUser,
+    pool: web::Data<Pool>,
+    token_id: web::Path<String>,
+) -> std::io::Result<impl Responder> {
+    let token_id = match Uuid::parse_str(&token_id.into_inner()) {
+        Ok(uuid) => uuid,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid token ID format".to_string(),
+            }));
+        }
+    };
+    let user_id = match account_user(&user) {
+        Ok(id) => id,
+        Err(response) => return Ok(response),
+    };
+    let psql_client = match get_psql_pool(&pool).await {
+        Ok(client) => client,
+        Err(_) => {
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "database connection error.".to_string(),
+            }));
+        }
+    };
+    match psql_client
+        .execute(
+            "UPDATE dev_token SET is_revoked = true, updated_at = NOW()
+            WHERE token_id = $1 AND user_id = $2 AND is_revoked = false",
+            &[&token_id, &user_id],
+        )
+        .await
+    {
+        Ok(0) => Ok(HttpResponse::NotFound().json(ErrorResponse {
+            error: "token not found.".to_string(),
+        })),
+        Ok(_) => Ok(HttpResponse::Ok().json(RevokeResponse {
+            token_id: token_id.to_string(),
+            message: "token revoked.".to_string(),
+        })),
+        Err(e) => {
+            eprintln!("Failed to revoke dev token: {}", e);
+            Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "database query error.".to_string(),
+            }))
+        }
+    }
+}
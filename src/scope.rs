@@ -0,0 +1,79 @@
+//! What a dev token may do.
+//! `dev_token.scope` holds space-separated scope names. Session tokens act for the
+//! user directly and carry every scope.
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "posts:read")]
+    PostsRead,
+    #[serde(rename = "posts:write")]
+    PostsWrite,
+    #[serde(rename = "posts:delete")]
+    PostsDelete,
+    #[serde(rename = "tags:write")]
+    TagsWrite,
+    /// Account management, including dev tokens. Implies every other scope.
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 5] = [
+        Scope::PostsRead,
+        Scope::PostsWrite,
+        Scope::PostsDelete,
+        Scope::TagsWrite,
+        Scope::Admin,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::PostsRead => "posts:read",
+            Scope::PostsWrite => "posts:write",
+            Scope::PostsDelete => "posts:delete",
+            Scope::TagsWrite => "tags:write",
+            Scope::Admin => "admin",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Scope> {
+        Scope::ALL.into_iter().find(|scope| scope.as_str() == name)
+    }
+
+    /// Whether holding `self` is enough for `required`.
+    /// Writing posts includes reading them, e.g. to resume an upload.
+    pub fn covers(&self, required: Scope) -> bool {
+        match (self, required) {
+            (Scope::Admin, _) => true,
+            (Scope::PostsWrite, Scope::PostsRead) => true,
+            (held, required) => *held == required,
+        }
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Scopes of a stored `dev_token.scope`. Unknown names grant nothing.
+pub fn parse_scopes(stored: &str) -> Vec<Scope> {
+    stored.split_whitespace().filter_map(Scope::parse).collect()
+}
+
+/// The form kept in `dev_token.scope`, without duplicates
+pub fn format_scopes(scopes: &[Scope]) -> String {
+    let mut names: Vec<&str> = Vec::new();
+    for scope in Scope::ALL {
+        if scopes.contains(&scope) {
+            names.push(scope.as_str());
+        }
+    }
+    names.join(" ")
+}
+
+pub fn allows(held: &[Scope], required: Scope) -> bool {
+    held.iter().any(|scope| scope.covers(required))
+}
@@ -0,0 +1,487 @@
+//! Runtime configuration.
+//!
+//! [`Settings::load`] starts from the defaults below, then applies a TOML file,
+//! environment variables and command line flags, each overriding the one before.
+//! The result is checked with [`Settings::validate`] before the server starts and
+//! handed to handlers as `web::Data<Settings>`.
+//!
+//! The file is `--config`, else `MEDIAPUB_CONFIG`, else `mediapub.toml` when it exists.
+//! Environment variables use the upper-case section and key, e.g. `POSTGRES_HOST`
+//! or `FILES_MAX_PAYLOAD_SIZE`. The older `S3_*` and `GRIDFS_BUCKET` names are still
+//! read for the storage section, the `STORAGE_` names win when both are set.
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_CONFIG_FILE: &str = "mediapub.toml";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub server: ServerSettings,
+    pub postgres: PostgresSettings,
+    pub mongodb: MongoSettings,
+    pub files: FileSettings,
+    pub storage: StorageSettings,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub host: String,
+    pub port: u16,
+    pub workers: usize,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        ServerSettings {
+            host: "0.0.0.0".to_string(),
+            port: 8080,
+            workers: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PostgresSettings {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    #[serde(skip_serializing)]
+    pub password: String,
+    pub dbname: String,
+}
+
+//these defaults are for the development environment
+impl Default for PostgresSettings {
+    fn default() -> Self {
+        PostgresSettings {
+            host: "localhost".to_string(),
+            port: 5432,
+            user: "ahogehub".to_string(),
+            password: "ahogehub_pass".to_string(),
+            dbname: "ahogehub".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MongoSettings {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    #[serde(skip_serializing)]
+    pub password: String,
+    pub dbname: String,
+}
+
+impl Default for MongoSettings {
+    fn default() -> Self {
+        MongoSettings {
+            host: "localhost".to_string(),
+            port: 27017,
+            user: "ahogehub".to_string(),
+            password: "ahogehub_pass".to_string(),
+            dbname: "ahogehub".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileSettings {
+    /// Root for spooled, resumable and legacy uploads and thumbnails
+    pub destination: PathBuf,
+    pub max_payload_size: usize,
+    /// Largest single file, for `POST /upload` and resumable uploads alike.
+    /// Defaults to `max_payload_size`, lower it to cap files further.
+    pub max_upload_file_size: u64,
+    pub max_upload_files: usize,
+    /// Limit for non-file multipart parts such as `metadata`
+    pub max_form_field_size: usize,
+    pub derived_cache_dir: PathBuf,
+    pub derived_cache_max_bytes: u64,
+}
+
+impl Default for FileSettings {
+    fn default() -> Self {
+        FileSettings {
+            destination: PathBuf::from("./tmp"),
+            max_payload_size: 1024 * 1024 * 1024,
+            max_upload_file_size: 1024 * 1024 * 1024,
+            max_upload_files: 32,
+            max_form_field_size: 1024 * 1024,
+            derived_cache_dir: PathBuf::from("./cache/derived"),
+            derived_cache_max_bytes: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    #[default]
+    Local,
+    S3,
+    Gridfs,
+}
+
+impl std::str::FromStr for StorageBackend {
+    type Err = ();
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        match raw {
+            "local" => Ok(StorageBackend::Local),
+            "s3" => Ok(StorageBackend::S3),
+            "gridfs" => Ok(StorageBackend::Gridfs),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Where blob bytes are kept, see [`crate::storage`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageSettings {
+    pub backend: StorageBackend,
+    /// Root of the `local` backend, `files.destination/blobs` when unset
+    pub local_root: Option<PathBuf>,
+    pub s3_bucket: String,
+    pub s3_region: String,
+    /// For S3-compatible servers such as MinIO
+    pub s3_endpoint: Option<String>,
+    pub s3_access_key: String,
+    #[serde(skip_serializing)]
+    pub s3_secret_key: String,
+    /// MinIO and most self-hosted servers need path-style requests
+    pub s3_path_style: bool,
+    pub s3_prefix: String,
+    pub gridfs_bucket: String,
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        StorageSettings {
+            backend: StorageBackend::Local,
+            local_root: None,
+            s3_bucket: String::new(),
+            s3_region: "us-east-1".to_string(),
+            s3_endpoint: None,
+            s3_access_key: String::new(),
+            s3_secret_key: String::new(),
+            s3_path_style: false,
+            s3_prefix: "blobs/".to_string(),
+            gridfs_bucket: "blobs".to_string(),
+        }
+    }
+}
+
+impl StorageSettings {
+    /// Root of the `local` backend
+    pub fn local_root(&self, destination: &Path) -> PathBuf {
+        match &self.local_root {
+            Some(root) => root.clone(),
+            None => destination.join("blobs"),
+        }
+    }
+}
+
+/// Flags that override the file and the environment
+#[derive(Debug, Clone, Default, Args)]
+pub struct SettingsArgs {
+    /// TOML configuration file
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    #[arg(long)]
+    pub host: Option<String>,
+    #[arg(long)]
+    pub port: Option<u16>,
+    #[arg(long)]
+    pub workers: Option<usize>,
+    #[arg(long)]
+    pub postgres_host: Option<String>,
+    #[arg(long)]
+    pub postgres_port: Option<u16>,
+    #[arg(long)]
+    pub mongodb_host: Option<String>,
+    #[arg(long)]
+    pub mongodb_port: Option<u16>,
+    #[arg(long)]
+    pub destination: Option<PathBuf>,
+    #[arg(long)]
+    pub max_payload_size: Option<usize>,
+    #[arg(long)]
+    pub max_upload_file_size: Option<u64>,
+    #[arg(long, value_enum)]
+    pub storage_backend: Option<StorageBackend>,
+}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    Read { path: PathBuf, error: std::io::Error },
+    Parse { path: PathBuf, error: String },
+    /// An environment variable that does not parse as its setting
+    Env { name: &'static str, value: String },
+    /// Every problem found by [`Settings::validate`]
+    Invalid(Vec<String>),
+}
+
+impl std::fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SettingsError::Read { path, error } => {
+                write!(f, "failed to read {}: {}", path.display(), error)
+            }
+            SettingsError::Parse { path, error } => {
+                write!(f, "failed to parse {}: {}", path.display(), error)
+            }
+            SettingsError::Env { name, value } => {
+                write!(f, "{} has an invalid value '{}'", name, value)
+            }
+            SettingsError::Invalid(problems) => {
+                write!(f, "invalid settings: {}", problems.join("; "))
+            }
+        }
+    }
+}
+impl std::error::Error for SettingsError {}
+
+fn env_string(target: &mut String, name: &'static str) {
+    if let Ok(value) = std::env::var(name) {
+        *target = value;
+    }
+}
+
+fn env_path(target: &mut PathBuf, name: &'static str) {
+    if let Ok(value) = std::env::var(name) {
+        *target = PathBuf::from(value);
+    }
+}
+
+fn env_option(target: &mut Option<String>, name: &'static str) {
+    if let Ok(value) = std::env::var(name) {
+        *target = Some(value);
+    }
+}
+
+fn env_bool(target: &mut bool, name: &'static str) -> Result<(), SettingsError> {
+    if let Ok(value) = std::env::var(name) {
+        match value.as_str() {
+            "true" | "1" => *target = true,
+            "false" | "0" => *target = false,
+            _ => return Err(SettingsError::Env { name, value }),
+        }
+    }
+    Ok(())
+}
+
+fn env_parse<T: std::str::FromStr>(target: &mut T, name: &'static str) -> Result<(), SettingsError> {
+    if let Ok(value) = std::env::var(name) {
+        match value.parse() {
+            Ok(parsed) => *target = parsed,
+            Err(_) => return Err(SettingsError::Env { name, value }),
+        }
+    }
+    Ok(())
+}
+
+fn set<T>(target: &mut T, value: &Option<T>)
+where
+    T: Clone,
+{
+    if let Some(value) = value {
+        *target = value.clone();
+    }
+}
+
+impl Settings {
+    /// Defaults, then the TOML file, then the environment, then `args`
+    pub fn load(args: &SettingsArgs) -> Result<Settings, SettingsError> {
+        let explicit = args
+            .config
+            .clone()
+            .or_else(|| std::env::var("MEDIAPUB_CONFIG").ok().map(PathBuf::from));
+        let mut settings = match explicit {
+            Some(path) => Settings::from_file(&path)?,
+            None => match Path::new(DEFAULT_CONFIG_FILE).exists() {
+                true => Settings::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+                false => Settings::default(),
+            },
+        };
+        settings.apply_env()?;
+        settings.apply_args(args);
+        Ok(settings)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Settings, SettingsError> {
+        let text = std::fs::read_to_string(path).map_err(|error| SettingsError::Read {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        toml::from_str(&text).map_err(|e| SettingsError::Parse {
+            path: path.to_path_buf(),
+            error: e.to_string(),
+        })
+    }
+
+    fn apply_env(&mut self) -> Result<(), SettingsError> {
+        env_string(&mut self.server.host, "SERVER_HOST");
+        env_parse(&mut self.server.port, "SERVER_PORT")?;
+        env_parse(&mut self.server.workers, "SERVER_WORKERS")?;
+
+        env_string(&mut self.postgres.host, "POSTGRES_HOST");
+        env_parse(&mut self.postgres.port, "POSTGRES_PORT")?;
+        env_string(&mut self.postgres.user, "POSTGRES_USER");
+        env_string(&mut self.postgres.password, "POSTGRES_PASSWORD");
+        env_string(&mut self.postgres.dbname, "POSTGRES_DBNAME");
+
+        env_string(&mut self.mongodb.host, "MONGODB_HOST");
+        env_parse(&mut self.mongodb.port, "MONGODB_PORT")?;
+        env_string(&mut self.mongodb.user, "MONGODB_USER");
+        env_string(&mut self.mongodb.password, "MONGODB_PASSWORD");
+        env_string(&mut self.mongodb.dbname, "MONGODB_DBNAME");
+
+        env_path(&mut self.files.destination, "FILES_DESTINATION");
+        env_parse(&mut self.files.max_payload_size, "FILES_MAX_PAYLOAD_SIZE")?;
+        env_parse(&mut self.files.max_upload_file_size, "FILES_MAX_UPLOAD_FILE_SIZE")?;
+        env_parse(&mut self.files.max_upload_files, "FILES_MAX_UPLOAD_FILES")?;
+        env_parse(&mut self.files.max_form_field_size, "FILES_MAX_FORM_FIELD_SIZE")?;
+        env_path(&mut self.files.derived_cache_dir, "FILES_DERIVED_CACHE_DIR");
+        env_parse(&mut self.files.derived_cache_max_bytes, "FILES_DERIVED_CACHE_MAX_BYTES")?;
+
+        let storage = &mut self.storage;
+        env_parse(&mut storage.backend, "STORAGE_BACKEND")?;
+        if let Ok(value) = std::env::var("STORAGE_LOCAL_ROOT") {
+            storage.local_root = Some(PathBuf::from(value));
+        }
+
+        //the older names first, so the `STORAGE_` ones win
+        env_string(&mut storage.s3_bucket, "S3_BUCKET");
+        env_string(&mut storage.s3_region, "S3_REGION");
+        env_option(&mut storage.s3_endpoint, "S3_ENDPOINT");
+        env_string(&mut storage.s3_access_key, "S3_ACCESS_KEY");
+        env_string(&mut storage.s3_secret_key, "S3_SECRET_KEY");
+        env_bool(&mut storage.s3_path_style, "S3_PATH_STYLE")?;
+        env_string(&mut storage.s3_prefix, "S3_PREFIX");
+        env_string(&mut storage.gridfs_bucket, "GRIDFS_BUCKET");
+        env_string(&mut storage.s3_bucket, "STORAGE_S3_BUCKET");
+        env_string(&mut storage.s3_region, "STORAGE_S3_REGION");
+        env_option(&mut storage.s3_endpoint, "STORAGE_S3_ENDPOINT");
+        env_string(&mut storage.s3_access_key, "STORAGE_S3_ACCESS_KEY");
+        env_string(&mut storage.s3_secret_key, "STORAGE_S3_SECRET_KEY");
+        env_bool(&mut storage.s3_path_style, "STORAGE_S3_PATH_STYLE")?;
+        env_string(&mut storage.s3_prefix, "STORAGE_S3_PREFIX");
+        env_string(&mut storage.gridfs_bucket, "STORAGE_GRIDFS_BUCKET");
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &SettingsArgs) {
+        set(&mut self.server.host, &args.host);
+        set(&mut self.server.port, &args.port);
+        set(&mut self.server.workers, &args.workers);
+        set(&mut self.postgres.host, &args.postgres_host);
+        set(&mut self.postgres.port, &args.postgres_port);
+        set(&mut self.mongodb.host, &args.mongodb_host);
+        set(&mut self.mongodb.port, &args.mongodb_port);
+        set(&mut self.files.destination, &args.destination);
+        set(&mut self.files.max_payload_size, &args.max_payload_size);
+        set(&mut self.files.max_upload_file_size, &args.max_upload_file_size);
+        set(&mut self.storage.backend, &args.storage_backend);
+    }
+
+    /// Report every problem at once rather than the first
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        let mut problems = Vec::new();
+        let mut require = |ok: bool, problem: &str| {
+            if !ok {
+                problems.push(problem.to_string());
+            }
+        };
+        require(!self.server.host.is_empty(), "server.host must not be empty");
+        require(self.server.port != 0, "server.port must not be 0");
+        require(self.server.workers > 0, "server.workers must be at least 1");
+        require(!self.postgres.host.is_empty(), "postgres.host must not be empty");
+        require(self.postgres.port != 0, "postgres.port must not be 0");
+        require(!self.postgres.user.is_empty(), "postgres.user must not be empty");
+        require(!self.postgres.dbname.is_empty(), "postgres.dbname must not be empty");
+        require(!self.mongodb.host.is_empty(), "mongodb.host must not be empty");
+        require(self.mongodb.port != 0, "mongodb.port must not be 0");
+        require(!self.mongodb.user.is_empty(), "mongodb.user must not be empty");
+        require(!self.mongodb.dbname.is_empty(), "mongodb.dbname must not be empty");
+        require(
+            !self.files.destination.as_os_str().is_empty(),
+            "files.destination must not be empty",
+        );
+        require(
+            !self.files.derived_cache_dir.as_os_str().is_empty(),
+            "files.derived_cache_dir must not be empty",
+        );
+        require(self.files.max_payload_size > 0, "files.max_payload_size must be positive");
+        require(
+            self.files.max_upload_file_size > 0,
+            "files.max_upload_file_size must be positive",
+        );
+        require(
+            self.files.max_upload_file_size <= self.files.max_payload_size as u64,
+            "files.max_upload_file_size must not exceed files.max_payload_size",
+        );
+        require(self.files.max_upload_files > 0, "files.max_upload_files must be at least 1");
+        require(
+            self.files.max_form_field_size > 0,
+            "files.max_form_field_size must be positive",
+        );
+        require(
+            self.files.derived_cache_max_bytes > 0,
+            "files.derived_cache_max_bytes must be positive",
+        );
+        let storage = &self.storage;
+        match storage.backend {
+            StorageBackend::Local => require(
+                storage.local_root.as_ref().is_none_or(|root| !root.as_os_str().is_empty()),
+                "storage.local_root must not be empty",
+            ),
+            StorageBackend::S3 => {
+                require(!storage.s3_bucket.is_empty(), "storage.s3_bucket must be set for s3");
+                require(!storage.s3_region.is_empty(), "storage.s3_region must be set for s3");
+                require(
+                    !storage.s3_access_key.is_empty(),
+                    "storage.s3_access_key must be set for s3",
+                );
+                require(
+                    !storage.s3_secret_key.is_empty(),
+                    "storage.s3_secret_key must be set for s3",
+                );
+                require(
+                    storage
+                        .s3_endpoint
+                        .as_ref()
+                        .is_none_or(|e| e.starts_with("http://") || e.starts_with("https://")),
+                    "storage.s3_endpoint must be an http or https URL",
+                );
+            }
+            StorageBackend::Gridfs => require(
+                !storage.gridfs_bucket.is_empty(),
+                "storage.gridfs_bucket must be set for gridfs",
+            ),
+        }
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(SettingsError::Invalid(problems)),
+        }
+    }
+}
+
+/// Load `.env` in debug builds and `.env_prod` in release builds into the environment
+/// read by [`Settings::load`]. A missing file is reported, not fatal.
+pub fn load_env_file() {
+    let env_file = match cfg!(debug_assertions) {
+        true => ".env",
+        false => ".env_prod",
+    };
+    if let Err(e) = dotenvy::from_filename(env_file) {
+        eprintln!("{} was not loaded: {}", env_file, e);
+    }
+}
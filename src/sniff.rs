@@ -0,0 +1,100 @@
+//! Media type detection from magic bytes.
+//! Only the types in `ALLOWED` may be uploaded, whatever the client claims.
+use std::io::Read;
+use std::path::Path;
+
+/// Bytes read from the start of a file for detection
+pub const SNIFF_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MediaType {
+    pub mime: &'static str,
+    pub extension: &'static str,
+}
+
+pub const PNG: MediaType = MediaType { mime: "image/png", extension: "png" };
+pub const JPEG: MediaType = MediaType { mime: "image/jpeg", extension: "jpg" };
+pub const GIF: MediaType = MediaType { mime: "image/gif", extension: "gif" };
+pub const WEBP: MediaType = MediaType { mime: "image/webp", extension: "webp" };
+pub const AVIF: MediaType = MediaType { mime: "image/avif", extension: "avif" };
+pub const MP4: MediaType = MediaType { mime: "video/mp4", extension: "mp4" };
+pub const WEBM: MediaType = MediaType { mime: "video/webm", extension: "webm" };
+
+pub const ALLOWED: [MediaType; 7] = [PNG, JPEG, GIF, WEBP, AVIF, MP4, WEBM];
+
+const MP4_BRANDS: [&[u8; 4]; 11] = [
+    b"isom", b"iso2", b"iso3", b"iso4", b"iso5", b"iso6", b"mp41", b"mp42", b"avc1", b"dash",
+    b"M4V ",
+];
+
+/// Brands of an ISO BMFF `ftyp` box, major brand first
+fn ftyp_brands(header: &[u8]) -> Vec<&[u8]> {
+    if header.len() < 12 || &header[4..8] != b"ftyp" {
+        return Vec::new();
+    }
+    let box_size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let end = box_size.clamp(12, header.len());
+    let mut brands = vec![&header[8..12]];
+    //skip minor_version
+    let mut offset = 16;
+    while offset + 4 <= end {
+        brands.push(&header[offset..offset + 4]);
+        offset += 4;
+    }
+    brands
+}
+
+pub fn sniff(header: &[u8]) -> Option<MediaType> {
+    if header.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some(PNG);
+    }
+    if header.starts_with(b"\xff\xd8\xff") {
+        return Some(JPEG);
+    }
+    if header.starts_with(b"GIF87a") || header.starts_with(b"GIF89a") {
+        return Some(GIF);
+    }
+    if header.len() >= 12 && header.starts_with(b"RIFF") && &header[8..12] == b"WEBP" {
+        return Some(WEBP);
+    }
+    let brands = ftyp_brands(header);
+    if let Some(major) = brands.first() {
+        if *major == b"avif" || *major == b"avis" {
+            return Some(AVIF);
+        }
+        if MP4_BRANDS.iter().any(|b| b.as_slice() == *major) {
+            return Some(MP4);
+        }
+        if brands.iter().any(|b| *b == b"avif" || *b == b"avis") {
+            return Some(AVIF);
+        }
+        if brands.iter().any(|b| MP4_BRANDS.iter().any(|m| m.as_slice() == *b)) {
+            return Some(MP4);
+        }
+        return None;
+    }
+    //EBML header, DocType must be webm (plain Matroska is not accepted)
+    if header.starts_with(b"\x1a\x45\xdf\xa3") && header.windows(4).any(|w| w == b"webm") {
+        return Some(WEBM);
+    }
+    None
+}
+
+pub fn sniff_file(path: &Path) -> std::io::Result<Option<MediaType>> {
+    let mut file = std::fs::File::open(path)?;
+    let mut header = Vec::with_capacity(SNIFF_LENGTH);
+    file.by_ref()
+        .take(SNIFF_LENGTH as u64)
+        .read_to_end(&mut header)?;
+    Ok(sniff(&header))
+}
+
+/// Whether a client supplied content type agrees with the detected one
+pub fn matches_claim(detected: &MediaType, claimed: &str) -> bool {
+    let claimed = claimed.trim().to_lowercase();
+    match claimed.as_str() {
+        "application/octet-stream" => true,
+        "image/jpg" | "image/pjpeg" => detected.mime == JPEG.mime,
+        _ => detected.mime == claimed,
+    }
+}
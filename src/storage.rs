@@ -0,0 +1,185 @@
+//! Where blob bytes are kept.
+//!
+//! [`BlobStore`] is implemented for a local directory, S3-compatible object storage
+//! and MongoDB GridFS. The backend is chosen by the `storage` section of
+//! [`Settings`] (`local`, `s3` or `gridfs`). Thumbnails and the derived image cache
+//! always stay on local disk.
+pub mod gridfs;
+pub mod local;
+pub mod s3;
+
+use crate::settings::{Settings, StorageBackend};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+pub type ByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+pub struct BlobReader {
+    /// Length in bytes when the backend knows it up front
+    pub size: Option<u64>,
+    pub stream: ByteStream,
+}
+
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Store the file at `source` under `key`, replacing anything already there.
+    /// `source` may be moved rather than copied.
+    async fn put(&self, key: &str, source: &Path) -> io::Result<()>;
+    /// Store `stream` under `key` as it arrives. A stream that ends in an error
+    /// fails the call, whatever was written under `key` is left for the caller to delete.
+    async fn put_stream(&self, key: &str, stream: ByteStream) -> io::Result<()>;
+    /// Move the bytes under `from` to `to`, replacing anything already there
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+    /// Stream the bytes stored under `key`, `None` when there is nothing
+    async fn get(&self, key: &str) -> io::Result<Option<BlobReader>>;
+    /// Remove `key`, a missing key is not an error
+    async fn delete(&self, key: &str) -> io::Result<()>;
+    async fn exists(&self, key: &str) -> io::Result<bool>;
+    /// Every key in the store
+    async fn list(&self) -> io::Result<Vec<String>>;
+    /// Path of `key` on this machine, for backends that keep files on local disk
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+pub enum StorageConfig {
+    Local {
+        root: PathBuf,
+    },
+    S3 {
+        bucket: String,
+        region: String,
+        endpoint: Option<String>,
+        access_key: String,
+        secret_key: String,
+        path_style: bool,
+        prefix: String,
+    },
+    GridFs {
+        bucket: String,
+    },
+}
+
+impl StorageConfig {
+    /// The backend named by `settings.storage`, already checked by [`Settings::validate`]
+    pub fn from_settings(settings: &Settings) -> StorageConfig {
+        let storage = &settings.storage;
+        match storage.backend {
+            StorageBackend::Local => StorageConfig::Local {
+                root: storage.local_root(&settings.files.destination),
+            },
+            StorageBackend::S3 => StorageConfig::S3 {
+                bucket: storage.s3_bucket.clone(),
+                region: storage.s3_region.clone(),
+                endpoint: storage.s3_endpoint.clone(),
+                access_key: storage.s3_access_key.clone(),
+                secret_key: storage.s3_secret_key.clone(),
+                path_style: storage.s3_path_style,
+                prefix: storage.s3_prefix.clone(),
+            },
+            StorageBackend::Gridfs => StorageConfig::GridFs {
+                bucket: storage.gridfs_bucket.clone(),
+            },
+        }
+    }
+}
+
+/// Build the configured backend
+pub fn open(config: &StorageConfig, mongo_pool: &mongodb::Client) -> Result<Arc<dyn BlobStore>, String> {
+    match config {
+        StorageConfig::Local { root } => Ok(Arc::new(local::LocalStore::new(root.clone()))),
+        StorageConfig::S3 {
+            bucket,
+            region,
+            endpoint,
+            access_key,
+            secret_key,
+            path_style,
+            prefix,
+        } => {
+            let store = s3::S3Store::new(
+                bucket,
+                region,
+                endpoint.as_deref(),
+                access_key,
+                secret_key,
+                *path_style,
+                prefix,
+            )
+            .map_err(|e| e.to_string())?;
+            Ok(Arc::new(store))
+        }
+        StorageConfig::GridFs { bucket } => Ok(Arc::new(gridfs::GridFsStore::new(mongo_pool, bucket))),
+    }
+}
+
+/// A file on local disk holding a post's bytes, removed on drop when it is a temporary copy
+pub struct LocalFile {
+    pub path: PathBuf,
+    temporary: bool,
+}
+
+impl Drop for LocalFile {
+    fn drop(&mut self) {
+        if self.temporary
+            && let Err(e) = std::fs::remove_file(&self.path)
+        {
+            eprintln!("Failed to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Get a post's bytes onto local disk for decoding. Local blobs are used in place,
+/// other backends are downloaded to a temporary file. Posts stored before blobs
+/// existed have no `blob_hash` and live at `destination/<filename>`.
+pub async fn fetch_local(
+    store: &dyn BlobStore,
+    destination: &Path,
+    filename: &str,
+    blob_hash: Option<&str>,
+) -> io::Result<LocalFile> {
+    let hash = match blob_hash {
+        Some(hash) => hash,
+        None => {
+            return Ok(LocalFile {
+                path: destination.join(filename),
+                temporary: false,
+            });
+        }
+    };
+    if let Some(path) = store.local_path(hash) {
+        return Ok(LocalFile {
+            path,
+            temporary: false,
+        });
+    }
+    let mut reader = match store.get(hash).await? {
+        Some(reader) => reader,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("blob {} is missing", hash),
+            ));
+        }
+    };
+    let spool = destination.join("spool");
+    tokio::fs::create_dir_all(&spool).await?;
+    let local = LocalFile {
+        path: spool.join(Uuid::new_v4().to_string()),
+        temporary: true,
+    };
+    let mut file = tokio::fs::File::create(&local.path).await?;
+    while let Some(chunk) = reader.stream.next().await {
+        file.write_all(&chunk?).await?;
+    }
+    file.flush().await?;
+    Ok(local)
+}
@@ -0,0 +1,128 @@
+use super::{BlobReader, BlobStore, ByteStream};
+use crate::db_pool::mongo_database;
+use async_trait::async_trait;
+use futures_util::{TryStreamExt, io::AsyncWriteExt};
+use mongodb::{
+    Client,
+    bson::{Bson, doc},
+    error::{ErrorKind, GridFsErrorKind},
+    gridfs::GridFsBucket,
+    options::GridFsBucketOptions,
+};
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tokio_util::io::ReaderStream;
+
+/// Blobs as GridFS files named by their key, in the application database
+pub struct GridFsStore {
+    bucket: GridFsBucket,
+}
+
+fn to_io(e: mongodb::error::Error) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+impl GridFsStore {
+    pub fn new(mongo_pool: &Client, bucket: &str) -> GridFsStore {
+        let options = GridFsBucketOptions::builder()
+            .bucket_name(bucket.to_string())
+            .build();
+        GridFsStore {
+            bucket: mongo_database(mongo_pool).gridfs_bucket(options),
+        }
+    }
+
+    /// GridFS keeps every revision under a name, only the newest one is needed
+    async fn remove_older_revisions(&self, key: &str, newest: &Bson) -> io::Result<()> {
+        let mut older = self
+            .bucket
+            .find(doc! {"filename": key, "_id": {"$ne": newest.clone()}})
+            .await
+            .map_err(to_io)?;
+        while older.advance().await.map_err(to_io)? {
+            let id = older.deserialize_current().map_err(to_io)?.id;
+            self.bucket.delete(id).await.map_err(to_io)?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlobStore for GridFsStore {
+    async fn put(&self, key: &str, source: &Path) -> io::Result<()> {
+        let file = tokio::fs::File::open(source).await?;
+        self.put_stream(key, Box::pin(ReaderStream::new(file))).await
+    }
+
+    async fn put_stream(&self, key: &str, stream: ByteStream) -> io::Result<()> {
+        let mut upload = self.bucket.open_upload_stream(key).await.map_err(to_io)?;
+        if let Err(e) = futures_util::io::copy(stream.into_async_read(), &mut upload).await {
+            //chunks written so far are removed with the upload
+            if let Err(abort) = upload.abort().await {
+                eprintln!("Failed to abort GridFS upload of {}: {}", key, abort);
+            }
+            return Err(e);
+        }
+        upload.close().await?;
+        self.remove_older_revisions(key, upload.id()).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let id = match self.bucket.find_one(doc! {"filename": from}).await.map_err(to_io)? {
+            Some(file) => file.id,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} is not stored", from),
+                ));
+            }
+        };
+        self.bucket.rename(id.clone(), to).await.map_err(to_io)?;
+        self.remove_older_revisions(to, &id).await
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Option<BlobReader>> {
+        let size = match self.bucket.find_one(doc! {"filename": key}).await.map_err(to_io)? {
+            Some(file) => file.length,
+            None => return Ok(None),
+        };
+        let download = self
+            .bucket
+            .open_download_stream_by_name(key)
+            .await
+            .map_err(to_io)?;
+        Ok(Some(BlobReader {
+            size: Some(size),
+            stream: Box::pin(ReaderStream::new(download.compat())),
+        }))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match self.bucket.delete_by_name(key).await {
+            Ok(_) => Ok(()),
+            Err(e) => match *e.kind {
+                ErrorKind::GridFs(GridFsErrorKind::FileNotFound { .. }) => Ok(()),
+                _ => Err(to_io(e)),
+            },
+        }
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        let found = self.bucket.find_one(doc! {"filename": key}).await.map_err(to_io)?;
+        Ok(found.is_some())
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        let mut files = self.bucket.find(doc! {}).await.map_err(to_io)?;
+        //older revisions share a name
+        let mut keys = HashSet::new();
+        while files.advance().await.map_err(to_io)? {
+            if let Some(key) = files.deserialize_current().map_err(to_io)?.filename {
+                keys.insert(key);
+            }
+        }
+        Ok(keys.into_iter().collect())
+    }
+}
@@ -0,0 +1,108 @@
+use super::{BlobReader, BlobStore, ByteStream};
+use crate::blob::blob_key;
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+/// Blobs as files under a local directory
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: PathBuf) -> LocalStore {
+        LocalStore { root }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(blob_key(key))
+    }
+
+    /// Path of `key` with its parent directories created
+    async fn prepared_path(&self, key: &str) -> io::Result<PathBuf> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        Ok(path)
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalStore {
+    async fn put(&self, key: &str, source: &Path) -> io::Result<()> {
+        let path = self.prepared_path(key).await?;
+        //rename fails across filesystems, fall back to a copy
+        if tokio::fs::rename(source, &path).await.is_err() {
+            tokio::fs::copy(source, &path).await?;
+        }
+        Ok(())
+    }
+
+    async fn put_stream(&self, key: &str, mut stream: ByteStream) -> io::Result<()> {
+        let mut file = tokio::fs::File::create(self.prepared_path(key).await?).await?;
+        while let Some(chunk) = stream.next().await {
+            file.write_all(&chunk?).await?;
+        }
+        file.flush().await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let path = self.prepared_path(to).await?;
+        tokio::fs::rename(self.path(from), path).await
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Option<BlobReader>> {
+        match tokio::fs::File::open(self.path(key)).await {
+            Ok(file) => {
+                let size = file.metadata().await?.len();
+                Ok(Some(BlobReader {
+                    size: Some(size),
+                    stream: Box::pin(ReaderStream::new(file)),
+                }))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match tokio::fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        tokio::fs::try_exists(self.path(key)).await
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        //keys are stored as ab/cd/<key>
+        let mut keys = Vec::new();
+        let mut pending = vec![(self.root.clone(), 0)];
+        while let Some((dir, depth)) = pending.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let file_type = entry.file_type().await?;
+                match (depth, file_type.is_dir()) {
+                    (0 | 1, true) => pending.push((entry.path(), depth + 1)),
+                    (2, false) => keys.push(entry.file_name().to_string_lossy().into_owned()),
+                    _ => {}
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.path(key))
+    }
+}
@@ -0,0 +1,126 @@
+use super::{BlobReader, BlobStore, ByteStream};
+use crate::blob::blob_key;
+use async_trait::async_trait;
+use futures_util::TryStreamExt;
+use s3::{Bucket, Region, creds::Credentials, error::S3Error};
+use std::io;
+use std::path::Path;
+use tokio_util::io::StreamReader;
+
+/// Blobs as objects in an S3-compatible bucket (AWS, MinIO, ...)
+pub struct S3Store {
+    bucket: Box<Bucket>,
+    prefix: String,
+}
+
+fn to_io(e: S3Error) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+fn is_not_found(e: &S3Error) -> bool {
+    matches!(e, S3Error::HttpFailWithBody(404, _))
+}
+
+impl S3Store {
+    /// With an `endpoint` the region name is only used for signing
+    pub fn new(
+        bucket: &str,
+        region: &str,
+        endpoint: Option<&str>,
+        access_key: &str,
+        secret_key: &str,
+        path_style: bool,
+        prefix: &str,
+    ) -> Result<S3Store, S3Error> {
+        let region = match endpoint {
+            Some(endpoint) => Region::Custom {
+                region: region.to_string(),
+                endpoint: endpoint.to_string(),
+            },
+            None => region.parse()?,
+        };
+        let credentials = Credentials::new(Some(access_key), Some(secret_key), None, None, None)?;
+        let mut bucket = Bucket::new(bucket, region, credentials)?;
+        if path_style {
+            bucket = bucket.with_path_style();
+        }
+        Ok(S3Store {
+            bucket,
+            prefix: prefix.to_string(),
+        })
+    }
+
+    fn object(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, blob_key(key))
+    }
+}
+
+#[async_trait]
+impl BlobStore for S3Store {
+    async fn put(&self, key: &str, source: &Path) -> io::Result<()> {
+        let mut file = tokio::fs::File::open(source).await?;
+        self.bucket
+            .put_object_stream(&mut file, self.object(key))
+            .await
+            .map_err(to_io)?;
+        Ok(())
+    }
+
+    async fn put_stream(&self, key: &str, stream: ByteStream) -> io::Result<()> {
+        let mut reader = StreamReader::new(stream);
+        self.bucket
+            .put_object_stream(&mut reader, self.object(key))
+            .await
+            .map_err(to_io)?;
+        Ok(())
+    }
+
+    //S3 has no rename, the object is copied within the bucket
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.bucket
+            .copy_object_internal(self.object(from), self.object(to))
+            .await
+            .map_err(to_io)?;
+        self.delete(from).await
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Option<BlobReader>> {
+        match self.bucket.get_object_stream(self.object(key)).await {
+            Ok(response) => Ok(Some(BlobReader {
+                size: None,
+                stream: Box::pin(response.bytes.map_err(to_io)),
+            })),
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(to_io(e)),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match self.bucket.delete_object(self.object(key)).await {
+            Err(e) if !is_not_found(&e) => Err(to_io(e)),
+            _ => Ok(()),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        match self.bucket.object_exists(self.object(key)).await {
+            Ok(exists) => Ok(exists),
+            Err(e) if is_not_found(&e) => Ok(false),
+            Err(e) => Err(to_io(e)),
+        }
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        let pages = self
+            .bucket
+            .list(self.prefix.clone(), None)
+            .await
+            .map_err(to_io)?;
+        let keys = pages
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .filter_map(|object| object.key.rsplit('/').next().map(str::to_string))
+            .collect();
+        Ok(keys)
+    }
+}
@@ -0,0 +1,19 @@
+//! Tag names shared by the tag routes and the search query language.
+
+pub const MAX_TAG_LENGTH: usize = 64;
+
+/// Lowercase and validate a tag name.
+/// Tags may not contain whitespace or query syntax (`:`, `(`, `)`, `|`, `"`) and may not start with `-`.
+pub fn normalize_tag(raw: &str) -> Option<String> {
+    let tag = raw.trim().to_lowercase();
+    if tag.is_empty() || tag.chars().count() > MAX_TAG_LENGTH || tag.starts_with('-') {
+        return None;
+    }
+    if !tag
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.')
+    {
+        return None;
+    }
+    Some(tag)
+}
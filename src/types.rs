@@ -1,5 +1,7 @@
-use actix_multipart::form::{MultipartForm, json::Json, tempfile::TempFile};
+use crate::{repair::RepairStep, scope::Scope};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
 use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -10,13 +12,82 @@ pub struct Post {
     pub source: String,
     pub description: String,
     pub uploader: Uuid,
+    /// Metadata fields removed from the file on upload
+    #[serde(default)]
+    pub stripped_metadata: Vec<String>,
 }
 
 
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct ResponseFile {
+#[derive(Debug, Serialize, Clone)]
+pub struct DuplicateMatch {
+    /// Stored filename of the uploaded file
+    pub file: String,
+    /// Existing post that looks the same
+    pub post_id: Uuid,
+    pub distance: u32,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct UploadResponse {
     pub file: Vec<String>,
+    pub possible_duplicates: Vec<DuplicateMatch>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SimilarQuery {
+    pub distance: Option<u32>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct SimilarPost {
+    pub post_id: Uuid,
+    pub distance: u32,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct SimilarResponse {
+    pub post_id: Uuid,
+    pub similar: Vec<SimilarPost>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ItemQuery {
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+    pub before: Option<DateTime<Utc>>,
+    pub after: Option<DateTime<Utc>>,
+    pub uploader: Option<String>,
+    pub content_type: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TransformQuery {
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    pub fit: Option<String>,
+    pub fmt: Option<String>,
+    pub crop: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueryErrorResponse {
+    pub error: String,
+    pub position: usize,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ItemListResponse {
+    pub file: Vec<String>,
+    pub next_cursor: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -27,11 +98,13 @@ pub struct UploadJson {
     pub description: String,
 }
 
-#[derive(Debug, MultipartForm)]
-pub struct UploadFrom {
-    #[multipart(limit = "10MB")]
-    pub file: Vec<TempFile>,
-    pub metadata: Json<Vec<UploadJson>>,
+/// Partial `UploadJson`, only the fields that are present get updated
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UpdateJson {
+    pub title: Option<String>,
+    pub creator: Option<String>,
+    pub source: Option<String>,
+    pub description: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -50,6 +123,9 @@ pub struct SignUpResponse {
 pub struct LoginRequest {
     pub username: String,
     pub password: String,
+    /// Also set the session cookie, for browsers
+    #[serde(default)]
+    pub set_cookie: bool,
 }
 #[derive(Debug, Deserialize)]
 pub struct LoginSession {
@@ -58,6 +134,9 @@ pub struct LoginSession {
 #[derive(Debug, Deserialize)]
 pub struct RefreshToken {
     pub refresh_token: String,
+    /// Also set the session cookie, for browsers
+    #[serde(default)]
+    pub set_cookie: bool,
 }
 #[derive(Debug, Serialize)]
 pub struct LoginResponse {
@@ -72,6 +151,7 @@ pub struct SessionTokenResponse {
     pub username: String,
     pub session_token: String,
     pub refresh_token: String,
+    pub session_expires_at: DateTime<Utc>,
     pub message: String,
 }
 
@@ -84,4 +164,140 @@ pub struct ErrorResponse {
 pub struct ItemResponse {
     pub image: String,
     pub metadata: UploadJson,
+    pub tags: Vec<String>,
+    pub analysis: Option<ImageAnalysis>,
+    pub analysis_status: AnalysisStatus,
+}
+
+/// Where the background analysis of a post stands. Types the pipeline cannot
+/// decode, such as AVIF, are never analysed and report `unsupported`.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AnalysisStatus {
+    Pending,
+    Complete,
+    Unsupported,
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct CameraInfo {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub lens_model: Option<String>,
+    pub exposure_time: Option<String>,
+    pub f_number: Option<String>,
+    pub iso: Option<String>,
+    pub focal_length: Option<String>,
+    pub taken_at: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ImageAnalysis {
+    pub width: u32,
+    pub height: u32,
+    pub orientation: u8,
+    pub bit_depth: u16,
+    pub frame_count: u32,
+    pub camera: Option<CameraInfo>,
+    pub palette: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagRequest {
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PostTagsResponse {
+    pub post_id: String,
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagQuery {
+    pub prefix: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagCount {
+    pub name: String,
+    pub count: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagListResponse {
+    pub tags: Vec<TagCount>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DropResponse {
+    pub post_id: String,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StepErrorResponse {
+    pub error: String,
+    pub failed_step: RepairStep,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateTokenRequest {
+    pub name: String,
+    pub scope: Vec<Scope>,
+    /// Defaults to `DEFAULT_TOKEN_DAYS`
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DevTokenInfo {
+    pub token_id: Uuid,
+    pub name: String,
+    pub scope: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// The only response that carries the plaintext token
+#[derive(Debug, Serialize)]
+pub struct CreatedTokenResponse {
+    pub token: String,
+    #[serde(flatten)]
+    pub info: DevTokenInfo,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DevTokenListResponse {
+    pub tokens: Vec<DevTokenInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RevokeResponse {
+    pub token_id: String,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LogoutResponse {
+    pub sessions_revoked: u64,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub token_id: Uuid,
+    pub ip_address: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    /// When the device logged in, refreshes keep this
+    pub signed_in_at: DateTime<Utc>,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    /// The session this request was made with
+    pub current: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionListResponse {
+    pub sessions: Vec<SessionInfo>,
 }
@@ -1,6 +1,7 @@
-use crate::types::ErrorResponse;
+use crate::{auth::resolve, scope::Scope, types::ErrorResponse};
 use actix_web::HttpResponse;
 use deadpool_postgres::{Object, Pool};
+use mongodb::bson::{Binary, spec::BinarySubtype};
 use serde::Deserialize;
 use uuid::Uuid;
 
@@ -10,102 +11,80 @@ pub async fn get_psql_pool(pool: &Pool) -> std::io::Result<Object> {
         Ok(conn) => Ok(conn),
         Err(e) => {
             eprintln!("Failed to get connection from pool: {}", e);
-            Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Failed to get database connection",
-            ))
+            Err(std::io::Error::other("Failed to get database connection"))
         }
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
 pub enum CredentialType {
     SessionToken,
     DevToken,
 }
 
+/// Mongo stores post ids as generic binary (see `route::items::get_one`)
+pub fn uuid_to_binary(id: &Uuid) -> Binary {
+    Binary {
+        subtype: BinarySubtype::Generic,
+        bytes: id.as_bytes().to_vec(),
+    }
+}
+
+/// `Token <dev token>` is a dev token, `Bearer <session token>` or a bare token a session
+pub fn parse_credential(header: &str) -> (&str, CredentialType) {
+    if let Some(token) = header.strip_prefix("Token ") {
+        return (token.trim(), CredentialType::DevToken);
+    }
+    match header.strip_prefix("Bearer ") {
+        Some(token) => (token.trim(), CredentialType::SessionToken),
+        None => (header, CredentialType::SessionToken),
+    }
+}
+
 use crate::errors::{
-    AHError::{AccountSuspended, InvalidCredential, UserInactive},
-    DBError::{ConnectionFailed, QueryFailed},
+    AHError::{AccountSuspended, InvalidCredential, MissingScope, PermissionDenied, UserInactive},
+    DBError::{NotFound, QueryFailed},
     DBType::Postgres,
     ErrorKind::{self, AuthError, DatabaseError},
 };
 
-fn extract_user_id_from_row(row: &tokio_postgres::Row) -> Result<Uuid, ErrorKind> {
-    match row.try_get::<_, Uuid>(0) {
-        Ok(uuid) => Ok(uuid),
-        Err(e) => {
-            eprintln!("Invalid user_id format: {}", e);
-            Err(DatabaseError(QueryFailed(Postgres)))
-        }
-    }
-}
-
+/// Resolve a credential and require `required` of it, see [`crate::auth::resolve`]
 pub async fn check_user_validity_with_pool(
     pool: &Pool,
     credential: &str,
     credential_type: CredentialType,
+    required: Scope,
 ) -> Result<Uuid, ErrorKind> {
-    let psql_client = match pool.get().await {
-        Ok(conn) => conn,
-        Err(e) => {
-            eprintln!("Failed to get connection from pool: {}", e);
-            return Err(DatabaseError(ConnectionFailed(Postgres)));
-        }
-    };
+    let user = resolve(pool, credential, credential_type).await?;
+    user.require(required)?;
+    Ok(user.user_id)
+}
 
-    let user_id = match credential_type {
-        CredentialType::DevToken => {
-            match psql_client
-                .query_one(
-                    "SELECT user_id FROM dev_token WHERE token_hash = $1 AND is_revoked = false",
-                    &[&credential],
-                )
-                .await
-            {
-                Ok(row) => extract_user_id_from_row(&row),
-                Err(e) => {
-                    eprintln!("Dev token query failed: {}", e);
-                    Err(DatabaseError(QueryFailed(Postgres)))
-                }
-            }
-        }
-        CredentialType::SessionToken => {
-            match psql_client
-                .query_one(
-                    "SELECT user_id FROM session WHERE session_token = $1 AND is_revoked = false",
-                    &[&credential],
-                )
-                .await
-            {
-                Ok(row) => {
-                    let user_id: Uuid = row.get(0);
-                    Ok(user_id)
-                }
-                Err(e) => {
-                    eprintln!("Session token query failed: {}", e);
-                    Err(DatabaseError(QueryFailed(Postgres)))
-                }
-            }
-        }
-    };
-    let user_id = match user_id {
-        Ok(id) => id,
-        Err(e) => return Err(e),
-    };
-    match psql_client
-        .query_one(
-            "SELECT is_active FROM \"user\" WHERE user_id = $1 AND is_active = true",
-            &[&user_id],
+/// Returns the stored filename when `user_id` owns the post
+pub async fn check_post_ownership(
+    psql_client: &deadpool_postgres::Client,
+    post_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<String, ErrorKind> {
+    let row = match psql_client
+        .query_opt(
+            "SELECT user_id, filename FROM post WHERE post_id = $1",
+            &[post_id],
         )
         .await
     {
-        Ok(_) => Ok(user_id),
+        Ok(Some(row)) => row,
+        Ok(None) => return Err(DatabaseError(NotFound(Postgres))),
         Err(e) => {
-            eprintln!("User active check failed: {}", e);
-            Err(AuthError(AccountSuspended))
+            eprintln!("Post ownership query failed: {}", e);
+            return Err(DatabaseError(QueryFailed(Postgres)));
         }
+    };
+    let owner: Uuid = row.get(0);
+    if owner != *user_id {
+        return Err(AuthError(PermissionDenied));
     }
+    Ok(row.get(1))
 }
 
 pub fn generate_response(error: &ErrorKind) -> HttpResponse {
@@ -123,6 +102,17 @@ pub fn generate_response(error: &ErrorKind) -> HttpResponse {
                 error: "account suspended".to_string(),
             })
         }
+        ErrorKind::AuthError(PermissionDenied) => HttpResponse::Forbidden().json(ErrorResponse {
+            error: "permission denied".to_string(),
+        }),
+        ErrorKind::AuthError(MissingScope(scope)) => {
+            HttpResponse::Forbidden().json(ErrorResponse {
+                error: format!("token is missing the {} scope", scope),
+            })
+        }
+        ErrorKind::DatabaseError(NotFound(_)) => HttpResponse::NotFound().json(ErrorResponse {
+            error: "Item not found".to_string(),
+        }),
         ErrorKind::DatabaseError(_) => HttpResponse::ExpectationFailed().json(ErrorResponse {
             error: "Database error".to_string(),
         }),